@@ -16,16 +16,12 @@
 use std::fmt;
 
 use prost_wkt_types::Timestamp;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+use crate::backend::routing::Priority;
 use crate::durabletask_pb::{CreateOrchestrationAction, OrchestrationStatus, TaskFailureDetails};
-
-pub static ERR_INSTANCE_NOT_FOUND: &str = "no such instance exists";
-pub static ERR_NOT_STARTED: &str = "orchestration has not started";
-pub static ERR_NOT_COMPLETED: &str = "orchestration has not yet completed";
-pub static ERR_NO_FAILURES: &str = "orchestration did not report failure details";
-pub static ERR_DUPLICATE_INSTANCE: &str = "orchestration instance already exists";
-pub static ERR_IGNORE_INSTANCE: &str = "ignore creating orchestration instance";
+use crate::payload::{from_payload, JsonCodec, Payload, PayloadCodec};
+use crate::Error;
 
 pub static REUSE_ID_ACTION_ERROR: CreateOrchestrationAction = CreateOrchestrationAction::Error;
 pub static REUSE_ID_ACTION_IGNORE: CreateOrchestrationAction = CreateOrchestrationAction::Ignore;
@@ -71,8 +67,9 @@ impl From<&InstanceID> for InstanceID {
 pub struct NewOrchestrationBuilder {
     instance_id: Option<InstanceID>,
     orchestration_id_reuse_policy: Option<OrchestrationIdReusePolicy>,
-    input: Option<String>,
+    input: Option<Payload>,
     scheduled_start_timestamp: Option<Timestamp>,
+    pub(crate) priority: Option<Priority>,
 }
 
 impl NewOrchestrationBuilder {
@@ -92,15 +89,14 @@ impl NewOrchestrationBuilder {
         self
     }
 
+    /// Sets the input, serialized with the client's codec when the orchestration is scheduled.
     pub fn input<T: Serialize>(mut self, input: &T) -> Self {
-        let bytes = serde_json::to_vec(input).unwrap_or_else(|_| vec![]);
-
-        self.input = Some(String::from_utf8(bytes).unwrap());
+        self.input = Some(Payload::from_value(input));
         self
     }
 
     pub fn raw_input(mut self, input: String) -> Self {
-        self.input = Some(input);
+        self.input = Some(Payload::Raw(input));
         self
     }
 
@@ -109,20 +105,36 @@ impl NewOrchestrationBuilder {
         self
     }
 
-    pub fn build(self) -> NewOrchestration {
+    /// Sets the priority of the instance's work items, instead of the one its
+    /// [`TaskRouting`](crate::backend::routing::TaskRouting) assigns.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Builds the request, serializing the input as JSON.
+    pub fn build(self) -> Result<NewOrchestration, Error> {
+        self.build_with(&JsonCodec)
+    }
+
+    pub(crate) fn build_with(self, codec: &dyn PayloadCodec) -> Result<NewOrchestration, Error> {
         let instance_id = match self.instance_id {
             None => "".to_string(),
             Some(id) => id.0,
         };
+        let input = match self.input {
+            None => None,
+            Some(input) => input.encode(codec)?,
+        };
 
-        NewOrchestration {
+        Ok(NewOrchestration {
             instance_id,
             name: "name".to_string(),
             version: None,
-            input: self.input,
+            input,
             scheduled_start_timestamp: self.scheduled_start_timestamp,
             orchestration_id_reuse_policy: self.orchestration_id_reuse_policy,
-        }
+        })
     }
 }
 
@@ -150,7 +162,7 @@ pub type RaiseEvent = crate::durabletask_pb::RaiseEventRequest;
 
 #[derive(Default, Debug, PartialEq)]
 pub struct RaiseEventBuilder {
-    input: Option<String>,
+    input: Option<Payload>,
 }
 
 impl RaiseEventBuilder {
@@ -161,23 +173,28 @@ impl RaiseEventBuilder {
     }
 
     pub fn event_payload<T: Serialize>(mut self, payload: &T) -> Self {
-        let bytes = serde_json::to_vec(payload).unwrap_or_else(|_| vec![]);
-
-        self.input = Some(String::from_utf8(bytes).unwrap());
+        self.input = Some(Payload::from_value(payload));
         self
     }
 
     pub fn raw_event_data(mut self, payload: String) -> Self {
-        self.input = Some(payload);
+        self.input = Some(Payload::Raw(payload));
         self
     }
+
+    pub(crate) fn encode_payload(&self, codec: &dyn PayloadCodec) -> Result<Option<String>, Error> {
+        match &self.input {
+            None => Ok(None),
+            Some(input) => input.encode(codec),
+        }
+    }
 }
 
 pub type Terminate = crate::durabletask_pb::TerminateRequest;
 
 #[derive(Default, Debug, PartialEq)]
 pub struct TerminateBuilder {
-    output: Option<String>,
+    output: Option<Payload>,
     recursive: Option<bool>,
 }
 
@@ -189,14 +206,12 @@ impl TerminateBuilder {
     }
 
     pub fn output<T: Serialize>(mut self, data: &T) -> Self {
-        let bytes = serde_json::to_vec(data).unwrap_or_else(|_| vec![]);
-
-        self.output = Some(String::from_utf8(bytes).unwrap());
+        self.output = Some(Payload::from_value(data));
         self
     }
 
     pub fn raw_output(mut self, data: String) -> Self {
-        self.output = Some(data);
+        self.output = Some(Payload::Raw(data));
         self
     }
 
@@ -204,6 +219,17 @@ impl TerminateBuilder {
         self.recursive = Some(recursive);
         self
     }
+
+    pub(crate) fn encode_output(&self, codec: &dyn PayloadCodec) -> Result<Option<String>, Error> {
+        match &self.output {
+            None => Ok(None),
+            Some(output) => output.encode(codec),
+        }
+    }
+
+    pub(crate) fn is_recursive(&self) -> bool {
+        self.recursive.unwrap_or_default()
+    }
 }
 
 pub type Purge = crate::durabletask_pb::PurgeInstancesRequest;
@@ -326,12 +352,12 @@ impl OrchestrationMetadataBuilder {
         self
     }
 
-    pub fn build(self) -> Result<OrchestrationMetadata, &'static str> {
-        let instance_id = self.instance_id.ok_or("instance_id is required")?;
-        let name = self.name.ok_or("name is required")?;
-        let status = self.status.ok_or("status is required")?;
-        let created_at = self.created_at.ok_or("created_at is required")?;
-        let last_updated_at = self.last_updated_at.ok_or("last_updated_at is required")?;
+    pub fn build(self) -> Result<OrchestrationMetadata, Error> {
+        let instance_id = self.instance_id.ok_or(required("instance_id"))?;
+        let name = self.name.ok_or(required("name"))?;
+        let status = self.status.ok_or(required("status"))?;
+        let created_at = self.created_at.ok_or(required("created_at"))?;
+        let last_updated_at = self.last_updated_at.ok_or(required("last_updated_at"))?;
 
         Ok(OrchestrationMetadata {
             instance_id,
@@ -347,6 +373,10 @@ impl OrchestrationMetadataBuilder {
     }
 }
 
+pub(crate) fn required(field: &str) -> Error {
+    Error::InvalidArgument(format!("{} is required", field))
+}
+
 impl OrchestrationMetadata {
     pub fn builder() -> OrchestrationMetadataBuilder {
         OrchestrationMetadataBuilder::new()
@@ -365,6 +395,25 @@ impl OrchestrationMetadata {
                 | OrchestrationStatus::Canceled
         )
     }
+
+    /// Returns the serialized output of a completed orchestration.
+    ///
+    /// Fails with [`Error::OrchestrationFailed`] if the orchestration failed and
+    /// [`Error::NotCompleted`] if it hasn't completed yet.
+    pub fn serialized_result(&self) -> Result<Option<&str>, Error> {
+        match self.runtime_status {
+            OrchestrationStatus::Failed => Err(Error::OrchestrationFailed(
+                self.failure_details.clone().unwrap_or_default(),
+            )),
+            _ if self.is_complete() => Ok(self.serialized_output.as_deref()),
+            _ => Err(Error::NotCompleted),
+        }
+    }
+
+    /// Deserializes the output of a completed orchestration with `codec`.
+    pub fn output<T: DeserializeOwned>(&self, codec: &dyn PayloadCodec) -> Result<T, Error> {
+        from_payload(codec, self.serialized_result()?)
+    }
 }
 
 #[cfg(test)]
@@ -384,7 +433,8 @@ mod tests {
             .orchestration_id_reuse_policy(OrchestrationIdReusePolicy::default())
             .raw_input(input.to_string())
             .start_time(time.clone())
-            .build();
+            .build()
+            .unwrap();
 
         assert_eq!(new_orchestration.instance_id, instance_id.0);
         assert_eq!(new_orchestration.input, Some(input.to_string()));
@@ -395,6 +445,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_orchestration_builder_input() {
+        let new_orchestration = NewOrchestration::builder()
+            .input(&vec![1, 2, 3])
+            .build()
+            .unwrap();
+        assert_eq!(new_orchestration.input, Some("[1,2,3]".to_string()));
+
+        let mut invalid = std::collections::HashMap::new();
+        invalid.insert((1, 2), "tuple keys can't be serialized as JSON");
+        let result = NewOrchestration::builder().input(&invalid).build();
+        assert!(matches!(result, Err(Error::Serialization(_))));
+    }
+
     #[test]
     fn test_fetch_orchestration_metadata_builder() {
         let builder = FetchOrchestrationMetadataBuilder::new().fetch_payloads(true);
@@ -407,7 +471,7 @@ mod tests {
         let payload = "test event payload";
         let builder = RaiseEventBuilder::new().raw_event_data(payload.to_string());
 
-        assert_eq!(builder.input, Some(payload.to_string()));
+        assert_eq!(builder.input, Some(Payload::Raw(payload.to_string())));
     }
 
     #[test]
@@ -417,7 +481,7 @@ mod tests {
             .raw_output(output.to_string())
             .recursive_terminate(true);
 
-        assert_eq!(builder.output, Some(output.to_string()));
+        assert_eq!(builder.output, Some(Payload::Raw(output.to_string())));
         assert_eq!(builder.recursive, Some(true));
     }
 
@@ -454,6 +518,47 @@ mod tests {
         assert_eq!(metadata.serialized_input, Some("test input".to_string()));
     }
 
+    #[test]
+    fn test_orchestration_metadata_builder_missing_field() {
+        let result = OrchestrationMetadata::builder()
+            .instance_id(InstanceID("test-id".to_string()))
+            .build();
+
+        match result {
+            Err(Error::InvalidArgument(message)) => assert_eq!(message, "name is required"),
+            _ => panic!("expected an invalid argument error"),
+        }
+    }
+
+    #[test]
+    fn test_orchestration_metadata_serialized_result() {
+        let completed = OrchestrationMetadata {
+            runtime_status: OrchestrationStatus::Completed,
+            serialized_output: Some("42".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(completed.serialized_result().unwrap(), Some("42"));
+
+        let running = OrchestrationMetadata::default();
+        assert!(matches!(
+            running.serialized_result(),
+            Err(Error::NotCompleted)
+        ));
+
+        let failed = OrchestrationMetadata {
+            runtime_status: OrchestrationStatus::Failed,
+            failure_details: Some(TaskFailureDetails {
+                error_message: "boom".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        match failed.serialized_result() {
+            Err(Error::OrchestrationFailed(details)) => assert_eq!(details.error_message, "boom"),
+            _ => panic!("expected the orchestration failure"),
+        }
+    }
+
     #[test]
     fn test_orchestration_metadata_is_running() {
         let mut metadata = OrchestrationMetadata::default();
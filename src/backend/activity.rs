@@ -0,0 +1,483 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::backend::deadletter::DeadLetter;
+use crate::backend::executor::Executor;
+use crate::backend::lock::WorkItemLock;
+use crate::backend::routing::WorkItemFilter;
+use crate::backend::throttle::{ActivityLimit, ActivityThrottle, TaskHubLimits, ThrottlePermit};
+use crate::backend::worker::TaskProcessor;
+use crate::backend::workitem::ActivityWorkItem;
+use crate::backend::{Backend, BackendError};
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::{HistoryEvent, TaskFailureDetails};
+use crate::internal::new_task_failed_event;
+use crate::task::activity::ActivityHooks;
+use crate::task::cancellation::CancellationSource;
+use crate::task::{ActivityOptions, CancellationToken, ACTIVITY_TIMEOUT_ERROR_TYPE};
+use crate::Error;
+
+/// Fetches activity work items and runs them through the [`Executor`].
+pub(crate) struct ActivityProcessor {
+    be: Arc<dyn Backend>,
+    executor: Arc<dyn Executor>,
+    filter: WorkItemFilter,
+    throttle: ActivityThrottle,
+    task_hub_limits: TaskHubLimits,
+    cancellation: CancellationToken,
+    /// Cancels the running activities whose instance was terminated, by sequence number.
+    cancelled_instances: Mutex<HashMap<i64, CancellationSource>>,
+    /// The lock expiries returned by the heartbeats of running activities, by sequence number.
+    heartbeat_leases: Mutex<HashMap<i64, SystemTime>>,
+}
+
+impl ActivityProcessor {
+    pub(crate) fn new(be: Arc<dyn Backend>, executor: Arc<dyn Executor>) -> Self {
+        ActivityProcessor {
+            be,
+            executor,
+            filter: WorkItemFilter::default(),
+            throttle: ActivityThrottle::default(),
+            task_hub_limits: TaskHubLimits::default(),
+            cancellation: CancellationToken::default(),
+            cancelled_instances: Mutex::default(),
+            heartbeat_leases: Mutex::default(),
+        }
+    }
+
+    /// Only fetches the activities that pass `filter`.
+    pub(crate) fn with_filter(mut self, filter: WorkItemFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Passes `cancellation` to the activities, to be cancelled when the worker shuts down.
+    pub(crate) fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Defers activities that reached their limit instead of running them.
+    pub(crate) fn with_limits(mut self, limits: &HashMap<String, ActivityLimit>) -> Self {
+        self.throttle = ActivityThrottle::new(limits);
+        self
+    }
+
+    /// Has the backend skip activities that reached their task hub wide limit.
+    pub(crate) fn with_task_hub_limits(mut self, limits: TaskHubLimits) -> Self {
+        self.task_hub_limits = limits;
+        self
+    }
+}
+
+#[async_trait]
+impl TaskProcessor for ActivityProcessor {
+    type WorkItem = ActivityWorkItem;
+
+    async fn fetch_work_item(&self) -> Result<ActivityWorkItem, BackendError> {
+        self.be
+            .get_activity_work_item(&self.filter, &self.task_hub_limits)
+            .await
+    }
+
+    async fn process_work_item(&self, wi: &mut ActivityWorkItem) -> Result<(), Error> {
+        // Later dispatches were delayed by an earlier attempt, not by waiting for a worker
+        if let Some(timeout) = wi.options.schedule_to_start_timeout() {
+            if wi.dispatch_count == 0 && time_in_queue(wi) > timeout {
+                wi.result = Some(timeout_failed_event(wi, ActivityTimeout::ScheduleToStart));
+                return Ok(());
+            }
+        }
+
+        let running = RunningActivity::new(self, wi.sequence_number);
+        // Cancelled when the worker shuts down, the activity times out or its instance is
+        // terminated
+        let attempt = CancellationSource::default();
+        let (heartbeats, mut received) = mpsc::unbounded_channel();
+        let hooks = ActivityHooks {
+            cancellation: attempt.token(),
+            heartbeats: Some(heartbeats),
+            heartbeat_details: wi.heartbeat_details.clone(),
+        };
+        let lock = wi.lock();
+        let execution = self
+            .executor
+            .execute_activity(&wi.instance_id, &wi.new_event, hooks);
+        tokio::pin!(execution);
+
+        let started = Instant::now();
+        let mut last_heartbeat = started;
+        let mut shutting_down = false;
+        let mut instance_cancelled = false;
+        let result = loop {
+            let next_timeout = next_timeout(&wi.options, started, last_heartbeat);
+            tokio::select! {
+                result = &mut execution => match result {
+                    // The backend drops the results of cancelled activities
+                    _ if instance_cancelled => break cancelled_failed_event(wi),
+                    result => break result?,
+                },
+                Some(details) = received.recv() => {
+                    last_heartbeat = Instant::now();
+                    if let Some(lock) = &lock {
+                        let recorded = self.be.record_activity_heartbeat(lock, details.as_deref());
+                        match recorded.await {
+                            Ok(expires_at) => {
+                                self.heartbeat_leases
+                                    .lock()
+                                    .unwrap()
+                                    .insert(wi.sequence_number, expires_at);
+                            }
+                            Err(BackendError::WorkItemCancelled) => self.cancel_work_item(lock),
+                            // A lost lease is noticed by the worker renewing it
+                            Err(_) => {}
+                        }
+                    }
+                }
+                _ = running.token.cancelled(), if !instance_cancelled => {
+                    instance_cancelled = true;
+                    attempt.cancel();
+                }
+                timeout = expire(next_timeout) => {
+                    attempt.cancel();
+                    break timeout_failed_event(wi, timeout);
+                }
+                _ = self.cancellation.cancelled(), if !shutting_down => {
+                    shutting_down = true;
+                    attempt.cancel();
+                }
+            }
+        };
+        wi.result = Some(result);
+        Ok(())
+    }
+
+    async fn complete_work_item(&self, wi: &ActivityWorkItem) -> Result<(), BackendError> {
+        self.be.complete_activity_work_item(wi).await
+    }
+
+    async fn abandon_work_item(
+        &self,
+        wi: &ActivityWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.be.abandon_activity_work_item(wi, delay).await
+    }
+
+    fn try_acquire(&self, wi: &ActivityWorkItem) -> Result<ThrottlePermit, Duration> {
+        match &wi.new_event.event_type {
+            Some(EventType::TaskScheduled(scheduled)) => self.throttle.try_acquire(&scheduled.name),
+            _ => Ok(ThrottlePermit::unlimited()),
+        }
+    }
+
+    async fn defer_work_item(
+        &self,
+        wi: &ActivityWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.be.defer_activity_work_item(wi, delay).await
+    }
+
+    fn retry_count(&self, wi: &ActivityWorkItem) -> i32 {
+        wi.retry_count
+    }
+
+    async fn dead_letter_work_item(
+        &self,
+        wi: &ActivityWorkItem,
+        failure_details: &TaskFailureDetails,
+    ) -> Result<(), BackendError> {
+        let dead_letter = DeadLetter::from_activity_work_item(wi, failure_details);
+        self.be
+            .dead_letter_activity_work_item(wi, &dead_letter)
+            .await
+    }
+
+    fn work_item_lock(&self, wi: &ActivityWorkItem) -> Option<WorkItemLock> {
+        wi.lock()
+    }
+
+    async fn renew_work_item_lock(&self, lock: &WorkItemLock) -> Result<SystemTime, BackendError> {
+        self.be.renew_work_item_lock(lock).await
+    }
+
+    fn renewed_lock_expiry(&self, lock: &WorkItemLock) -> Option<SystemTime> {
+        let sequence_number = lock.sequence_number?;
+        self.heartbeat_leases
+            .lock()
+            .unwrap()
+            .get(&sequence_number)
+            .copied()
+    }
+
+    fn cancel_work_item(&self, lock: &WorkItemLock) {
+        let Some(sequence_number) = lock.sequence_number else {
+            return;
+        };
+        if let Some(source) = self
+            .cancelled_instances
+            .lock()
+            .unwrap()
+            .get(&sequence_number)
+        {
+            source.cancel();
+        }
+    }
+}
+
+/// Registers a running activity so it can be cancelled when its instance is terminated, until
+/// it's dropped along with the leases its heartbeats renewed.
+struct RunningActivity<'a> {
+    processor: &'a ActivityProcessor,
+    sequence_number: i64,
+    token: CancellationToken,
+}
+
+impl<'a> RunningActivity<'a> {
+    fn new(processor: &'a ActivityProcessor, sequence_number: i64) -> Self {
+        let source = CancellationSource::default();
+        let token = source.token();
+        processor
+            .cancelled_instances
+            .lock()
+            .unwrap()
+            .insert(sequence_number, source);
+        RunningActivity {
+            processor,
+            sequence_number,
+            token,
+        }
+    }
+}
+
+impl Drop for RunningActivity<'_> {
+    fn drop(&mut self) {
+        self.processor
+            .cancelled_instances
+            .lock()
+            .unwrap()
+            .remove(&self.sequence_number);
+        self.processor
+            .heartbeat_leases
+            .lock()
+            .unwrap()
+            .remove(&self.sequence_number);
+    }
+}
+
+/// The [`ActivityOptions`] timeout an activity exceeded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ActivityTimeout {
+    ScheduleToStart,
+    StartToClose,
+    Heartbeat,
+}
+
+impl ActivityTimeout {
+    fn limit(self, options: &ActivityOptions) -> Option<Duration> {
+        match self {
+            ActivityTimeout::ScheduleToStart => options.schedule_to_start_timeout(),
+            ActivityTimeout::StartToClose => options.start_to_close_timeout(),
+            ActivityTimeout::Heartbeat => options.heartbeat_timeout(),
+        }
+    }
+}
+
+impl fmt::Display for ActivityTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActivityTimeout::ScheduleToStart => write!(f, "schedule-to-start"),
+            ActivityTimeout::StartToClose => write!(f, "start-to-close"),
+            ActivityTimeout::Heartbeat => write!(f, "heartbeat"),
+        }
+    }
+}
+
+/// How long the activity waited to be started since it was scheduled.
+fn time_in_queue(wi: &ActivityWorkItem) -> Duration {
+    wi.new_event
+        .timestamp
+        .clone()
+        .and_then(|timestamp| SystemTime::try_from(timestamp).ok())
+        .and_then(|scheduled| scheduled.elapsed().ok())
+        .unwrap_or_default()
+}
+
+/// The timeout a running activity exceeds first unless it completes or heartbeats, and when.
+fn next_timeout(
+    options: &ActivityOptions,
+    started: Instant,
+    last_heartbeat: Instant,
+) -> Option<(Instant, ActivityTimeout)> {
+    let start_to_close = options
+        .start_to_close_timeout()
+        .map(|timeout| (started + timeout, ActivityTimeout::StartToClose));
+    let heartbeat = options
+        .heartbeat_timeout()
+        .map(|timeout| (last_heartbeat + timeout, ActivityTimeout::Heartbeat));
+    match (start_to_close, heartbeat) {
+        (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Waits for `timeout` to expire, which is never if there's none.
+async fn expire(timeout: Option<(Instant, ActivityTimeout)>) -> ActivityTimeout {
+    match timeout {
+        Some((deadline, timeout)) => {
+            tokio::time::sleep_until(deadline).await;
+            timeout
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn cancelled_failed_event(wi: &ActivityWorkItem) -> HistoryEvent {
+    let details = TaskFailureDetails {
+        error_type: "ActivityCancelled".to_string(),
+        error_message: format!("activity of instance '{}' was cancelled", wi.instance_id),
+        ..Default::default()
+    };
+    new_task_failed_event(wi.new_event.event_id, Some(&details))
+}
+
+fn timeout_failed_event(wi: &ActivityWorkItem, timeout: ActivityTimeout) -> HistoryEvent {
+    let name = match &wi.new_event.event_type {
+        Some(EventType::TaskScheduled(scheduled)) => scheduled.name.as_str(),
+        _ => "",
+    };
+    let details = TaskFailureDetails {
+        error_type: ACTIVITY_TIMEOUT_ERROR_TYPE.to_string(),
+        error_message: format!(
+            "activity '{name}' exceeded its {timeout} timeout of {:?}",
+            timeout.limit(&wi.options).unwrap_or_default()
+        ),
+        ..Default::default()
+    };
+    new_task_failed_event(wi.new_event.event_id, Some(&details))
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_wkt_types::Timestamp;
+
+    use super::*;
+    use crate::backend::routing::TaskRoute;
+    use crate::backend::testing::TestBackend;
+    use crate::internal::new_task_scheduled_event;
+    use crate::payload::{JsonCodec, PayloadPipeline};
+    use crate::task::executor::TaskExecutor;
+    use crate::task::TaskRegistry;
+
+    fn test_processor(be: Arc<TestBackend>) -> ActivityProcessor {
+        let executor = TaskExecutor::new(
+            Arc::new(TaskRegistry::new()),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        ActivityProcessor::new(be, Arc::new(executor))
+    }
+
+    fn failure_message(wi: &ActivityWorkItem) -> String {
+        match wi.result.as_ref().and_then(|e| e.event_type.as_ref()) {
+            Some(EventType::TaskFailed(failed)) => failed
+                .failure_details
+                .as_ref()
+                .map(|details| details.error_message.clone())
+                .unwrap_or_default(),
+            _ => panic!("expected a failed task"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_respects_task_hub_limits() {
+        let be = Arc::new(TestBackend::default());
+        let limits = TaskHubLimits::default().with_activity_concurrency("charge", 1);
+        let processor = test_processor(be.clone()).with_task_hub_limits(limits);
+        for (task_id, name) in [(0, "charge"), (1, "charge"), (2, "geocode")] {
+            let event = new_task_scheduled_event(task_id, name, None, None, None);
+            be.enqueue_activity("abc", event, TaskRoute::default());
+        }
+
+        let first = processor.fetch_work_item().await.unwrap();
+        // The second charge waits for the first, while other activities aren't limited
+        let next = processor.fetch_work_item().await.unwrap();
+        assert_eq!(next.new_event.event_id, 2);
+        assert!(matches!(
+            processor.fetch_work_item().await,
+            Err(BackendError::NoWorkItems)
+        ));
+
+        processor.complete_work_item(&first).await.unwrap();
+        let second = processor.fetch_work_item().await.unwrap();
+        assert_eq!(second.new_event.event_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_to_start_timeout_only_on_first_dispatch() {
+        let be = Arc::new(TestBackend::default());
+        let processor = test_processor(be.clone());
+        let mut event = new_task_scheduled_event(0, "charge", None, None, None);
+        event.timestamp = Some(Timestamp::from(SystemTime::now() - Duration::from_secs(60)));
+        be.enqueue_activity("abc", event, TaskRoute::default());
+        let mut wi = processor.fetch_work_item().await.unwrap();
+        wi.options = ActivityOptions::new().with_schedule_to_start_timeout(Duration::from_secs(1));
+
+        processor.process_work_item(&mut wi).await.unwrap();
+        assert!(failure_message(&wi).contains("schedule-to-start"));
+
+        // A retried activity already started once, so it runs
+        wi.result = None;
+        wi.dispatch_count = 1;
+        processor.process_work_item(&mut wi).await.unwrap();
+        assert!(!failure_message(&wi).contains("schedule-to-start"));
+    }
+
+    #[test]
+    fn test_next_timeout() {
+        let started = Instant::now();
+        let options = ActivityOptions::new();
+        assert_eq!(next_timeout(&options, started, started), None);
+
+        let options = options
+            .with_start_to_close_timeout(Duration::from_secs(60))
+            .with_heartbeat_timeout(Duration::from_secs(10));
+        assert_eq!(
+            next_timeout(&options, started, started),
+            Some((
+                started + Duration::from_secs(10),
+                ActivityTimeout::Heartbeat
+            ))
+        );
+        let last_heartbeat = started + Duration::from_secs(55);
+        assert_eq!(
+            next_timeout(&options, started, last_heartbeat),
+            Some((
+                started + Duration::from_secs(60),
+                ActivityTimeout::StartToClose
+            ))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::time::Duration;
+
+use backon::BackoffBuilder;
+
+/// How long an abandoned work item waits before it's retried.
+///
+/// Any [`backon`] backoff builder can be used, e.g. `ConstantBuilder` or `ExponentialBuilder`
+/// with jitter. The n-th retry waits for the n-th delay of the backoff, or its last delay once
+/// it runs out of retries.
+pub trait AbandonBackoff: Send + Sync {
+    /// The delay of a work item that was retried `retry_count` times before.
+    fn delay(&self, retry_count: u32) -> Duration;
+}
+
+impl<B: BackoffBuilder> AbandonBackoff for B {
+    fn delay(&self, retry_count: u32) -> Duration {
+        self.build()
+            .take(retry_count as usize + 1)
+            .last()
+            .unwrap_or_default()
+    }
+}
+
+/// A backoff that retries right away, then waits `step` longer for every retry, up to `max`.
+#[derive(Clone, Debug)]
+pub struct LinearBuilder {
+    step: Duration,
+    max_delay: Duration,
+}
+
+impl Default for LinearBuilder {
+    fn default() -> Self {
+        LinearBuilder {
+            step: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl LinearBuilder {
+    pub fn with_step(mut self, step: Duration) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+impl BackoffBuilder for LinearBuilder {
+    type Backoff = LinearBackoff;
+
+    fn build(&self) -> LinearBackoff {
+        LinearBackoff {
+            step: self.step,
+            max_delay: self.max_delay,
+            current_delay: None,
+        }
+    }
+}
+
+/// The backoff built by a [`LinearBuilder`]. It never runs out of retries.
+#[derive(Debug)]
+pub struct LinearBackoff {
+    step: Duration,
+    max_delay: Duration,
+    current_delay: Option<Duration>,
+}
+
+impl Iterator for LinearBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = match self.current_delay {
+            None => Duration::ZERO,
+            Some(delay) => delay.saturating_add(self.step).min(self.max_delay),
+        };
+        self.current_delay = Some(delay);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backon::{ConstantBuilder, ExponentialBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_linear_backoff() {
+        let backoff = LinearBuilder::default().with_max_delay(Duration::from_secs(3));
+        let delays: Vec<_> = (0..5).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, [0, 1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_backon_backoffs() {
+        let constant = ConstantBuilder::default().with_delay(Duration::from_secs(2));
+        assert_eq!(constant.delay(0), Duration::from_secs(2));
+        // The builder's retry limit only stops the delays from growing
+        assert_eq!(constant.delay(100), Duration::from_secs(2));
+
+        let exponential = ExponentialBuilder::default()
+            .with_min_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(10))
+            .with_max_times(usize::MAX);
+        let delays: Vec<_> = (0..6).map(|n| exponential.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+
+        let jittered = exponential.with_jitter();
+        let delay = jittered.delay(2);
+        assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,190 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+/// Limits for the extended session caches of a
+/// [`TaskHubWorker`](crate::backend::worker::TaskHubWorker).
+///
+/// With extended sessions the worker keeps the runtime state and the suspended orchestrator of
+/// recently active instances in memory, so their next work item only processes its new events
+/// instead of reloading and replaying the full history.
+#[derive(Clone, Debug)]
+pub struct ExtendedSessionOptions {
+    max_entries: usize,
+    max_memory_bytes: usize,
+    idle_timeout: Duration,
+}
+
+impl Default for ExtendedSessionOptions {
+    fn default() -> Self {
+        ExtendedSessionOptions {
+            max_entries: 1000,
+            max_memory_bytes: 256 * 1024 * 1024,
+            idle_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl ExtendedSessionOptions {
+    /// Sets the most instances kept in memory. Least recently used instances are evicted first.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Sets the approximate memory, based on the encoded size of the cached history, that
+    /// cached instances may use.
+    pub fn with_max_memory_bytes(mut self, max_memory_bytes: usize) -> Self {
+        self.max_memory_bytes = max_memory_bytes;
+        self
+    }
+
+    /// Sets how long an instance stays cached without receiving a work item.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+}
+
+/// An LRU cache of per-instance session values, bounded by entry count, approximate memory
+/// and idle time.
+///
+/// Values are taken out while a work item is processed and put back once it's committed, so an
+/// entry is never shared between concurrent work items.
+pub(crate) struct SessionCache<V> {
+    options: ExtendedSessionOptions,
+    inner: Mutex<Sessions<V>>,
+}
+
+struct Sessions<V> {
+    entries: LruCache<String, Session<V>>,
+    memory_bytes: usize,
+}
+
+struct Session<V> {
+    value: V,
+    size: usize,
+    last_used: Instant,
+}
+
+impl<V> SessionCache<V> {
+    pub(crate) fn new(options: ExtendedSessionOptions) -> Self {
+        SessionCache {
+            options,
+            inner: Mutex::new(Sessions {
+                entries: LruCache::unbounded(),
+                memory_bytes: 0,
+            }),
+        }
+    }
+
+    /// Removes and returns the cached value for `instance_id`.
+    pub(crate) fn take(&self, instance_id: &str) -> Option<V> {
+        let mut sessions = self.inner.lock().unwrap();
+        self.evict_idle(&mut sessions);
+        let session = sessions.entries.pop(instance_id)?;
+        sessions.memory_bytes -= session.size;
+        Some(session.value)
+    }
+
+    /// Caches `value` for `instance_id`, evicting other instances to stay within the limits.
+    /// Values larger than the memory limit aren't cached.
+    pub(crate) fn put(&self, instance_id: &str, value: V, size: usize) {
+        let mut sessions = self.inner.lock().unwrap();
+        if let Some(previous) = sessions.entries.pop(instance_id) {
+            sessions.memory_bytes -= previous.size;
+        }
+        if self.options.max_entries == 0 || size > self.options.max_memory_bytes {
+            return;
+        }
+        self.evict_idle(&mut sessions);
+        while sessions.entries.len() >= self.options.max_entries
+            || sessions.memory_bytes + size > self.options.max_memory_bytes
+        {
+            let Some((_, evicted)) = sessions.entries.pop_lru() else {
+                break;
+            };
+            sessions.memory_bytes -= evicted.size;
+        }
+        sessions.memory_bytes += size;
+        sessions.entries.put(
+            instance_id.to_string(),
+            Session {
+                value,
+                size,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    fn evict_idle(&self, sessions: &mut Sessions<V>) {
+        while let Some((_, session)) = sessions.entries.peek_lru() {
+            if session.last_used.elapsed() < self.options.idle_timeout {
+                break;
+            }
+            if let Some((_, evicted)) = sessions.entries.pop_lru() {
+                sessions.memory_bytes -= evicted.size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_cache_limits() {
+        let cache = SessionCache::new(
+            ExtendedSessionOptions::default()
+                .with_max_entries(2)
+                .with_max_memory_bytes(100),
+        );
+        cache.put("a", 1, 10);
+        cache.put("b", 2, 10);
+        cache.put("c", 3, 10);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.take("a"), None);
+        assert_eq!(cache.take("b"), Some(2));
+        assert_eq!(cache.take("b"), None);
+
+        cache.put("d", 4, 60);
+        cache.put("e", 5, 60);
+        assert_eq!(cache.take("c"), None);
+        assert_eq!(cache.take("d"), None);
+        assert_eq!(cache.take("e"), Some(5));
+
+        cache.put("f", 6, 101);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_session_cache_idle_eviction() {
+        let cache =
+            SessionCache::new(ExtendedSessionOptions::default().with_idle_timeout(Duration::ZERO));
+        cache.put("a", 1, 10);
+        assert_eq!(cache.take("a"), None);
+        assert_eq!(cache.len(), 0);
+    }
+}
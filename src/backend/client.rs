@@ -0,0 +1,524 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::api::{
+    InstanceID, NewOrchestration, NewOrchestrationBuilder, OrchestrationMetadata,
+    RaiseEventBuilder, TerminateBuilder,
+};
+use crate::backend::deadletter::DeadLetter;
+use crate::backend::logger::new_logger;
+use crate::backend::routing::TaskRouting;
+use crate::backend::schedule::{
+    InMemoryScheduleStore, ProcessedSchedules, Schedule, ScheduleStatus, ScheduleStore,
+};
+use crate::backend::{
+    purge_orchestration_state, with_orchestration_id_reuse_policy, Backend, BackendError,
+};
+use crate::durabletask_pb::HistoryEvent;
+use crate::internal::{
+    new_event_raised_event, new_execution_started_event, new_execution_terminated_event,
+};
+use crate::payload::{JsonCodec, PayloadCodec, PayloadField, PayloadPipeline, PayloadTransformer};
+use crate::Error;
+
+/// A client for managing orchestrations and schedules directly against a [`Backend`].
+pub struct TaskHubClient {
+    be: Arc<dyn Backend>,
+    schedules: Arc<dyn ScheduleStore>,
+    codec: Arc<dyn PayloadCodec>,
+    payloads: PayloadPipeline,
+    routing: TaskRouting,
+}
+
+impl TaskHubClient {
+    pub fn new(be: Arc<dyn Backend>) -> Self {
+        TaskHubClient {
+            be,
+            schedules: Arc::new(InMemoryScheduleStore::default()),
+            codec: Arc::new(JsonCodec),
+            payloads: PayloadPipeline::default(),
+            routing: TaskRouting::default(),
+        }
+    }
+
+    /// Sets the codec used to serialize inputs, event data and termination output. This must
+    /// match the codec used by the workers of the same task hub.
+    pub fn with_codec(mut self, codec: Arc<dyn PayloadCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Adds a transformer applied to payloads after they're encoded, e.g. to encrypt them.
+    /// Workers of the same task hub must be configured with the same transformers.
+    pub fn with_payload_transformer(mut self, transformer: Arc<dyn PayloadTransformer>) -> Self {
+        self.payloads.push(transformer);
+        self
+    }
+
+    pub fn codec(&self) -> &dyn PayloadCodec {
+        self.codec.as_ref()
+    }
+
+    /// Sets the task routing used to prioritize new orchestrations that don't set a priority.
+    /// This should match the routing of the workers of the same task hub.
+    pub fn with_task_routing(mut self, routing: TaskRouting) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    pub fn with_schedule_store(mut self, store: Arc<dyn ScheduleStore>) -> Self {
+        self.schedules = store;
+        self
+    }
+
+    /// Schedules a new instance of the orchestration `name`, returning its instance ID.
+    ///
+    /// A random instance ID is generated when `orchestration` doesn't specify one.
+    pub async fn schedule_new_orchestration(
+        &self,
+        name: &str,
+        orchestration: NewOrchestrationBuilder,
+    ) -> Result<InstanceID, Error> {
+        let priority = orchestration.priority;
+        let orchestration = orchestration.build_with(self.codec.as_ref())?;
+        let instance_id = if orchestration.instance_id.is_empty() {
+            Uuid::new_v4().to_string()
+        } else {
+            orchestration.instance_id
+        };
+        let input = self
+            .payloads
+            .seal_option(&instance_id, PayloadField::Input, orchestration.input)
+            .await?;
+
+        let e = new_execution_started_event(
+            name,
+            &instance_id,
+            input.as_deref(),
+            None,
+            None,
+            orchestration.scheduled_start_timestamp,
+        );
+        let priority = priority.unwrap_or_else(|| self.routing.orchestration_priority(&e));
+        let result = self
+            .be
+            .create_orchestration_instance(
+                &e,
+                priority,
+                vec![with_orchestration_id_reuse_policy(
+                    orchestration.orchestration_id_reuse_policy,
+                )],
+            )
+            .await;
+
+        match result {
+            // The reuse policy chose to keep the existing instance
+            Ok(()) | Err(BackendError::IgnoreInstance) => Ok(InstanceID(instance_id)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Raises the event `name` on a running orchestration instance.
+    pub async fn raise_event(
+        &self,
+        instance_id: &InstanceID,
+        name: &str,
+        event: RaiseEventBuilder,
+    ) -> Result<(), Error> {
+        let data = event.encode_payload(self.codec.as_ref())?;
+        let data = self
+            .payloads
+            .seal_option(&instance_id.0, PayloadField::EventData, data)
+            .await?;
+        let e = new_event_raised_event(name, data.as_deref());
+        Ok(self
+            .be
+            .add_new_orchestration_event(&instance_id.0, &e)
+            .await?)
+    }
+
+    pub async fn terminate_orchestration(
+        &self,
+        instance_id: &InstanceID,
+        terminate: TerminateBuilder,
+    ) -> Result<(), Error> {
+        let output = terminate.encode_output(self.codec.as_ref())?;
+        let output = self
+            .payloads
+            .seal_option(&instance_id.0, PayloadField::Reason, output)
+            .await?;
+        let e = new_execution_terminated_event(output.as_deref(), terminate.is_recursive());
+        Ok(self
+            .be
+            .add_new_orchestration_event(&instance_id.0, &e)
+            .await?)
+    }
+
+    /// Fetches the metadata of an orchestration instance. The input, output and custom status
+    /// are only included when `fetch_payloads` is set, which may read offloaded payloads.
+    pub async fn fetch_orchestration_metadata(
+        &self,
+        instance_id: &InstanceID,
+        fetch_payloads: bool,
+    ) -> Result<OrchestrationMetadata, Error> {
+        let mut metadata = self.be.get_orchestration_metadata(&instance_id.0).await?;
+        if fetch_payloads {
+            metadata.serialized_input = self
+                .payloads
+                .unseal_option(
+                    &instance_id.0,
+                    PayloadField::Input,
+                    metadata.serialized_input,
+                )
+                .await?;
+            metadata.serialized_output = self
+                .payloads
+                .unseal_option(
+                    &instance_id.0,
+                    PayloadField::Output,
+                    metadata.serialized_output,
+                )
+                .await?;
+            metadata.serialized_custom_status = self
+                .payloads
+                .unseal_option(
+                    &instance_id.0,
+                    PayloadField::CustomStatus,
+                    metadata.serialized_custom_status,
+                )
+                .await?;
+        } else {
+            metadata.serialized_input = None;
+            metadata.serialized_output = None;
+            metadata.serialized_custom_status = None;
+        }
+        self.payloads
+            .unseal_failure(&instance_id.0, metadata.failure_details.as_mut())
+            .await?;
+        Ok(metadata)
+    }
+
+    /// Fetches the history of an orchestration instance, or of one of its executions when
+    /// `execution_id` is set. Payloads that can't be unsealed are returned as they're stored.
+    pub async fn get_orchestration_history(
+        &self,
+        instance_id: &InstanceID,
+        execution_id: Option<&str>,
+    ) -> Result<Vec<HistoryEvent>, Error> {
+        let mut history = self
+            .be
+            .get_orchestration_history(&instance_id.0, execution_id)
+            .await?;
+        // Payloads that can't be read anymore, e.g. whose blob was deleted, are left sealed
+        for e in history.iter_mut() {
+            self.payloads.unseal_event_or_keep(&instance_id.0, e).await;
+        }
+        Ok(history)
+    }
+
+    /// Lists the work items that were dead-lettered after exhausting their retries.
+    pub async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, Error> {
+        let mut dead_letters = self.be.list_dead_letters().await?;
+        for dead_letter in dead_letters.iter_mut() {
+            self.unseal_dead_letter(dead_letter).await?;
+        }
+        Ok(dead_letters)
+    }
+
+    pub async fn get_dead_letter(&self, id: &str) -> Result<DeadLetter, Error> {
+        let mut dead_letter = self
+            .be
+            .get_dead_letter(id)
+            .await?
+            .ok_or(Error::DeadLetterNotFound)?;
+        self.unseal_dead_letter(&mut dead_letter).await?;
+        Ok(dead_letter)
+    }
+
+    /// Puts a dead-lettered work item back on its queue, resuming its instance.
+    pub async fn requeue_dead_letter(&self, id: &str) -> Result<(), Error> {
+        if self.be.requeue_dead_letter(id).await? {
+            Ok(())
+        } else {
+            Err(Error::DeadLetterNotFound)
+        }
+    }
+
+    async fn unseal_dead_letter(&self, dead_letter: &mut DeadLetter) -> Result<(), Error> {
+        for e in dead_letter.events.iter_mut() {
+            self.payloads
+                .unseal_event(&dead_letter.instance_id.0, e)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the state of a completed orchestration instance, and of its sub-orchestrations
+    /// when `recursive` is set, returning the number of instances that were purged.
+    ///
+    /// Payloads held outside of history for the purged instances, e.g. offloaded blobs, are
+    /// released as well.
+    pub async fn purge_orchestration_state(
+        &self,
+        instance_id: &InstanceID,
+        recursive: bool,
+    ) -> Result<usize, Error> {
+        let purged = purge_orchestration_state(self.be.as_ref(), instance_id, recursive).await?;
+        for id in &purged {
+            self.payloads.purge(&id.0).await?;
+        }
+        Ok(purged.len())
+    }
+
+    pub async fn create_schedule(&self, schedule: Schedule) -> Result<(), Error> {
+        if self.schedules.create_schedule(&schedule).await? {
+            Ok(())
+        } else {
+            Err(Error::ScheduleExists)
+        }
+    }
+
+    pub async fn get_schedule(&self, id: &str) -> Result<Schedule, Error> {
+        self.schedules
+            .get_schedule(id)
+            .await?
+            .ok_or(Error::ScheduleNotFound)
+    }
+
+    pub async fn list_schedules(&self) -> Result<Vec<Schedule>, Error> {
+        Ok(self.schedules.list_schedules().await?)
+    }
+
+    /// Stops a schedule from starting new instances until it's resumed.
+    pub async fn pause_schedule(&self, id: &str) -> Result<(), Error> {
+        self.update_schedule(id, |schedule| schedule.status = ScheduleStatus::Paused)
+            .await
+    }
+
+    /// Resumes a paused schedule. Ticks that elapsed while paused are skipped.
+    pub async fn resume_schedule(&self, id: &str) -> Result<(), Error> {
+        self.update_schedule(id, |schedule| {
+            if schedule.is_paused() {
+                schedule.status = ScheduleStatus::Active;
+                schedule.advance(SystemTime::now());
+            }
+        })
+        .await
+    }
+
+    /// Applies `update` to the schedule `id`, reapplying it if the schedule was changed
+    /// concurrently.
+    async fn update_schedule(&self, id: &str, update: impl Fn(&mut Schedule)) -> Result<(), Error> {
+        loop {
+            let current = self.get_schedule(id).await?;
+            let mut schedule = current.clone();
+            update(&mut schedule);
+            if self.schedules.update_schedule(&current, &schedule).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn delete_schedule(&self, id: &str) -> Result<(), Error> {
+        if self.schedules.delete_schedule(id).await? {
+            Ok(())
+        } else {
+            Err(Error::ScheduleNotFound)
+        }
+    }
+
+    /// Starts an orchestration for every schedule that is due at `now`.
+    ///
+    /// A schedule is only advanced once its instance was created, so a failed tick is retried
+    /// by the next call. Failing schedules don't hold up the others.
+    pub async fn process_due_schedules(
+        &self,
+        now: SystemTime,
+    ) -> Result<ProcessedSchedules, Error> {
+        let mut processed = ProcessedSchedules::default();
+        for schedule in self.schedules.list_schedules().await? {
+            if !schedule.is_due(now) {
+                continue;
+            }
+            match self.process_due_schedule(&schedule, now).await {
+                Ok(Some(instance_id)) => processed.started.push(instance_id),
+                Ok(None) => {}
+                Err(e) => processed.failed.push((schedule.id, e)),
+            }
+        }
+        Ok(processed)
+    }
+
+    /// Starts the instance of the due tick of `schedule` and advances it. Returns `None` if the
+    /// schedule was changed or deleted meanwhile; a tick that's started again maps onto the
+    /// same instance.
+    async fn process_due_schedule(
+        &self,
+        schedule: &Schedule,
+        now: SystemTime,
+    ) -> Result<Option<InstanceID>, Error> {
+        let tick = schedule.next_run_at.expect("due schedules have a next run");
+        let mut builder = NewOrchestration::builder()
+            .instance_id(schedule.instance_id_for(tick))
+            .orchestration_id_reuse_policy(schedule.orchestration_id_reuse_policy.clone());
+        if let Some(input) = &schedule.input {
+            builder = builder.raw_input(input.clone());
+        }
+        let instance_id = self
+            .schedule_new_orchestration(&schedule.orchestration_name, builder)
+            .await?;
+
+        let mut advanced = schedule.clone();
+        advanced.last_run_at = Some(tick);
+        advanced.last_instance_id = Some(instance_id.clone());
+        advanced.advance(now);
+        if self.schedules.update_schedule(schedule, &advanced).await? {
+            Ok(Some(instance_id))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Processes due schedules every `poll_interval` until the returned future is dropped.
+    pub async fn run_schedules(&self, poll_interval: Duration) {
+        loop {
+            // Failed ticks aren't advanced and are retried on the next poll.
+            match self.process_due_schedules(SystemTime::now()).await {
+                Ok(processed) => {
+                    for (id, e) in processed.failed {
+                        new_logger().error(format!("failed to process schedule '{id}': {e}"));
+                    }
+                }
+                Err(e) => new_logger().error(format!("failed to process schedules: {e}")),
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::backend::testing::TestBackend;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn hourly(id: &str) -> Schedule {
+        Schedule::builder()
+            .id(id)
+            .orchestration_name("report")
+            .every(Duration::from_secs(3600))
+            .start_at(at(0))
+            .build()
+            .unwrap()
+    }
+
+    fn client() -> (Arc<TestBackend>, TaskHubClient) {
+        let be = Arc::new(TestBackend::default());
+        (be.clone(), TaskHubClient::new(be))
+    }
+
+    #[tokio::test]
+    async fn test_schedule_lifecycle() {
+        let (_, client) = client();
+        client.create_schedule(hourly("report")).await.unwrap();
+        let duplicate = client.create_schedule(hourly("report")).await;
+        assert!(matches!(duplicate, Err(Error::ScheduleExists)));
+
+        client.pause_schedule("report").await.unwrap();
+        let paused = client.get_schedule("report").await.unwrap();
+        assert!(paused.is_paused());
+        assert!(!paused.is_due(at(7200)));
+
+        client.resume_schedule("report").await.unwrap();
+        let resumed = client.get_schedule("report").await.unwrap();
+        assert!(!resumed.is_paused());
+        // Ticks missed while paused are skipped
+        assert!(resumed.next_run_at.unwrap() > SystemTime::now());
+
+        client.delete_schedule("report").await.unwrap();
+        assert!(matches!(
+            client.get_schedule("report").await,
+            Err(Error::ScheduleNotFound)
+        ));
+        assert!(matches!(
+            client.pause_schedule("report").await,
+            Err(Error::ScheduleNotFound)
+        ));
+        assert!(matches!(
+            client.delete_schedule("report").await,
+            Err(Error::ScheduleNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process_due_schedules() {
+        let (be, client) = client();
+        client.create_schedule(hourly("a")).await.unwrap();
+        client.create_schedule(hourly("b")).await.unwrap();
+        let failing = hourly("a").instance_id_for(at(0));
+        be.failing_instances
+            .lock()
+            .unwrap()
+            .insert(failing.0.clone());
+
+        // The failing schedule doesn't hold up the other one
+        let processed = client.process_due_schedules(at(10)).await.unwrap();
+        assert_eq!(processed.started, vec![hourly("b").instance_id_for(at(0))]);
+        assert_eq!(processed.failed.len(), 1);
+        assert_eq!(processed.failed[0].0, "a");
+        assert!(be.state(&processed.started[0].0).is_some());
+
+        let b = client.get_schedule("b").await.unwrap();
+        assert_eq!(b.next_run_at, Some(at(3600)));
+        assert_eq!(b.last_instance_id, Some(processed.started[0].clone()));
+        let a = client.get_schedule("a").await.unwrap();
+        assert_eq!(a.next_run_at, Some(at(0)));
+
+        // The failed tick is retried
+        be.failing_instances.lock().unwrap().clear();
+        let processed = client.process_due_schedules(at(20)).await.unwrap();
+        assert_eq!(processed.started, vec![failing]);
+        assert!(processed.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_schedule_not_restored() {
+        let (_, client) = client();
+        let schedule = hourly("report");
+        client.create_schedule(schedule.clone()).await.unwrap();
+        client.delete_schedule("report").await.unwrap();
+
+        // A pass that read the schedule before it was deleted doesn't save it back
+        let started = client
+            .process_due_schedule(&schedule, at(10))
+            .await
+            .unwrap();
+        assert_eq!(started, None);
+        assert!(matches!(
+            client.get_schedule("report").await,
+            Err(Error::ScheduleNotFound)
+        ));
+    }
+}
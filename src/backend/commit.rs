@@ -0,0 +1,413 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::api::{InstanceID, OrchestrationIdReusePolicy};
+use crate::backend::routing::{Priority, TaskRoute, TaskRouting};
+use crate::backend::workitem::OrchestrationWorkItem;
+use crate::backend::{
+    route_pending_messages, with_orchestration_id_reuse_policy, Backend, BackendError,
+    InstanceMessages,
+};
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::{
+    HistoryEvent, OrchestrationStatus, ParentInstanceInfo, TaskFailureDetails,
+};
+use crate::internal::new_sub_orchestration_failed_event;
+use crate::task::ActivityOptions;
+
+/// How a commit changes the history of the instance.
+///
+/// History spans all executions of the instance, each starting with its `ExecutionStarted`
+/// event, or the `OrchestratorStarted` event right before it. See
+/// [`filter_history_by_execution`](crate::backend::filter_history_by_execution).
+#[derive(Debug, PartialEq)]
+pub enum HistoryUpdate {
+    /// Appends the events to the committed history. When the orchestration continued as new,
+    /// they end the current execution and start the next ones.
+    Append(Vec<HistoryEvent>),
+    /// Replaces the committed events of the current execution with the events, after a
+    /// snapshot. Earlier executions are kept.
+    Replace(Vec<HistoryEvent>),
+}
+
+/// Everything a completed orchestration work item changes, which
+/// [`Backend::complete_orchestration_work_item`] must commit atomically: either all of it is
+/// saved and the work item's lock is released, or none of it is.
+#[derive(Debug)]
+pub struct OrchestrationCommit {
+    pub instance_id: InstanceID,
+    /// The execution the work item ran, which activities and timers are addressed to.
+    pub execution_id: Option<String>,
+    pub history: HistoryUpdate,
+    pub runtime_status: OrchestrationStatus,
+    pub custom_status: Option<String>,
+    /// `TaskScheduled` events to enqueue as activity work items.
+    pub activities: Vec<HistoryEvent>,
+    /// The task queue and priority of each activity, by task ID.
+    pub activity_routes: HashMap<i32, TaskRoute>,
+    /// The options of the activities scheduled with any, by task ID. They're saved with the
+    /// activities' work items, see
+    /// [`ActivityWorkItem::options`](crate::backend::workitem::ActivityWorkItem::options).
+    pub activity_options: HashMap<i32, ActivityOptions>,
+    /// `TimerFired` events to deliver to the instance once their `fire_at` time is reached.
+    pub timers: Vec<HistoryEvent>,
+    /// Events for other instances, grouped by [`route_pending_messages`].
+    pub messages: Vec<InstanceMessages>,
+}
+
+impl OrchestrationCommit {
+    /// Collects the changes of `wi`, routing the activities and orchestrations it starts with
+    /// `routing`.
+    pub(crate) fn new(wi: &OrchestrationWorkItem, routing: &TaskRouting) -> Self {
+        let state = &wi.state;
+        let history = if let Some(snapshot) = state.snapshot() {
+            let mut events = snapshot.to_vec();
+            events.extend_from_slice(state.new_events());
+            HistoryUpdate::Replace(events)
+        } else {
+            let mut events = state.ended_executions().to_vec();
+            events.extend_from_slice(state.new_events());
+            HistoryUpdate::Append(events)
+        };
+        OrchestrationCommit {
+            instance_id: wi.instance_id.clone(),
+            execution_id: state.execution_id().map(str::to_string),
+            history,
+            runtime_status: state.runtime_status(),
+            custom_status: state.custom_status().map(str::to_string),
+            activities: state.pending_tasks().to_vec(),
+            activity_options: state
+                .pending_tasks()
+                .iter()
+                .filter_map(|e| Some((e.event_id, state.pending_task_options(e.event_id)?.clone())))
+                .collect(),
+            activity_routes: state
+                .pending_tasks()
+                .iter()
+                .map(|e| {
+                    let options = state.pending_task_options(e.event_id);
+                    (e.event_id, routing.route_activity(e, options))
+                })
+                .collect(),
+            timers: state.pending_timers().to_vec(),
+            messages: route_pending_messages(state, routing),
+        }
+    }
+
+    /// Whether the instance was terminated or cancelled, so the activities it scheduled
+    /// earlier must be cancelled.
+    pub fn cancels_activities(&self) -> bool {
+        matches!(
+            self.runtime_status,
+            OrchestrationStatus::Terminated | OrchestrationStatus::Canceled
+        )
+    }
+
+    /// Returns the messages as outbox entries, in the order they must be delivered.
+    pub fn outbox_messages(&self) -> Vec<OutboxMessage> {
+        self.messages
+            .iter()
+            .flat_map(|route| {
+                route
+                    .events
+                    .iter()
+                    .zip(&route.target_execution_ids)
+                    .zip(&route.reuse_policies)
+                    .zip(&route.priorities)
+                    .map(
+                        |(((event, target_execution_id), reuse_policy), priority)| OutboxMessage {
+                            id: Uuid::new_v4().to_string(),
+                            instance_id: route.instance_id.clone(),
+                            target_execution_id: target_execution_id.clone(),
+                            reuse_policy: reuse_policy.clone(),
+                            priority: *priority,
+                            event: event.clone(),
+                        },
+                    )
+            })
+            .collect()
+    }
+}
+
+/// A message saved with the commit of the work item that sent it, to be delivered later by
+/// [`relay_outbox_messages`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxMessage {
+    pub id: String,
+    pub instance_id: String,
+    pub target_execution_id: Option<String>,
+    /// The policy to create the instance with, for the start of a detached orchestration.
+    pub reuse_policy: Option<OrchestrationIdReusePolicy>,
+    /// The priority to create the instance with, for the start of an orchestration.
+    pub priority: Priority,
+    pub event: HistoryEvent,
+}
+
+/// Storage for the messages of committed work items, for backends that can't enqueue them for
+/// other instances in the same transaction as the rest of the commit.
+///
+/// Such backends save [`OrchestrationCommit::outbox_messages`] with the sending instance's
+/// history instead, and return their outbox from [`Backend::outbox`] so workers relay them.
+#[async_trait]
+pub trait Outbox: Send + Sync {
+    /// Returns up to `max` undelivered messages, oldest first.
+    async fn get_outbox_messages(&self, max: usize) -> Result<Vec<OutboxMessage>, BackendError>;
+    /// Removes a message once it was delivered.
+    async fn delete_outbox_message(&self, id: &str) -> Result<(), BackendError>;
+}
+
+/// Delivers up to `max` outbox messages to their target instances, returning how many were
+/// delivered.
+///
+/// Delivery is at least once: a message is only removed after its target accepted it, and
+/// relaying stops at the first failure so later messages can't overtake it. Starting a
+/// sub-orchestration that already exists with the same parent counts as delivered, since it was
+/// created by an earlier attempt; if its ID belongs to another instance, the parent is told
+/// the sub-orchestration failed instead. Detached starts rejected by their reuse policy are
+/// dropped.
+pub async fn relay_outbox_messages(
+    be: &dyn Backend,
+    outbox: &dyn Outbox,
+    max: usize,
+) -> Result<usize, BackendError> {
+    let messages = outbox.get_outbox_messages(max).await?;
+    for message in &messages {
+        let result = match &message.event.event_type {
+            Some(EventType::ExecutionStarted(started)) => {
+                let policy = with_orchestration_id_reuse_policy(message.reuse_policy.clone());
+                match be
+                    .create_orchestration_instance(&message.event, message.priority, vec![policy])
+                    .await
+                {
+                    Err(BackendError::DuplicateInstance | BackendError::IgnoreInstance) => {
+                        resolve_existing_instance(be, message, started.parent_instance.as_ref())
+                            .await
+                    }
+                    result => result,
+                }
+            }
+            _ => {
+                be.add_orchestration_message(
+                    &message.instance_id,
+                    &message.event,
+                    message.target_execution_id.as_deref(),
+                )
+                .await
+            }
+        };
+        result?;
+        outbox.delete_outbox_message(&message.id).await?;
+    }
+    Ok(messages.len())
+}
+
+/// Resolves the start of a sub-orchestration with `parent` whose instance ID is already taken.
+async fn resolve_existing_instance(
+    be: &dyn Backend,
+    message: &OutboxMessage,
+    parent: Option<&ParentInstanceInfo>,
+) -> Result<(), BackendError> {
+    let Some(parent) = parent else {
+        return Ok(());
+    };
+    // An earlier attempt to relay the message created the instance
+    let existing_parent = be.get_orchestration_parent(&message.instance_id).await?;
+    if existing_parent.as_ref() == Some(parent) {
+        return Ok(());
+    }
+    let details = TaskFailureDetails {
+        error_type: "OrchestrationAlreadyExists".to_string(),
+        error_message: format!(
+            "an orchestration with the ID '{}' already exists",
+            message.instance_id
+        ),
+        ..Default::default()
+    };
+    let failed = new_sub_orchestration_failed_event(parent.task_scheduled_id, Some(&details));
+    let instance = parent.orchestration_instance.clone().unwrap_or_default();
+    be.add_orchestration_message(
+        &instance.instance_id,
+        &failed,
+        instance.execution_id.as_deref(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use prost_wkt_types::Timestamp;
+
+    use crate::backend::runtimestate::OrchestrationRuntimeState;
+    use crate::backend::testing::TestBackend;
+    use crate::durabletask_pb::OrchestrationInstance;
+    use crate::internal::{
+        new_create_timer_action, new_execution_started_event, new_orchestrator_started_event,
+        new_schedule_task_action, new_send_event_action,
+    };
+
+    fn work_item() -> OrchestrationWorkItem {
+        let instance_id = InstanceID("abc".to_string());
+        let history = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("Orchestration", "abc", None, None, None, None),
+        ];
+        let mut state = OrchestrationRuntimeState::new(&instance_id, &history);
+        state
+            .apply_actions(&[
+                new_schedule_task_action(0, "Activity", None),
+                new_create_timer_action(1, &Timestamp::from(std::time::SystemTime::now())),
+                new_send_event_action(2, "xyz", "first", None),
+                new_send_event_action(3, "xyz", "second", Some("2")),
+            ])
+            .unwrap();
+        // Options of tasks that weren't scheduled are dropped
+        let options = ActivityOptions::new().with_start_to_close_timeout(Duration::from_secs(5));
+        state.set_pending_task_options(HashMap::from([(0, options.clone()), (1, options)]));
+        OrchestrationWorkItem {
+            instance_id,
+            state,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_commit_from_work_item() {
+        let wi = work_item();
+        let routing = TaskRouting::default().with_activity(
+            "Activity",
+            TaskRoute::default()
+                .with_task_queue("gpu")
+                .with_priority(Priority::High),
+        );
+        let commit = OrchestrationCommit::new(&wi, &routing);
+        let HistoryUpdate::Append(events) = &commit.history else {
+            panic!("expected appended events");
+        };
+        assert_eq!(events.len(), 4);
+        assert_eq!(commit.activities.len(), 1);
+        assert_eq!(commit.activity_options.keys().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!(commit.activity_routes[&0].task_queue(), "gpu");
+        assert_eq!(commit.activity_routes[&0].priority(), Priority::High);
+        assert!(!commit.cancels_activities());
+        assert_eq!(commit.timers.len(), 1);
+        assert_eq!(commit.messages.len(), 1);
+        assert_eq!(commit.messages[0].events.len(), 2);
+
+        let outbox = commit.outbox_messages();
+        assert_eq!(outbox.len(), 2);
+        assert!(outbox.iter().all(|m| m.instance_id == "xyz"));
+        assert_ne!(outbox[0].id, outbox[1].id);
+        let names: Vec<_> = outbox
+            .iter()
+            .map(|m| match &m.event.event_type {
+                Some(EventType::EventRaised(e)) => e.name.clone(),
+                _ => panic!("expected raised events"),
+            })
+            .collect();
+        assert_eq!(names, ["first", "second"]);
+    }
+
+    #[derive(Default)]
+    struct TestOutbox {
+        messages: Mutex<Vec<OutboxMessage>>,
+    }
+
+    #[async_trait]
+    impl Outbox for TestOutbox {
+        async fn get_outbox_messages(
+            &self,
+            max: usize,
+        ) -> Result<Vec<OutboxMessage>, BackendError> {
+            let messages = self.messages.lock().unwrap();
+            Ok(messages.iter().take(max).cloned().collect())
+        }
+
+        async fn delete_outbox_message(&self, id: &str) -> Result<(), BackendError> {
+            self.messages.lock().unwrap().retain(|m| m.id != id);
+            Ok(())
+        }
+    }
+
+    fn start_message(instance_id: &str, parent: Option<ParentInstanceInfo>) -> OutboxMessage {
+        OutboxMessage {
+            id: Uuid::new_v4().to_string(),
+            instance_id: instance_id.to_string(),
+            target_execution_id: None,
+            reuse_policy: None,
+            priority: Priority::default(),
+            event: new_execution_started_event("Child", instance_id, None, parent, None, None),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_sub_orchestration_with_taken_id() {
+        let be = TestBackend::default();
+        let outbox = TestOutbox::default();
+        let parent = ParentInstanceInfo {
+            task_scheduled_id: 3,
+            orchestration_instance: Some(OrchestrationInstance {
+                instance_id: "parent".to_string(),
+                execution_id: None,
+            }),
+            ..Default::default()
+        };
+        outbox.messages.lock().unwrap().extend([
+            start_message("parent", None),
+            start_message("child", Some(parent.clone())),
+            // Relayed again, e.g. after the relay crashed before deleting it
+            start_message("child", Some(parent.clone())),
+            start_message("unrelated", None),
+            start_message("unrelated", Some(parent)),
+        ]);
+
+        assert_eq!(relay_outbox_messages(&be, &outbox, 10).await.unwrap(), 5);
+        assert!(outbox.messages.lock().unwrap().is_empty());
+        let instances = be.instances.lock().unwrap();
+        assert_eq!(instances["child"].inbox.len(), 1);
+        // Only the start that lost its ID to another instance fails
+        let inbox = &instances["parent"].inbox;
+        assert_eq!(inbox.len(), 2);
+        let Some(EventType::SubOrchestrationInstanceFailed(failed)) = &inbox[1].event_type else {
+            panic!("expected a failed sub-orchestration");
+        };
+        assert_eq!(failed.task_scheduled_id, 3);
+    }
+
+    #[test]
+    fn test_commit_replaces_snapshotted_history() {
+        let mut wi = work_item();
+        wi.state.commit();
+        wi.state.take_snapshot();
+        wi.state
+            .add_event(&new_orchestrator_started_event(), true)
+            .unwrap();
+
+        let commit = OrchestrationCommit::new(&wi, &TaskRouting::default());
+        let mut events = wi.state.snapshot().unwrap().to_vec();
+        events.extend_from_slice(wi.state.new_events());
+        assert_eq!(commit.history, HistoryUpdate::Replace(events));
+        assert!(commit.activities.is_empty());
+        assert!(commit.messages.is_empty());
+    }
+}
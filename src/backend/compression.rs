@@ -0,0 +1,287 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+//! Optional compression of marshalled history events.
+//!
+//! Compressed records start with a header that can't begin a protobuf message, since field
+//! number zero is invalid: `0x00 'D' 'T' <version> <algorithm>`. Records without the header are
+//! plain protobuf, so history written before compression was enabled stays readable.
+use std::borrow::Cow;
+
+use crate::Error;
+
+const MAGIC: [u8; 3] = [0x00, b'D', b'T'];
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+const GZIP: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// The default limit on the size of a decompressed event, 16 MiB.
+const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// How history events are compressed by `marshal_history_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryCompression {
+    algorithm: CompressionAlgorithm,
+    threshold: usize,
+    level: Option<i32>,
+    max_decompressed_size: usize,
+}
+
+impl Default for HistoryCompression {
+    fn default() -> Self {
+        HistoryCompression::none()
+    }
+}
+
+impl HistoryCompression {
+    /// Stores every event uncompressed.
+    pub fn none() -> Self {
+        HistoryCompression {
+            algorithm: CompressionAlgorithm::None,
+            threshold: 1024,
+            level: None,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    pub fn gzip() -> Self {
+        HistoryCompression {
+            algorithm: CompressionAlgorithm::Gzip,
+            ..Self::none()
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn zstd() -> Self {
+        HistoryCompression {
+            algorithm: CompressionAlgorithm::Zstd,
+            ..Self::none()
+        }
+    }
+
+    /// Only compresses events whose encoding is larger than `threshold` bytes. Defaults to 1 KiB.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the algorithm specific compression level.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Fails to read compressed events that decompress to more than `limit` bytes, instead of
+    /// buffering all of them. Defaults to 16 MiB.
+    pub fn with_max_decompressed_size(mut self, limit: usize) -> Self {
+        self.max_decompressed_size = limit;
+        self
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    /// Compresses an encoded event, returning it unchanged when it's below the threshold or
+    /// doesn't get smaller.
+    pub(crate) fn compress(&self, encoded: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if encoded.len() <= self.threshold {
+            return Ok(encoded);
+        }
+        let compressed: Option<(u8, Vec<u8>)> = match self.algorithm {
+            CompressionAlgorithm::None => None,
+            #[cfg(feature = "gzip")]
+            CompressionAlgorithm::Gzip => Some((GZIP, gzip_compress(&encoded, self.level)?)),
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => Some((ZSTD, zstd_compress(&encoded, self.level)?)),
+        };
+        let Some((id, compressed)) = compressed else {
+            return Ok(encoded);
+        };
+        if compressed.len() + HEADER_LEN >= encoded.len() {
+            return Ok(encoded);
+        }
+
+        let mut record = Vec::with_capacity(HEADER_LEN + compressed.len());
+        record.extend_from_slice(&MAGIC);
+        record.extend_from_slice(&[VERSION, id]);
+        record.extend_from_slice(&compressed);
+        Ok(record)
+    }
+
+    /// Returns the protobuf encoding of a record written by [`compress`](Self::compress),
+    /// with any algorithm.
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn decompress<'a>(&self, record: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        if !record.starts_with(&MAGIC) {
+            return Ok(Cow::Borrowed(record));
+        }
+        let (version, id, data) = match record {
+            [_, _, _, version, id, data @ ..] => (*version, *id, data),
+            _ => return Err(compression_error("truncated compression header")),
+        };
+        if version != VERSION {
+            return Err(compression_error(format!(
+                "unsupported compression header version {version}"
+            )));
+        }
+        let limit = self.max_decompressed_size;
+        match id {
+            #[cfg(feature = "gzip")]
+            GZIP => read_limited(flate2::read::GzDecoder::new(data), limit).map(Cow::Owned),
+            #[cfg(feature = "zstd")]
+            ZSTD => {
+                let decoder = zstd::stream::read::Decoder::new(data)
+                    .map_err(|e| Error::Serialization(e.into()))?;
+                read_limited(decoder, limit).map(Cow::Owned)
+            }
+            #[cfg(not(feature = "gzip"))]
+            GZIP => Err(compression_error(
+                "history event is gzip compressed, but the gzip feature isn't enabled",
+            )),
+            #[cfg(not(feature = "zstd"))]
+            ZSTD => Err(compression_error(
+                "history event is zstd compressed, but the zstd feature isn't enabled",
+            )),
+            _ => Err(compression_error(format!(
+                "unknown compression algorithm {id}"
+            ))),
+        }
+    }
+}
+
+/// Reads all of `decoder`, failing once it yields more than `limit` bytes.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_limited(decoder: impl std::io::Read, limit: usize) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    let mut decoded = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| Error::Serialization(e.into()))?;
+    if decoded.len() > limit {
+        return Err(compression_error(format!(
+            "history event decompresses to more than {limit} bytes"
+        )));
+    }
+    Ok(decoded)
+}
+
+fn compression_error(message: impl Into<String>) -> Error {
+    Error::Serialization(message.into().into())
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_compress(data: &[u8], level: Option<i32>) -> Result<Vec<u8>, Error> {
+    use std::io::Write;
+
+    let level = level.map_or(flate2::Compression::default(), |l| {
+        flate2::Compression::new(l.clamp(0, 9) as u32)
+    });
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+    encoder
+        .write_all(data)
+        .map_err(|e| Error::Serialization(e.into()))?;
+    encoder.finish().map_err(|e| Error::Serialization(e.into()))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(data: &[u8], level: Option<i32>) -> Result<Vec<u8>, Error> {
+    zstd::encode_all(data, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))
+        .map_err(|e| Error::Serialization(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uncompressed_records_pass_through() {
+        let encoded = vec![0x08, 0x01, 0x12, 0x00];
+        let record = HistoryCompression::none()
+            .with_threshold(0)
+            .compress(encoded.clone())
+            .unwrap();
+        assert_eq!(record, encoded);
+        let decompressed = HistoryCompression::none().decompress(&record).unwrap();
+        assert_eq!(decompressed.as_ref(), encoded.as_slice());
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let compression = HistoryCompression::none();
+        assert!(compression
+            .decompress(&[0x00, b'D', b'T', VERSION])
+            .is_err());
+        assert!(compression
+            .decompress(&[0x00, b'D', b'T', 9, GZIP])
+            .is_err());
+        assert!(compression
+            .decompress(&[0x00, b'D', b'T', VERSION, 42, 1, 2])
+            .is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_roundtrip() {
+        roundtrip(HistoryCompression::gzip(), GZIP);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_roundtrip() {
+        roundtrip(HistoryCompression::zstd().with_level(3), ZSTD);
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn roundtrip(compression: HistoryCompression, id: u8) {
+        let encoded = b"history ".repeat(256);
+        let record = compression.clone().compress(encoded.clone()).unwrap();
+        assert_eq!(record[..HEADER_LEN], [0x00, b'D', b'T', VERSION, id]);
+        assert!(record.len() < encoded.len());
+        let decompressed = compression.decompress(&record).unwrap();
+        assert_eq!(decompressed.as_ref(), encoded.as_slice());
+
+        // Records that decompress past the limit are rejected rather than buffered
+        let limited = compression
+            .clone()
+            .with_max_decompressed_size(encoded.len() - 1);
+        assert!(matches!(
+            limited.decompress(&record),
+            Err(Error::Serialization(_))
+        ));
+        let exact = compression
+            .clone()
+            .with_max_decompressed_size(encoded.len());
+        assert!(exact.decompress(&record).is_ok());
+
+        // Small events stay uncompressed
+        let small = b"small".to_vec();
+        assert_eq!(compression.compress(small.clone()).unwrap(), small);
+    }
+}
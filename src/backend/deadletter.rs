@@ -0,0 +1,100 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::api::InstanceID;
+use crate::backend::workitem::{ActivityWorkItem, OrchestrationWorkItem};
+use crate::durabletask_pb::{HistoryEvent, TaskFailureDetails};
+
+/// The kind of work item that was dead-lettered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkItemKind {
+    Orchestration,
+    Activity,
+}
+
+/// A work item that kept failing and was moved out of its queue.
+///
+/// Its instance is marked as failed with the dead letter's failure details, until the work
+/// item is requeued.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub id: String,
+    pub kind: WorkItemKind,
+    pub instance_id: InstanceID,
+    /// The execution the work item was addressed to, if known.
+    pub execution_id: Option<String>,
+    /// The events the work item delivered: the new events of an orchestration work item, or
+    /// the `TaskScheduled` event of an activity.
+    pub events: Vec<HistoryEvent>,
+    /// How many times the work item was retried before it was dead-lettered.
+    pub retry_count: i32,
+    pub failure_details: TaskFailureDetails,
+    pub dead_lettered_at: SystemTime,
+}
+
+impl DeadLetter {
+    pub fn from_orchestration_work_item(
+        wi: &OrchestrationWorkItem,
+        failure_details: &TaskFailureDetails,
+    ) -> Self {
+        DeadLetter {
+            id: Uuid::new_v4().to_string(),
+            kind: WorkItemKind::Orchestration,
+            instance_id: wi.instance_id.clone(),
+            execution_id: wi.state.execution_id().map(str::to_string),
+            events: wi.new_events.clone(),
+            retry_count: wi.retry_count,
+            failure_details: failure_details.clone(),
+            dead_lettered_at: SystemTime::now(),
+        }
+    }
+
+    pub fn from_activity_work_item(
+        wi: &ActivityWorkItem,
+        failure_details: &TaskFailureDetails,
+    ) -> Self {
+        DeadLetter {
+            id: Uuid::new_v4().to_string(),
+            kind: WorkItemKind::Activity,
+            instance_id: wi.instance_id.clone(),
+            execution_id: wi.execution_id.clone(),
+            events: vec![wi.new_event.clone()],
+            retry_count: wi.retry_count,
+            failure_details: failure_details.clone(),
+            dead_lettered_at: SystemTime::now(),
+        }
+    }
+}
+
+/// The failure details of a work item that failed `attempts` times, most recently with
+/// `error`.
+pub(crate) fn poison_work_item_failure_details(
+    attempts: i32,
+    error: &dyn std::fmt::Display,
+) -> TaskFailureDetails {
+    TaskFailureDetails {
+        error_type: "PoisonWorkItem".to_string(),
+        error_message: format!(
+            "work item was dead-lettered after {} failed attempts, last error: {}",
+            attempts, error
+        ),
+        is_non_retriable: true,
+        ..Default::default()
+    }
+}
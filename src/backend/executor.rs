@@ -0,0 +1,70 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use prost_wkt_types::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{InstanceID, OrchestrationIdReusePolicy};
+use crate::backend::routing::Priority;
+use crate::durabletask_pb::{HistoryEvent, OrchestratorResponse};
+use crate::task::activity::ActivityHooks;
+use crate::task::ActivityOptions;
+use crate::Error;
+
+/// The actions an orchestrator scheduled, with what the protocol can't carry.
+pub(crate) struct OrchestratorOutput {
+    pub response: OrchestratorResponse,
+    /// The options of the activities scheduled by `response`, by task ID.
+    pub activity_options: HashMap<i32, ActivityOptions>,
+    /// The `CreateSubOrchestration` actions of `response` that start detached orchestrations,
+    /// by task ID.
+    pub detached_starts: HashMap<i32, DetachedStart>,
+}
+
+/// How to create an orchestration started by
+/// [`OrchestrationContext::start_orchestration`](crate::task::OrchestrationContext::start_orchestration).
+/// Unlike a sub-orchestration, it has no parent.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DetachedStart {
+    pub reuse_policy: Option<OrchestrationIdReusePolicy>,
+    pub scheduled_start_timestamp: Option<Timestamp>,
+    pub priority: Option<Priority>,
+}
+
+/// Runs orchestrator and activity code on behalf of the backend processors.
+#[async_trait]
+pub(crate) trait Executor: Send + Sync {
+    /// Replays `old_events` and applies `new_events`, returning the actions scheduled by the
+    /// orchestrator.
+    async fn execute_orchestrator(
+        &self,
+        instance_id: &InstanceID,
+        old_events: &[HistoryEvent],
+        new_events: &[HistoryEvent],
+    ) -> Result<OrchestratorOutput, Error>;
+
+    /// Runs the activity scheduled by a `TaskScheduled` event, returning a `TaskCompleted` or
+    /// `TaskFailed` event, or [`Error::Cancelled`] if the activity failed after the
+    /// cancellation token of `hooks` was cancelled.
+    async fn execute_activity(
+        &self,
+        instance_id: &InstanceID,
+        event: &HistoryEvent,
+        hooks: ActivityHooks,
+    ) -> Result<HistoryEvent, Error>;
+}
@@ -0,0 +1,173 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::api::InstanceID;
+use crate::backend::BackendError;
+
+/// How long backends lease work items to the worker that fetched them.
+///
+/// A lease that isn't renewed in time expires, and the work item can be fetched again by
+/// another worker, e.g. when the worker holding it crashed. Workers renew the leases of the
+/// work items they're processing, so only the lock timeout bounds how long recovery takes, not
+/// how long a work item may run.
+#[derive(Clone, Debug)]
+pub struct LockOptions {
+    orchestration_lock_timeout: Duration,
+    activity_lock_timeout: Duration,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            orchestration_lock_timeout: Duration::from_secs(2 * 60),
+            activity_lock_timeout: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+impl LockOptions {
+    pub fn with_orchestration_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.orchestration_lock_timeout = timeout;
+        self
+    }
+
+    pub fn with_activity_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.activity_lock_timeout = timeout;
+        self
+    }
+
+    /// The expiry of an orchestration lock taken or renewed at `now`.
+    pub fn orchestration_lock_expiry(&self, now: SystemTime) -> SystemTime {
+        now + self.orchestration_lock_timeout
+    }
+
+    /// The expiry of an activity lock taken or renewed at `now`.
+    pub fn activity_lock_expiry(&self, now: SystemTime) -> SystemTime {
+        now + self.activity_lock_timeout
+    }
+}
+
+/// The lease a worker holds on a work item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkItemLock {
+    pub instance_id: InstanceID,
+    /// The sequence number of an activity work item, or `None` for an orchestration.
+    pub sequence_number: Option<i64>,
+    pub locked_by: String,
+    /// Identifies the fetch that took the lock, so a worker that fetches a work item again
+    /// after its lease expired doesn't share the lock with its earlier attempt.
+    pub lock_token: String,
+    pub expires_at: SystemTime,
+}
+
+impl WorkItemLock {
+    /// How long to wait before renewing the lock: half of the time it has left, so a failed
+    /// renewal can be retried before it expires.
+    pub(crate) fn renew_delay(&self, now: SystemTime) -> Duration {
+        self.expires_at
+            .duration_since(now)
+            .unwrap_or(Duration::ZERO)
+            / 2
+    }
+}
+
+/// Creates the token of a new lock. Backends create one each time they lock a work item, store it
+/// with the work item and return it in the fetched work item.
+pub fn new_lock_token() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Whether a work item locked until `expires_at` may be fetched by another worker. Work items
+/// that were never locked are available.
+pub fn is_lock_available(expires_at: Option<SystemTime>, now: SystemTime) -> bool {
+    match expires_at {
+        Some(expires_at) => expires_at <= now,
+        None => true,
+    }
+}
+
+/// Checks that the fetch with `lock_token` still holds the lock of a work item, whose stored
+/// token is `stored_token`.
+///
+/// Backends call this before completing, abandoning or renewing a work item, and return the
+/// error so results of a worker whose lease expired are rejected. A lock that expired is lost
+/// even when no other worker took it yet, and the token tells apart two fetches of the same
+/// worker.
+pub fn check_work_item_lock(
+    stored_token: Option<&str>,
+    expires_at: Option<SystemTime>,
+    lock_token: &str,
+    now: SystemTime,
+) -> Result<(), BackendError> {
+    match (stored_token, expires_at) {
+        (Some(stored), Some(expires_at)) if stored == lock_token && expires_at > now => Ok(()),
+        // Backends without leases only track the owner
+        (Some(stored), None) if stored == lock_token => Ok(()),
+        _ => Err(BackendError::WorkItemLockLost),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_work_item_lock() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(30);
+        let token = new_lock_token();
+        assert!(check_work_item_lock(Some(&token), Some(later), &token, now).is_ok());
+        assert!(check_work_item_lock(Some(&token), None, &token, now).is_ok());
+        // The same worker fetched the work item again after its lease expired
+        let refetched = new_lock_token();
+        assert!(matches!(
+            check_work_item_lock(Some(&refetched), Some(later), &token, now),
+            Err(BackendError::WorkItemLockLost)
+        ));
+        assert!(matches!(
+            check_work_item_lock(Some(&token), Some(now), &token, now),
+            Err(BackendError::WorkItemLockLost)
+        ));
+        assert!(matches!(
+            check_work_item_lock(None, None, &token, now),
+            Err(BackendError::WorkItemLockLost)
+        ));
+
+        assert!(is_lock_available(None, now));
+        assert!(is_lock_available(Some(now), now));
+        assert!(!is_lock_available(Some(later), now));
+    }
+
+    #[test]
+    fn test_renew_delay() {
+        let now = SystemTime::now();
+        let lock = WorkItemLock {
+            instance_id: InstanceID("abc".to_string()),
+            sequence_number: None,
+            locked_by: "w1".to_string(),
+            lock_token: new_lock_token(),
+            expires_at: now + Duration::from_secs(60),
+        };
+        assert_eq!(lock.renew_delay(now), Duration::from_secs(30));
+        assert_eq!(
+            lock.renew_delay(now + Duration::from_secs(90)),
+            Duration::ZERO
+        );
+    }
+}
@@ -18,30 +18,62 @@ use std::error::Error;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use prost::Message;
 
 use crate::api::{InstanceID, OrchestrationIdReusePolicy, OrchestrationMetadata};
+use crate::backend::commit::{OrchestrationCommit, Outbox};
+use crate::backend::compression::HistoryCompression;
+use crate::backend::deadletter::DeadLetter;
+use crate::backend::lock::WorkItemLock;
+use crate::backend::routing::{Priority, TaskRouting, WorkItemFilter};
 use crate::backend::runtimestate::OrchestrationRuntimeState;
+use crate::backend::throttle::TaskHubLimits;
 use crate::backend::workitem::{ActivityWorkItem, OrchestrationWorkItem};
-use crate::durabletask_pb::history_event::EventType::SubOrchestrationInstanceCreated;
-use crate::durabletask_pb::{ExecutionTerminatedEvent, HistoryEvent};
-use crate::internal::new_execution_terminated_event;
+use crate::durabletask_pb::history_event::EventType::{
+    ExecutionStarted, OrchestratorStarted, SubOrchestrationInstanceCreated,
+};
+use crate::durabletask_pb::{
+    CreateOrchestrationAction, ExecutionTerminatedEvent, HistoryEvent, OrchestrationStatus,
+    ParentInstanceInfo,
+};
+use crate::internal::{new_execution_completed_event, new_execution_terminated_event};
 
+pub mod activity;
+pub mod backoff;
+pub mod cache;
+pub mod client;
+pub mod commit;
+pub mod compression;
+pub mod deadletter;
+pub mod executor;
+pub mod lock;
 pub mod logger;
 pub mod orchestration;
+pub mod routing;
 pub mod runtimestate;
+pub mod schedule;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod throttle;
+pub mod worker;
 pub mod workitem;
 
-#[allow(dead_code)]
 #[derive(Debug)]
-pub(crate) enum BackendError {
+pub enum BackendError {
     TaskHubExists,
     TaskHubNotFound,
     NotInitialized,
     WorkItemLockLost,
+    /// The work item's orchestration instance was terminated or cancelled, so its results are
+    /// no longer wanted.
+    WorkItemCancelled,
     BackendAlreadyStarted,
+    NoWorkItems,
+    DuplicateInstance,
+    IgnoreInstance,
     Other(Box<dyn Error + Send + Sync>),
 }
 
@@ -52,7 +84,13 @@ impl fmt::Display for BackendError {
             BackendError::TaskHubNotFound => write!(f, "task hub not found"),
             BackendError::NotInitialized => write!(f, "backend not initialized"),
             BackendError::WorkItemLockLost => write!(f, "lock on work-item was lost"),
+            BackendError::WorkItemCancelled => write!(f, "work-item was cancelled"),
             BackendError::BackendAlreadyStarted => write!(f, "backend is already started"),
+            BackendError::NoWorkItems => write!(f, "no work items were found"),
+            BackendError::DuplicateInstance => {
+                write!(f, "orchestration instance already exists")
+            }
+            BackendError::IgnoreInstance => write!(f, "ignore creating orchestration instance"),
             BackendError::Other(e) => write!(f, "other error: {}", e),
         }
     }
@@ -73,12 +111,10 @@ impl From<Box<dyn Error + Send + Sync>> for BackendError {
     }
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) type OrchestrationIdReusePolicyOptions =
-    Box<dyn Fn(&mut OrchestrationIdReusePolicy) -> Result<(), Box<dyn Error>> + Send + Sync>;
+pub type OrchestrationIdReusePolicyOptions =
+    Box<dyn Fn(&mut OrchestrationIdReusePolicy) -> Result<(), crate::Error> + Send + Sync>;
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) fn with_orchestration_id_reuse_policy(
+pub fn with_orchestration_id_reuse_policy(
     policy: Option<OrchestrationIdReusePolicy>,
 ) -> OrchestrationIdReusePolicyOptions {
     Box::new(move |po: &mut OrchestrationIdReusePolicy| {
@@ -90,16 +126,89 @@ pub(crate) fn with_orchestration_id_reuse_policy(
     })
 }
 
-#[allow(dead_code)] // TODO: Remove
+/// What a backend should do when asked to create an orchestration instance.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CreateInstanceDecision {
+    /// No instance with the same ID exists, create it.
+    Create,
+    /// Terminate the current execution of the existing instance, ending it with
+    /// [`replaced_execution_events`] and dropping its pending events, timers and activities,
+    /// then start the new execution in its place. All steps must be committed in the same
+    /// transaction.
+    Replace,
+}
+
+/// Applies `options` to a default policy, which rejects every duplicate instance ID.
+pub fn resolve_orchestration_id_reuse_policy(
+    options: &[OrchestrationIdReusePolicyOptions],
+) -> Result<OrchestrationIdReusePolicy, BackendError> {
+    let mut policy = OrchestrationIdReusePolicy::default();
+    for configure in options {
+        configure(&mut policy).map_err(|e| BackendError::Other(Box::new(e)))?;
+    }
+    Ok(policy)
+}
+
+/// Evaluates `policy` against the status of an existing instance with the same ID, if any.
+///
+/// This is shared by all backends from `create_orchestration_instance` so the reuse semantics
+/// don't depend on the storage provider:
+///
+/// - no existing instance is always created
+/// - an existing instance whose status isn't in `operation_status` is a duplicate
+/// - otherwise the policy's action decides whether the request is rejected as a duplicate,
+///   ignored, or replaces the existing instance
+pub fn evaluate_orchestration_id_reuse_policy(
+    existing_status: Option<OrchestrationStatus>,
+    policy: &OrchestrationIdReusePolicy,
+) -> Result<CreateInstanceDecision, BackendError> {
+    let Some(status) = existing_status else {
+        return Ok(CreateInstanceDecision::Create);
+    };
+
+    if !policy.operation_status.contains(&(status as i32)) {
+        return Err(BackendError::DuplicateInstance);
+    }
+
+    match policy.action() {
+        CreateOrchestrationAction::Error => Err(BackendError::DuplicateInstance),
+        CreateOrchestrationAction::Ignore => Err(BackendError::IgnoreInstance),
+        CreateOrchestrationAction::Terminate => Ok(CreateInstanceDecision::Replace),
+    }
+}
+
+/// Returns the events that end the current execution of an instance replaced under
+/// [`CreateInstanceDecision::Replace`]: a `Terminated` `ExecutionCompleted` event, or none if
+/// the execution already completed.
+pub fn replaced_execution_events(state: &OrchestrationRuntimeState) -> Vec<HistoryEvent> {
+    if state.is_completed() || state.old_events.is_empty() {
+        return vec![];
+    }
+    vec![new_execution_completed_event(
+        -1,
+        OrchestrationStatus::Terminated as i32,
+        None,
+        None,
+    )]
+}
+
 #[async_trait]
-pub(crate) trait Backend: Send + Sync {
+pub trait Backend: Send + Sync {
     async fn create_task_hub(&self) -> Result<(), BackendError>;
     async fn delete_task_hub(&self) -> Result<(), BackendError>;
     async fn start(&self) -> Result<(), BackendError>;
     async fn stop(&self) -> Result<(), BackendError>;
+    /// Creates a new orchestration instance from an `ExecutionStarted` event. All of the
+    /// instance's work items are fetched with `priority`.
+    ///
+    /// If an instance with the same ID already exists, implementations must resolve it with
+    /// [`evaluate_orchestration_id_reuse_policy`] and return [`BackendError::DuplicateInstance`]
+    /// or [`BackendError::IgnoreInstance`] accordingly, or atomically replace the existing
+    /// instance as described by [`CreateInstanceDecision::Replace`].
     async fn create_orchestration_instance(
         &self,
         event: &HistoryEvent,
+        priority: Priority,
         options: Vec<OrchestrationIdReusePolicyOptions>,
     ) -> Result<(), BackendError>;
     async fn add_new_orchestration_event(
@@ -107,7 +216,23 @@ pub(crate) trait Backend: Send + Sync {
         instance_id: &str,
         event: &HistoryEvent,
     ) -> Result<(), BackendError>;
+    /// Enqueues an event sent by another orchestration, addressed to `execution_id` when set.
+    ///
+    /// Backends that track the execution of new events should override this; by default the
+    /// event is added to whichever execution is current.
+    async fn add_orchestration_message(
+        &self,
+        instance_id: &str,
+        event: &HistoryEvent,
+        _execution_id: Option<&str>,
+    ) -> Result<(), BackendError> {
+        self.add_new_orchestration_event(instance_id, event).await
+    }
+    /// Locks the next orchestration with new events, returning those with the highest
+    /// [priority](routing::TaskRouting) first.
     async fn get_orchestration_work_item(&self) -> Result<OrchestrationWorkItem, BackendError>;
+    /// Loads the state of the current execution of the work item's instance, from the events
+    /// returned by [`current_execution`].
     async fn get_orchestration_runtime_state(
         &self,
         work_item: &OrchestrationWorkItem,
@@ -116,47 +241,175 @@ pub(crate) trait Backend: Send + Sync {
         &self,
         instance_id: &str,
     ) -> Result<OrchestrationMetadata, BackendError>;
+    /// Returns the parent of `instance_id` from the `ExecutionStarted` event that created it,
+    /// including for instances that didn't run yet, or `None` for top-level instances.
+    async fn get_orchestration_parent(
+        &self,
+        instance_id: &str,
+    ) -> Result<Option<ParentInstanceInfo>, BackendError>;
+    /// Returns the history of `instance_id`, across all of its executions. When
+    /// `execution_id` is set, only the events of that execution are returned, as by
+    /// [`filter_history_by_execution`].
+    async fn get_orchestration_history(
+        &self,
+        instance_id: &str,
+        execution_id: Option<&str>,
+    ) -> Result<Vec<HistoryEvent>, BackendError>;
+    /// Commits the changes of a processed work item and releases its lock.
+    ///
+    /// All of `commit` must be saved in one transaction, or none of it if an error is returned:
+    ///
+    /// - the history is updated as described by [`commit::HistoryUpdate`]: appended to, including
+    ///   when the orchestration [continued as new](OrchestrationRuntimeState::continued_as_new),
+    ///   or the current execution is replaced when a
+    ///   [snapshot](OrchestrationRuntimeState::snapshot) was taken
+    /// - activities and timers are enqueued, addressed to the commit's execution so their
+    ///   results can be matched against `target_execution_ids` once they're delivered.
+    ///   Activities are enqueued on the task queue and with the priority of their
+    ///   `activity_routes` entry
+    /// - messages are enqueued for their target instances and executions. An `ExecutionStarted`
+    ///   message creates its instance like [`Backend::create_orchestration_instance`], with its
+    ///   entries in `priorities` and `reuse_policies`
+    /// - the runtime status and custom status are updated
+    /// - when [`OrchestrationCommit::cancels_activities`], the instance's activity work items
+    ///   are marked as cancelled: queued ones are no longer fetched, and renewing the lock of,
+    ///   recording a heartbeat for or completing a locked one fails with
+    ///   [`BackendError::WorkItemCancelled`]
+    ///
+    /// Backends that can't enqueue messages for other instances in the same transaction save
+    /// [`OrchestrationCommit::outbox_messages`] instead, and expose them through
+    /// [`Backend::outbox`].
     async fn complete_orchestration_work_item(
         &self,
         work_item: &OrchestrationWorkItem,
+        commit: &OrchestrationCommit,
     ) -> Result<(), BackendError>;
+    /// Releases the lock of a work item that failed and increments its retry count. Its events
+    /// mustn't be fetched again before `delay` has passed.
     async fn abandon_orchestration_work_item(
         &self,
         work_item: &OrchestrationWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError>;
+    /// Releases the lock of a work item the worker didn't finish because it's shutting down, so
+    /// it's fetched again after `delay`. Unlike abandoning it, this doesn't increment its retry
+    /// count.
+    async fn defer_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        delay: Duration,
     ) -> Result<(), BackendError>;
-    async fn get_activity_work_item(&self) -> Result<ActivityWorkItem, BackendError>;
+    /// Locks the next activity whose task queue and name pass
+    /// [`filter`](WorkItemFilter::matches), returning those with the highest
+    /// [priority](routing::TaskRouting) first.
+    ///
+    /// Activities that reached their task hub wide concurrency in `limits`, counting the
+    /// activities locked by all workers, are skipped and stay queued.
+    ///
+    /// Each fetch is counted in [`ActivityWorkItem::dispatch_count`].
+    async fn get_activity_work_item(
+        &self,
+        filter: &WorkItemFilter,
+        limits: &TaskHubLimits,
+    ) -> Result<ActivityWorkItem, BackendError>;
+    /// Commits the result of an activity and releases its lock. The result of an activity that
+    /// was cancelled is dropped instead of being delivered to its instance, and
+    /// [`BackendError::WorkItemCancelled`] is returned.
     async fn complete_activity_work_item(
         &self,
         work_item: &ActivityWorkItem,
     ) -> Result<(), BackendError>;
+    /// Releases the lock of an activity that was throttled, so it's fetched again after
+    /// `delay`. Unlike abandoning it, this doesn't increment its retry count, and the fetch
+    /// isn't counted in its dispatch count.
+    async fn defer_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError>;
+    /// Like [`Backend::abandon_orchestration_work_item`], for an activity.
     async fn abandon_activity_work_item(
         &self,
         work_item: &ActivityWorkItem,
+        delay: Duration,
     ) -> Result<(), BackendError>;
     async fn purge_orchestration_state(&self, instance_id: &InstanceID)
         -> Result<(), BackendError>;
+    /// Extends the lease of a work item that's still being processed, returning its new
+    /// expiry, or [`BackendError::WorkItemLockLost`] if it's no longer held by the fetch with
+    /// `lock.lock_token`. Work items whose lease expired may be fetched by other workers.
+    ///
+    /// Completing or abandoning a work item whose lease was lost must fail with the same
+    /// error, see [`lock::check_work_item_lock`].
+    async fn renew_work_item_lock(&self, lock: &WorkItemLock) -> Result<SystemTime, BackendError>;
+    /// Renews the lease of an activity that reported a heartbeat and saves its `details`,
+    /// which are returned as [`ActivityWorkItem::heartbeat_details`] if the activity is fetched
+    /// again. Backends that don't save them only renew the lease.
+    async fn record_activity_heartbeat(
+        &self,
+        lock: &WorkItemLock,
+        _details: Option<&str>,
+    ) -> Result<SystemTime, BackendError> {
+        self.renew_work_item_lock(lock).await
+    }
+    /// Moves a work item that kept failing to the dead-letter store, in one transaction with
+    /// releasing its lock and setting its instance's runtime status to failed with
+    /// `dead_letter.failure_details`. The instance's history isn't changed, so it can resume
+    /// once the work item is requeued.
+    async fn dead_letter_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), BackendError>;
+    /// Like [`Backend::dead_letter_orchestration_work_item`], for an activity. The instance
+    /// that scheduled the activity is marked as failed.
+    async fn dead_letter_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), BackendError>;
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, BackendError>;
+    async fn get_dead_letter(&self, id: &str) -> Result<Option<DeadLetter>, BackendError>;
+    /// Puts a dead-lettered work item back on its queue with a retry count of zero, restores
+    /// its instance's runtime status to running and deletes the dead letter. Returns whether
+    /// the dead letter existed.
+    async fn requeue_dead_letter(&self, id: &str) -> Result<bool, BackendError>;
+    /// Returns the outbox of backends that commit messages for other instances there, which
+    /// workers relay to their targets.
+    fn outbox(&self) -> Option<&dyn Outbox> {
+        None
+    }
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) fn marshal_history_event(e: &HistoryEvent) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn marshal_history_event(
+    e: &HistoryEvent,
+    compression: &HistoryCompression,
+) -> Result<Vec<u8>, crate::Error> {
     let mut buf = Vec::new();
-    e.encode(&mut buf)?;
-    Ok(buf)
+    e.encode(&mut buf)
+        .map_err(|e| crate::Error::Serialization(Box::new(e)))?;
+    compression.compress(buf)
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) fn unmarshal_history_event(bytes: &[u8]) -> Result<HistoryEvent, Box<dyn Error>> {
-    HistoryEvent::decode(bytes).map_err(|e| Box::new(e) as Box<dyn Error>)
+/// Decodes an event written by [`marshal_history_event`], compressed or not. Compressed events
+/// larger than `compression`'s decompressed size limit are rejected.
+pub fn unmarshal_history_event(
+    bytes: &[u8],
+    compression: &HistoryCompression,
+) -> Result<HistoryEvent, crate::Error> {
+    let bytes = compression.decompress(bytes)?;
+    HistoryEvent::decode(bytes.as_ref()).map_err(|e| crate::Error::Serialization(Box::new(e)))
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) fn purge_orchestration_state<'a>(
+/// Purges `instance_id`, and its sub-orchestrations when `recursive` is set, returning the IDs
+/// of the purged instances.
+pub fn purge_orchestration_state<'a>(
     be: &'a (dyn Backend + 'a),
     instance_id: &'a InstanceID,
     recursive: bool,
-) -> Pin<Box<dyn Future<Output = Result<i32, BackendError>> + 'a>> {
+) -> Pin<Box<dyn Future<Output = Result<Vec<InstanceID>, BackendError>> + Send + 'a>> {
     Box::pin(async move {
-        let mut deleted_instance_count = 0;
+        let mut purged = Vec::new();
         if recursive {
             let owi = OrchestrationWorkItem {
                 instance_id: instance_id.into(),
@@ -172,25 +425,25 @@ pub(crate) fn purge_orchestration_state<'a>(
             let sub_orchestration_instances =
                 get_sub_orchestration_instances(&state.old_events, state.new_events());
             for sub_instance_id in sub_orchestration_instances {
-                let sub_result =
+                let sub_purged =
                     purge_orchestration_state(be, &InstanceID(sub_instance_id), recursive).await?;
-                deleted_instance_count += sub_result;
+                purged.extend(sub_purged);
             }
         }
         be.purge_orchestration_state(instance_id).await?;
-        Ok(deleted_instance_count + 1)
+        purged.push(instance_id.clone());
+        Ok(purged)
     })
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) async fn terminate_sub_orchestration_instances(
+pub async fn terminate_sub_orchestration_instances(
     be: &dyn Backend,
     /* Unused
     instance_id: InstanceID,
      */
     state: &OrchestrationRuntimeState,
     et: &ExecutionTerminatedEvent,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), crate::Error> {
     if !et.recurse {
         return Ok(());
     }
@@ -203,6 +456,125 @@ pub(crate) async fn terminate_sub_orchestration_instances(
     Ok(())
 }
 
+/// The events a completed work item sends to one orchestration instance.
+#[derive(Debug, Default)]
+pub struct InstanceMessages {
+    pub instance_id: String,
+    /// The events in the order they were sent. An `ExecutionStarted` event creates the
+    /// instance, e.g. for a sub-orchestration.
+    pub events: Vec<HistoryEvent>,
+    /// The execution each event is addressed to, as in
+    /// [`OrchestrationWorkItem::target_execution_ids`].
+    pub target_execution_ids: Vec<Option<String>>,
+    /// The reuse policy of each event that starts a detached orchestration, see
+    /// [`OrchestratorMessage::reuse_policy`](runtimestate::OrchestratorMessage::reuse_policy).
+    pub reuse_policies: Vec<Option<OrchestrationIdReusePolicy>>,
+    /// The priority of the instance each `ExecutionStarted` event creates. Other events have
+    /// the default priority.
+    pub priorities: Vec<Priority>,
+}
+
+/// Groups the pending messages of `state` by the instance they're routed to, keeping the order
+/// in which each instance receives them. Instances are created with the priority `routing`
+/// assigns them, unless they were started with one.
+pub fn route_pending_messages(
+    state: &OrchestrationRuntimeState,
+    routing: &TaskRouting,
+) -> Vec<InstanceMessages> {
+    let mut routes: Vec<InstanceMessages> = Vec::new();
+    for message in state.pending_messages() {
+        let Some(event) = message.history_event() else {
+            continue;
+        };
+        let i = match routes
+            .iter()
+            .position(|r| r.instance_id == message.target_instance_id())
+        {
+            Some(i) => i,
+            None => {
+                routes.push(InstanceMessages {
+                    instance_id: message.target_instance_id().to_string(),
+                    ..Default::default()
+                });
+                routes.len() - 1
+            }
+        };
+        routes[i].events.push(event.clone());
+        routes[i]
+            .target_execution_ids
+            .push(message.target_execution_id().map(str::to_string));
+        routes[i]
+            .reuse_policies
+            .push(message.reuse_policy().cloned());
+        routes[i].priorities.push(
+            message
+                .priority()
+                .unwrap_or_else(|| routing.orchestration_priority(event)),
+        );
+    }
+    routes
+}
+
+/// Splits a history that spans several executions into the events of each execution.
+///
+/// An execution starts with its `ExecutionStarted` event, or the `OrchestratorStarted` event
+/// right before it. Events before the first execution, if any, belong to it.
+pub fn split_history_by_execution(history: &[HistoryEvent]) -> Vec<&[HistoryEvent]> {
+    let mut starts = Vec::new();
+    for (i, e) in history.iter().enumerate() {
+        if let Some(ExecutionStarted(_)) = &e.event_type {
+            let start = match i.checked_sub(1).map(|j| &history[j].event_type) {
+                Some(Some(OrchestratorStarted(_))) => i - 1,
+                _ => i,
+            };
+            starts.push(if starts.is_empty() { 0 } else { start });
+        }
+    }
+    if starts.is_empty() {
+        return if history.is_empty() {
+            vec![]
+        } else {
+            vec![history]
+        };
+    }
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&history.len()]))
+        .map(|(&start, &end)| &history[start..end])
+        .collect()
+}
+
+/// Returns the events of the last execution of a history that spans several executions.
+pub fn current_execution(history: &[HistoryEvent]) -> &[HistoryEvent] {
+    split_history_by_execution(history)
+        .pop()
+        .unwrap_or_default()
+}
+
+/// Returns the events of the execution `execution_id` from a history that spans several
+/// executions, split as in [`split_history_by_execution`].
+pub fn filter_history_by_execution(
+    history: &[HistoryEvent],
+    execution_id: &str,
+) -> Vec<HistoryEvent> {
+    split_history_by_execution(history)
+        .into_iter()
+        .find(|events| {
+            events.iter().any(|e| match &e.event_type {
+                Some(ExecutionStarted(started)) => {
+                    started
+                        .orchestration_instance
+                        .as_ref()
+                        .and_then(|instance| instance.execution_id.as_deref())
+                        == Some(execution_id)
+                }
+                _ => false,
+            })
+        })
+        .map(<[HistoryEvent]>::to_vec)
+        .unwrap_or_default()
+}
+
 fn get_sub_orchestration_instances(
     old_events: &[HistoryEvent],
     new_events: &[HistoryEvent],
@@ -224,3 +596,176 @@ fn get_sub_orchestration_instance_id(event: &HistoryEvent) -> Option<String> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(
+        action: CreateOrchestrationAction,
+        statuses: &[OrchestrationStatus],
+    ) -> OrchestrationIdReusePolicy {
+        OrchestrationIdReusePolicy {
+            operation_status: statuses.iter().map(|s| *s as i32).collect(),
+            action: action as i32,
+        }
+    }
+
+    #[test]
+    fn test_reuse_policy_creates_new_instance() {
+        let decision =
+            evaluate_orchestration_id_reuse_policy(None, &OrchestrationIdReusePolicy::default());
+        assert_eq!(decision.unwrap(), CreateInstanceDecision::Create);
+    }
+
+    #[test]
+    fn test_reuse_policy_default_rejects_duplicates() {
+        let result = evaluate_orchestration_id_reuse_policy(
+            Some(OrchestrationStatus::Completed),
+            &resolve_orchestration_id_reuse_policy(&[with_orchestration_id_reuse_policy(None)])
+                .unwrap(),
+        );
+        assert!(matches!(result, Err(BackendError::DuplicateInstance)));
+    }
+
+    #[test]
+    fn test_reuse_policy_actions() {
+        let statuses = [OrchestrationStatus::Completed, OrchestrationStatus::Failed];
+
+        let ignore = policy(CreateOrchestrationAction::Ignore, &statuses);
+        let result =
+            evaluate_orchestration_id_reuse_policy(Some(OrchestrationStatus::Failed), &ignore);
+        assert!(matches!(result, Err(BackendError::IgnoreInstance)));
+
+        let terminate = policy(CreateOrchestrationAction::Terminate, &statuses);
+        let result = evaluate_orchestration_id_reuse_policy(
+            Some(OrchestrationStatus::Completed),
+            &terminate,
+        );
+        assert_eq!(result.unwrap(), CreateInstanceDecision::Replace);
+
+        let error = policy(CreateOrchestrationAction::Error, &statuses);
+        let result =
+            evaluate_orchestration_id_reuse_policy(Some(OrchestrationStatus::Completed), &error);
+        assert!(matches!(result, Err(BackendError::DuplicateInstance)));
+    }
+
+    #[test]
+    fn test_reuse_policy_status_mismatch_is_duplicate() {
+        let terminate = policy(
+            CreateOrchestrationAction::Terminate,
+            &[OrchestrationStatus::Completed],
+        );
+        let result =
+            evaluate_orchestration_id_reuse_policy(Some(OrchestrationStatus::Running), &terminate);
+        assert!(matches!(result, Err(BackendError::DuplicateInstance)));
+    }
+
+    #[test]
+    fn test_marshal_history_event_roundtrip() {
+        let e = crate::internal::new_event_raised_event("approval", Some(&"x".repeat(4096)));
+        let roundtrip = |compression: HistoryCompression| {
+            let bytes = marshal_history_event(&e, &compression).unwrap();
+            assert_eq!(unmarshal_history_event(&bytes, &compression).unwrap(), e);
+        };
+
+        roundtrip(HistoryCompression::none());
+        #[cfg(feature = "gzip")]
+        roundtrip(HistoryCompression::gzip());
+        #[cfg(feature = "zstd")]
+        roundtrip(HistoryCompression::zstd());
+    }
+
+    #[test]
+    fn test_resolve_reuse_policy_options() {
+        let expected = policy(
+            CreateOrchestrationAction::Terminate,
+            &[OrchestrationStatus::Running],
+        );
+        let resolved =
+            resolve_orchestration_id_reuse_policy(&[with_orchestration_id_reuse_policy(Some(
+                expected.clone(),
+            ))])
+            .unwrap();
+        assert_eq!(resolved, expected);
+    }
+
+    #[test]
+    fn test_filter_history_by_execution() {
+        use crate::internal::{new_execution_started_event, new_orchestrator_started_event};
+
+        let first = new_execution_started_event("counter", "abc", Some("1"), None, None, None);
+        let second = new_execution_started_event("counter", "abc", Some("2"), None, None, None);
+        let execution_id = |e: &HistoryEvent| match &e.event_type {
+            Some(ExecutionStarted(started)) => started
+                .orchestration_instance
+                .as_ref()
+                .unwrap()
+                .execution_id
+                .clone()
+                .unwrap(),
+            _ => unreachable!(),
+        };
+        let history = [
+            first.clone(),
+            new_orchestrator_started_event(),
+            second.clone(),
+            new_orchestrator_started_event(),
+            new_orchestrator_started_event(),
+        ];
+
+        assert_eq!(
+            filter_history_by_execution(&history, &execution_id(&first)),
+            history[..1].to_vec()
+        );
+        assert_eq!(
+            filter_history_by_execution(&history, &execution_id(&second)),
+            history[1..].to_vec()
+        );
+        assert_eq!(current_execution(&history), &history[1..]);
+        assert!(filter_history_by_execution(&history, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_route_pending_messages() {
+        use crate::durabletask_pb::history_event::EventType::EventRaised;
+        use crate::internal::{
+            new_create_sub_orchestration_action, new_execution_started_event, new_send_event_action,
+        };
+
+        let mut state = OrchestrationRuntimeState::new(
+            &InstanceID("abc".to_string()),
+            &[new_execution_started_event(
+                "parent", "abc", None, None, None, None,
+            )],
+        );
+        state
+            .apply_actions(&[
+                new_send_event_action(0, "other", "first", None),
+                new_create_sub_orchestration_action(1, "child", "", None),
+                new_send_event_action(2, "other", "second", None),
+            ])
+            .unwrap();
+
+        let routing = TaskRouting::default().with_orchestration("child", Priority::High);
+        let routes = route_pending_messages(&state, &routing);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].instance_id, "other");
+        let names: Vec<_> = routes[0]
+            .events
+            .iter()
+            .map(|e| match &e.event_type {
+                Some(EventRaised(raised)) => raised.name.as_str(),
+                e => panic!("unexpected event: {e:?}"),
+            })
+            .collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(routes[0].target_execution_ids, [None, None]);
+        assert_eq!(routes[1].instance_id, "abc:0001");
+        assert!(matches!(
+            routes[1].events[0].event_type,
+            Some(ExecutionStarted(_))
+        ));
+        assert_eq!(routes[1].priorities, [Priority::High]);
+    }
+}
@@ -1 +1,371 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
 
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use prost::Message;
+
+use crate::backend::cache::{ExtendedSessionOptions, SessionCache};
+use crate::backend::commit::OrchestrationCommit;
+use crate::backend::deadletter::DeadLetter;
+use crate::backend::executor::Executor;
+use crate::backend::lock::WorkItemLock;
+use crate::backend::routing::TaskRouting;
+use crate::backend::runtimestate::OrchestrationRuntimeState;
+use crate::backend::worker::TaskProcessor;
+use crate::backend::workitem::OrchestrationWorkItem;
+use crate::backend::{Backend, BackendError};
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::{HistoryEvent, OrchestrationStatus, TaskFailureDetails};
+use crate::internal::{new_complete_orchestration_action, new_orchestrator_started_event};
+use crate::Error;
+
+/// Fetches orchestration work items and runs them through the [`Executor`].
+pub(crate) struct OrchestrationProcessor {
+    be: Arc<dyn Backend>,
+    executor: Arc<dyn Executor>,
+    sessions: Option<SessionCache<OrchestrationRuntimeState>>,
+    snapshot_threshold: Option<usize>,
+    routing: TaskRouting,
+}
+
+impl OrchestrationProcessor {
+    pub(crate) fn new(be: Arc<dyn Backend>, executor: Arc<dyn Executor>) -> Self {
+        OrchestrationProcessor {
+            be,
+            executor,
+            sessions: None,
+            snapshot_threshold: None,
+            routing: TaskRouting::default(),
+        }
+    }
+
+    /// Routes the activities and orchestrations started by work items with `routing`.
+    pub(crate) fn with_routing(mut self, routing: TaskRouting) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Compacts the history of running orchestrations once `threshold` events were committed
+    /// since their last snapshot.
+    pub(crate) fn with_snapshot_threshold(mut self, threshold: usize) -> Self {
+        self.snapshot_threshold = Some(threshold);
+        self
+    }
+
+    /// Keeps the runtime state of instances in memory between work items.
+    pub(crate) fn with_sessions(mut self, options: ExtendedSessionOptions) -> Self {
+        self.sessions = Some(SessionCache::new(options));
+        self
+    }
+
+    /// Returns the cached runtime state of the work item's instance, or loads it from the
+    /// backend when it isn't cached or the backend reports a different history.
+    async fn load_state(
+        &self,
+        wi: &OrchestrationWorkItem,
+    ) -> Result<OrchestrationRuntimeState, BackendError> {
+        let cached = self
+            .sessions
+            .as_ref()
+            .and_then(|sessions| sessions.take(&wi.instance_id.0))
+            .filter(|state| !matches!(wi.history_length, Some(n) if n != state.old_events().len()));
+        match cached {
+            Some(state) => Ok(state),
+            None => self.be.get_orchestration_runtime_state(wi).await,
+        }
+    }
+
+    /// Appends the work item's new events to its runtime state. Returns `false` when there's
+    /// nothing for the orchestrator to run.
+    fn apply_new_events(&self, wi: &mut OrchestrationWorkItem) -> Result<bool, Error> {
+        // Late results, e.g. of activities that finished after the instance was terminated,
+        // aren't appended to a completed history
+        if wi.state.is_completed() {
+            return Ok(false);
+        }
+        wi.state
+            .add_event(&new_orchestrator_started_event(), true)?;
+        for (i, e) in wi.new_events.iter().enumerate() {
+            if is_stale(wi, i) || is_duplicate_start(wi, e) {
+                continue;
+            }
+            wi.state.add_event(e, true)?;
+        }
+        Ok(wi.state.name().is_ok())
+    }
+}
+
+/// How many executions one work item runs when each continues as new without waiting for
+/// anything, before the orchestration is failed.
+const MAX_CONTINUE_AS_NEW_PER_WORK_ITEM: usize = 20;
+
+/// Fails an orchestration that kept continuing as new in the same work item.
+fn fail_tight_loop(state: &mut OrchestrationRuntimeState) -> Result<(), Error> {
+    let details = TaskFailureDetails {
+        error_type: "ContinueAsNewLoop".to_string(),
+        error_message: format!(
+            "continued as new {} times without waiting for an event",
+            MAX_CONTINUE_AS_NEW_PER_WORK_ITEM
+        ),
+        is_non_retriable: true,
+        ..Default::default()
+    };
+    let fail = new_complete_orchestration_action(
+        -1,
+        OrchestrationStatus::Failed,
+        None,
+        &[],
+        Some(&details),
+    );
+    state.apply_actions(&[fail]).map(|_| ())
+}
+
+/// Whether the `i`th new event is addressed to an execution other than the current one, e.g.
+/// the result of an activity scheduled before the orchestration continued as new.
+fn is_stale(wi: &OrchestrationWorkItem, i: usize) -> bool {
+    match (wi.target_execution_ids.get(i), wi.state.execution_id()) {
+        (Some(Some(target)), Some(current)) => target != current,
+        _ => false,
+    }
+}
+
+/// Whether `e` starts an instance that already started, e.g. a start delivered twice.
+fn is_duplicate_start(wi: &OrchestrationWorkItem, e: &HistoryEvent) -> bool {
+    matches!(e.event_type, Some(EventType::ExecutionStarted(_))) && wi.state.name().is_ok()
+}
+
+#[async_trait]
+impl TaskProcessor for OrchestrationProcessor {
+    type WorkItem = OrchestrationWorkItem;
+
+    async fn fetch_work_item(&self) -> Result<OrchestrationWorkItem, BackendError> {
+        self.be.get_orchestration_work_item().await
+    }
+
+    async fn process_work_item(&self, wi: &mut OrchestrationWorkItem) -> Result<(), Error> {
+        wi.state = self.load_state(wi).await?;
+        if !self.apply_new_events(wi)? {
+            return Ok(());
+        }
+
+        // The next execution runs right away, like an instance that was just started
+        for generation in 0.. {
+            let output = self
+                .executor
+                .execute_orchestrator(
+                    &wi.instance_id,
+                    wi.state.old_events(),
+                    wi.state.new_events(),
+                )
+                .await?;
+            wi.state.set_detached_starts(output.detached_starts);
+            if !wi.state.apply_actions(&output.response.actions)? {
+                wi.state.set_pending_task_options(output.activity_options);
+                wi.state.set_custom_status(output.response.custom_status);
+                break;
+            }
+            if generation + 1 >= MAX_CONTINUE_AS_NEW_PER_WORK_ITEM {
+                fail_tight_loop(&mut wi.state)?;
+                break;
+            }
+        }
+        if let Some(threshold) = self.snapshot_threshold {
+            if !wi.state.continued_as_new()
+                && !wi.state.is_completed()
+                && wi.state.events_since_snapshot() >= threshold
+            {
+                wi.state.take_snapshot();
+            }
+        }
+        Ok(())
+    }
+
+    async fn complete_work_item(&self, wi: &OrchestrationWorkItem) -> Result<(), BackendError> {
+        let commit = OrchestrationCommit::new(wi, &self.routing);
+        self.be.complete_orchestration_work_item(wi, &commit).await
+    }
+
+    async fn abandon_work_item(
+        &self,
+        wi: &OrchestrationWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.be.abandon_orchestration_work_item(wi, delay).await
+    }
+
+    async fn defer_work_item(
+        &self,
+        wi: &OrchestrationWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.be.defer_orchestration_work_item(wi, delay).await
+    }
+
+    fn retry_count(&self, wi: &OrchestrationWorkItem) -> i32 {
+        wi.retry_count
+    }
+
+    async fn dead_letter_work_item(
+        &self,
+        wi: &OrchestrationWorkItem,
+        failure_details: &TaskFailureDetails,
+    ) -> Result<(), BackendError> {
+        let dead_letter = DeadLetter::from_orchestration_work_item(wi, failure_details);
+        self.be
+            .dead_letter_orchestration_work_item(wi, &dead_letter)
+            .await
+    }
+
+    fn work_item_lock(&self, wi: &OrchestrationWorkItem) -> Option<WorkItemLock> {
+        wi.lock()
+    }
+
+    async fn renew_work_item_lock(&self, lock: &WorkItemLock) -> Result<SystemTime, BackendError> {
+        self.be.renew_work_item_lock(lock).await
+    }
+
+    async fn release_work_item(&self, mut wi: OrchestrationWorkItem) {
+        let Some(sessions) = &self.sessions else {
+            return;
+        };
+        if wi.state.is_completed() || wi.state.name().is_err() {
+            return;
+        }
+        wi.state.commit();
+        let size = wi.state.old_events().iter().map(|e| e.encoded_len()).sum();
+        sessions.put(&wi.instance_id.0, wi.state, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::InstanceID;
+    use crate::backend::routing::Priority;
+    use crate::backend::testing::TestBackend;
+    use crate::internal::{new_execution_started_event, new_task_completed_event};
+    use crate::payload::{JsonCodec, PayloadPipeline};
+    use crate::task::executor::TaskExecutor;
+    use crate::task::{OrchestrationContext, TaskRegistry};
+
+    #[test]
+    fn test_stale_events() {
+        let id = InstanceID("abc".to_string());
+        let state = OrchestrationRuntimeState::new(
+            &id,
+            &[new_execution_started_event(
+                "greet", "abc", None, None, None, None,
+            )],
+        );
+        let current = state.execution_id().unwrap().to_string();
+        let wi = OrchestrationWorkItem {
+            instance_id: id,
+            new_events: vec![
+                new_task_completed_event(0, None),
+                new_task_completed_event(0, None),
+                new_task_completed_event(1, None),
+            ],
+            target_execution_ids: vec![Some("previous".to_string()), Some(current), None],
+            state,
+            ..Default::default()
+        };
+        assert!(is_stale(&wi, 0));
+        assert!(!is_stale(&wi, 1));
+        assert!(!is_stale(&wi, 2));
+        assert!(!is_stale(&wi, 3));
+    }
+
+    fn test_processor(be: Arc<TestBackend>) -> OrchestrationProcessor {
+        processor_with(be, TaskRegistry::new())
+    }
+
+    fn processor_with(be: Arc<TestBackend>, registry: TaskRegistry) -> OrchestrationProcessor {
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        OrchestrationProcessor::new(be, Arc::new(executor))
+    }
+
+    #[test]
+    fn test_apply_new_events() {
+        let processor = test_processor(Arc::new(TestBackend::default()));
+        let start = new_execution_started_event("greet", "abc", None, None, None, None);
+        let mut wi = OrchestrationWorkItem {
+            instance_id: InstanceID("abc".to_string()),
+            new_events: vec![start.clone(), start],
+            ..Default::default()
+        };
+        // A start delivered twice is skipped
+        assert!(processor.apply_new_events(&mut wi).unwrap());
+        assert_eq!(wi.state.new_events().len(), 2);
+
+        // Events the runtime state rejects fail the work item
+        let mut invalid = new_task_completed_event(0, None);
+        invalid.timestamp = None;
+        wi.new_events = vec![invalid];
+        assert!(matches!(
+            processor.apply_new_events(&mut wi),
+            Err(Error::InvalidHistory(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_defer_isnt_a_failed_attempt() {
+        let be = Arc::new(TestBackend::default());
+        let processor = test_processor(be.clone());
+        let start = new_execution_started_event("greet", "abc", None, None, None, None);
+        be.create_orchestration_instance(&start, Priority::default(), Vec::new())
+            .await
+            .unwrap();
+
+        // Work items released on shutdown don't count towards the retry limit
+        let wi = processor.fetch_work_item().await.unwrap();
+        processor
+            .defer_work_item(&wi, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(*be.calls.lock().unwrap(), ["defer_orchestration_work_item"]);
+        let wi = processor.fetch_work_item().await.unwrap();
+        assert_eq!(wi.retry_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_continue_as_new_tight_loop_fails() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("forever", |ctx: OrchestrationContext| async move {
+                ctx.continue_as_new(&(), false)?;
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        let be = Arc::new(TestBackend::default());
+        let processor = processor_with(be.clone(), registry);
+        let start = new_execution_started_event("forever", "abc", None, None, None, None);
+        be.create_orchestration_instance(&start, Priority::default(), Vec::new())
+            .await
+            .unwrap();
+
+        let mut wi = processor.fetch_work_item().await.unwrap();
+        processor.process_work_item(&mut wi).await.unwrap();
+        assert_eq!(wi.state.runtime_status(), OrchestrationStatus::Failed);
+        let details = wi.state.failure_details().unwrap();
+        assert_eq!(details.error_type, "ContinueAsNewLoop");
+    }
+}
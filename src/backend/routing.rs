@@ -0,0 +1,236 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::HistoryEvent;
+use crate::task::ActivityOptions;
+
+/// The task queue of activities that aren't routed anywhere else.
+pub const DEFAULT_TASK_QUEUE: &str = "default";
+
+/// The priority of a work item. Backends hand out work items with a higher priority first, and
+/// work items of the same priority in the order they were enqueued.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Where the work items of an activity are enqueued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskRoute {
+    task_queue: String,
+    priority: Priority,
+}
+
+impl Default for TaskRoute {
+    fn default() -> Self {
+        TaskRoute {
+            task_queue: DEFAULT_TASK_QUEUE.to_string(),
+            priority: Priority::default(),
+        }
+    }
+}
+
+impl TaskRoute {
+    pub fn with_task_queue(mut self, task_queue: &str) -> Self {
+        self.task_queue = task_queue.to_string();
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn task_queue(&self) -> &str {
+        &self.task_queue
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+/// Assigns task queues and priorities to work items by the name of their activity or
+/// orchestration.
+///
+/// The routing is applied when work items are enqueued: activities when the orchestration that
+/// schedules them is committed, and orchestrations when they're created, which sets the
+/// priority of every work item of the instance. Configure the same routing on the workers and
+/// clients of a task hub.
+#[derive(Clone, Debug, Default)]
+pub struct TaskRouting {
+    activities: HashMap<String, TaskRoute>,
+    orchestrations: HashMap<String, Priority>,
+}
+
+impl TaskRouting {
+    pub fn with_activity(mut self, name: &str, route: TaskRoute) -> Self {
+        self.activities.insert(name.to_string(), route);
+        self
+    }
+
+    pub fn with_orchestration(mut self, name: &str, priority: Priority) -> Self {
+        self.orchestrations.insert(name.to_string(), priority);
+        self
+    }
+
+    /// The route of the activity scheduled by a `TaskScheduled` event, with the task queue and
+    /// priority it was scheduled with, if any, taking precedence.
+    pub(crate) fn route_activity(
+        &self,
+        event: &HistoryEvent,
+        options: Option<&ActivityOptions>,
+    ) -> TaskRoute {
+        let mut route = match &event.event_type {
+            Some(EventType::TaskScheduled(scheduled)) => self
+                .activities
+                .get(&scheduled.name)
+                .cloned()
+                .unwrap_or_default(),
+            _ => TaskRoute::default(),
+        };
+        if let Some(task_queue) = options.and_then(|options| options.task_queue()) {
+            route.task_queue = task_queue.to_string();
+        }
+        if let Some(priority) = options.and_then(|options| options.priority()) {
+            route.priority = priority;
+        }
+        route
+    }
+
+    /// The priority of the orchestration started by an `ExecutionStarted` event.
+    pub(crate) fn orchestration_priority(&self, event: &HistoryEvent) -> Priority {
+        match &event.event_type {
+            Some(EventType::ExecutionStarted(started)) => self
+                .orchestrations
+                .get(&started.name)
+                .copied()
+                .unwrap_or_default(),
+            _ => Priority::default(),
+        }
+    }
+}
+
+/// The activity work items a worker fetches.
+///
+/// By default only the [default task queue](DEFAULT_TASK_QUEUE) is fetched, so workers only
+/// pick up activities routed elsewhere, e.g. to a queue of GPU workers, when they opt in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkItemFilter {
+    task_queues: HashSet<String>,
+    activity_names: Option<HashSet<String>>,
+}
+
+impl Default for WorkItemFilter {
+    fn default() -> Self {
+        WorkItemFilter {
+            task_queues: HashSet::from([DEFAULT_TASK_QUEUE.to_string()]),
+            activity_names: None,
+        }
+    }
+}
+
+impl WorkItemFilter {
+    /// Fetches activities from `task_queues` instead of the default task queue.
+    pub fn with_task_queues<S: AsRef<str>>(mut self, task_queues: &[S]) -> Self {
+        self.task_queues = task_queues
+            .iter()
+            .map(|queue| queue.as_ref().to_string())
+            .collect();
+        self
+    }
+
+    /// Only fetches activities with one of these names.
+    pub fn with_activity_names<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+        self.activity_names = Some(names.iter().map(|name| name.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Whether an activity named `name` enqueued with `route` passes the filter.
+    pub fn matches(&self, name: &str, route: &TaskRoute) -> bool {
+        let name_matches = match &self.activity_names {
+            Some(names) => names.contains(name),
+            None => true,
+        };
+        name_matches && self.task_queues.contains(route.task_queue())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::{new_execution_started_event, new_task_scheduled_event};
+
+    #[test]
+    fn test_task_routing() {
+        let gpu = TaskRoute::default()
+            .with_task_queue("gpu")
+            .with_priority(Priority::Low);
+        let routing = TaskRouting::default()
+            .with_activity("render", gpu.clone())
+            .with_orchestration("checkout", Priority::High);
+
+        let render = new_task_scheduled_event(0, "render", None, None, None);
+        let resize = new_task_scheduled_event(1, "resize", None, None, None);
+        assert_eq!(routing.route_activity(&render, None), gpu);
+        assert_eq!(routing.route_activity(&resize, None), TaskRoute::default());
+
+        // Options of the call take precedence
+        let urgent = ActivityOptions::new().with_priority(Priority::High);
+        assert_eq!(
+            routing.route_activity(&render, Some(&urgent)),
+            gpu.clone().with_priority(Priority::High)
+        );
+        let cpu = ActivityOptions::new().with_task_queue("cpu");
+        assert_eq!(
+            routing.route_activity(&resize, Some(&cpu)),
+            TaskRoute::default().with_task_queue("cpu")
+        );
+
+        let checkout = new_execution_started_event("checkout", "abc", None, None, None, None);
+        let report = new_execution_started_event("report", "xyz", None, None, None, None);
+        assert_eq!(routing.orchestration_priority(&checkout), Priority::High);
+        assert_eq!(routing.orchestration_priority(&report), Priority::Normal);
+        assert!(Priority::High > Priority::Normal && Priority::Normal > Priority::Low);
+    }
+
+    #[test]
+    fn test_work_item_filter() {
+        let default_route = TaskRoute::default();
+        let gpu = TaskRoute::default().with_task_queue("gpu");
+
+        let filter = WorkItemFilter::default();
+        assert!(filter.matches("resize", &default_route));
+        assert!(!filter.matches("render", &gpu));
+
+        let filter = WorkItemFilter::default().with_task_queues(&["gpu", DEFAULT_TASK_QUEUE]);
+        assert!(filter.matches("render", &gpu));
+        assert!(filter.matches("resize", &default_route));
+
+        let filter = WorkItemFilter::default().with_activity_names(&["resize"]);
+        assert!(filter.matches("resize", &default_route));
+        assert!(!filter.matches("thumbnail", &default_route));
+    }
+}
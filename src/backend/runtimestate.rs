@@ -14,25 +14,59 @@
   limitations under the License.
 */
 use core::fmt;
-use std::{error::Error, time::SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 use prost_wkt_types::Timestamp;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    api,
+    api::{self, OrchestrationIdReusePolicy},
+    backend::{executor::DetachedStart, routing::Priority},
     durabletask_pb::{
         history_event::EventType, orchestrator_action::OrchestratorActionType,
-        ExecutionCompletedEvent, ExecutionStartedEvent, HistoryEvent, OrchestrationStatus,
-        OrchestratorAction, SubOrchestrationInstanceCompletedEvent, TaskFailureDetails,
+        CompleteOrchestrationAction, ExecutionCompletedEvent, ExecutionStartedEvent, HistoryEvent,
+        OrchestrationState, OrchestrationStatus, OrchestratorAction, TaskFailureDetails,
     },
     internal::{self, to_runtime_status_string},
+    task::ActivityOptions,
+    Error,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OrchestratorMessage {
     history_event: Option<HistoryEvent>,
     target_instance_id: String,
+    target_execution_id: Option<String>,
+    reuse_policy: Option<OrchestrationIdReusePolicy>,
+    priority: Option<Priority>,
+}
+
+impl OrchestratorMessage {
+    pub fn history_event(&self) -> Option<&HistoryEvent> {
+        self.history_event.as_ref()
+    }
+
+    pub fn target_instance_id(&self) -> &str {
+        &self.target_instance_id
+    }
+
+    /// The execution of the target instance the message is addressed to. Backends should drop
+    /// the message once that execution has ended.
+    pub fn target_execution_id(&self) -> Option<&str> {
+        self.target_execution_id.as_deref()
+    }
+
+    /// The policy to create the target instance with when the message is the
+    /// `ExecutionStarted` event of a detached orchestration.
+    pub fn reuse_policy(&self) -> Option<&OrchestrationIdReusePolicy> {
+        self.reuse_policy.as_ref()
+    }
+
+    /// The priority a detached orchestration was started with, if any.
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -41,8 +75,12 @@ pub struct OrchestrationRuntimeState {
     pub(crate) new_events: Vec<HistoryEvent>,
     pub(crate) old_events: Vec<HistoryEvent>,
     pending_tasks: Vec<HistoryEvent>,
+    /// The options of pending tasks scheduled with any, by task ID.
+    pending_task_options: HashMap<i32, ActivityOptions>,
     pending_timers: Vec<HistoryEvent>,
     pending_messages: Vec<OrchestratorMessage>,
+    /// The detached orchestrations the next applied actions start, by task ID.
+    detached_starts: HashMap<i32, DetachedStart>,
     start_event: Option<ExecutionStartedEvent>,
     completed_event: Option<ExecutionCompletedEvent>,
     created_time: Option<SystemTime>,
@@ -51,10 +89,13 @@ pub struct OrchestrationRuntimeState {
     continued_as_new: bool,
     is_suspended: bool,
     custom_status: Option<String>,
+    snapshot: Option<Vec<HistoryEvent>>,
+    /// The new events of the executions that continued as new since the state was loaded.
+    ended_executions: Vec<HistoryEvent>,
 }
 
 impl OrchestrationRuntimeState {
-    fn new(instance_id: &api::InstanceID, existing_history: &[HistoryEvent]) -> Self {
+    pub fn new(instance_id: &api::InstanceID, existing_history: &[HistoryEvent]) -> Self {
         let mut state = OrchestrationRuntimeState {
             instance_id: instance_id.to_owned(),
             new_events: Vec::with_capacity(10),
@@ -69,21 +110,23 @@ impl OrchestrationRuntimeState {
         state
     }
 
-    pub fn add_event(&mut self, event: &HistoryEvent, is_new: bool) -> Result<(), Box<dyn Error>> {
+    pub fn add_event(&mut self, event: &HistoryEvent, is_new: bool) -> Result<(), Error> {
         match &event.event_type {
             Some(EventType::ExecutionStarted(started_event)) => {
                 if self.start_event.is_some() {
-                    return Err("Duplicate start event".into());
+                    return Err(Error::InvalidHistory("duplicate start event".to_string()));
                 }
                 self.start_event = Some(started_event.clone());
-                self.created_time = Some(SystemTime::try_from(event.timestamp.clone().unwrap())?);
+                self.created_time = Some(event_time(event)?);
             }
             Some(EventType::ExecutionCompleted(completed_event)) => {
                 if self.completed_event.is_some() {
-                    return Err("Duplicate completed event".into());
+                    return Err(Error::InvalidHistory(
+                        "duplicate completed event".to_string(),
+                    ));
                 }
                 self.completed_event = Some(completed_event.clone());
-                self.completed_time = Some(SystemTime::try_from(event.timestamp.clone().unwrap())?);
+                self.completed_time = Some(event_time(event)?);
             }
             Some(EventType::ExecutionSuspended(_)) => {
                 self.is_suspended = true;
@@ -96,7 +139,7 @@ impl OrchestrationRuntimeState {
             }
         }
 
-        self.last_updated_time = Some(SystemTime::try_from(event.timestamp.clone().unwrap())?);
+        self.last_updated_time = Some(event_time(event)?);
 
         if is_new {
             self.new_events.push(event.clone());
@@ -111,10 +154,7 @@ impl OrchestrationRuntimeState {
         (self.old_events.is_empty() && self.new_events.is_empty()) || self.start_event.is_some()
     }
 
-    pub fn apply_actions(
-        &mut self,
-        actions: &[OrchestratorAction],
-    ) -> Result<bool, Box<dyn Error>> {
+    pub fn apply_actions(&mut self, actions: &[OrchestratorAction]) -> Result<bool, Error> {
         let mut continued_as_new = false;
 
         for action in actions {
@@ -123,10 +163,23 @@ impl OrchestrationRuntimeState {
                     if completed_action.orchestration_status()
                         == OrchestrationStatus::ContinuedAsNew
                     {
+                        // The ended execution is kept in history, before the new one
+                        self.add_event(
+                            &internal::new_execution_completed_event(
+                                -1,
+                                completed_action.orchestration_status,
+                                completed_action.result.as_deref(),
+                                None,
+                            ),
+                            true,
+                        )?;
                         let mut new_state = OrchestrationRuntimeState::new(&self.instance_id, &[]);
                         new_state.continued_as_new = true;
+                        new_state.ended_executions = std::mem::take(&mut self.ended_executions);
+                        new_state.ended_executions.append(&mut self.new_events);
 
-                        let _ = new_state.add_event(
+                        new_state.add_event(&internal::new_orchestrator_started_event(), true)?;
+                        new_state.add_event(
                             &internal::new_execution_started_event(
                                 &self.start_event.clone().unwrap().name,
                                 &self.instance_id(),
@@ -136,11 +189,13 @@ impl OrchestrationRuntimeState {
                                 None,
                             ),
                             true,
-                        );
+                        )?;
 
                         for event in completed_action.carryover_events.iter() {
                             new_state.add_event(event, true)?;
                         }
+                        // Messages sent before continuing as new are still delivered
+                        new_state.pending_messages = std::mem::take(&mut self.pending_messages);
 
                         *self = new_state;
                         continued_as_new = true;
@@ -164,22 +219,28 @@ impl OrchestrationRuntimeState {
                         if let Some(parent_instance) =
                             self.start_event.as_ref().unwrap().parent_instance.as_ref()
                         {
+                            let history_event = match completed_action.orchestration_status() {
+                                OrchestrationStatus::Completed => {
+                                    internal::new_sub_orchestration_completed_event(
+                                        parent_instance.task_scheduled_id,
+                                        completed_action.result.as_deref(),
+                                    )
+                                }
+                                _ => internal::new_sub_orchestration_failed_event(
+                                    parent_instance.task_scheduled_id,
+                                    Some(&sub_orchestration_failure_details(completed_action)),
+                                ),
+                            };
+                            let parent = parent_instance.orchestration_instance.as_ref();
                             self.pending_messages.push(OrchestratorMessage {
-                                history_event: Some(HistoryEvent {
-                                    event_id: -1,
-                                    timestamp: Some(Timestamp::from(SystemTime::now())),
-                                    event_type: Some(EventType::SubOrchestrationInstanceCompleted(
-                                        SubOrchestrationInstanceCompletedEvent {
-                                            task_scheduled_id: parent_instance.task_scheduled_id,
-                                            result: completed_action.result.clone(),
-                                        },
-                                    )),
-                                }),
-                                target_instance_id: parent_instance
-                                    .to_owned()
-                                    .orchestration_instance
-                                    .unwrap()
-                                    .instance_id,
+                                history_event: Some(history_event),
+                                target_instance_id: parent
+                                    .map(|instance| instance.instance_id.clone())
+                                    .unwrap_or_default(),
+                                target_execution_id: parent
+                                    .and_then(|instance| instance.execution_id.clone()),
+                                reuse_policy: None,
+                                priority: None,
                             });
                         }
                     }
@@ -211,7 +272,7 @@ impl OrchestrationRuntimeState {
                 }
                 Some(OrchestratorActionType::CreateSubOrchestration(create_so)) => {
                     let instance_id = if create_so.instance_id.is_empty() {
-                        format!("{:?}:{:04x}", self.instance_id, action.id)
+                        internal::sub_orchestration_instance_id(&self.instance_id.0, action.id)
                     } else {
                         create_so.instance_id.clone()
                     };
@@ -222,27 +283,38 @@ impl OrchestrationRuntimeState {
                             &create_so.name,
                             create_so.version.as_deref(),
                             create_so.input.as_deref(),
-                            &create_so.instance_id,
+                            &instance_id,
                             None, // TODO: Revisit context
                         );
 
                     self.add_event(&sub_orchestration_created_event, true)?;
 
-                    let sub_orchestration_start_event = internal::new_execution_started_event(
-                        &create_so.name,
-                        &create_so.instance_id,
-                        create_so.input.as_deref(),
-                        Some(internal::new_parent_info(
+                    // A detached orchestration is started without a parent to report back to
+                    let detached = self.detached_starts.remove(&action.id);
+                    let parent = match detached {
+                        Some(_) => None,
+                        None => Some(internal::new_parent_info(
                             action.id,
                             &self.start_event.as_ref().unwrap().name,
                             &self.instance_id(),
+                            self.execution_id(),
                         )),
+                    };
+                    let detached = detached.unwrap_or_default();
+                    let sub_orchestration_start_event = internal::new_execution_started_event(
+                        &create_so.name,
+                        &instance_id,
+                        create_so.input.as_deref(),
+                        parent,
                         None, // TODO: Revisit context
-                        None,
+                        detached.scheduled_start_timestamp,
                     );
                     self.pending_messages.push(OrchestratorMessage {
                         history_event: Some(sub_orchestration_start_event),
                         target_instance_id: instance_id,
+                        target_execution_id: None,
+                        reuse_policy: detached.reuse_policy,
+                        priority: detached.priority,
                     });
                 }
                 Some(OrchestratorActionType::SendEvent(send_event)) => {
@@ -254,9 +326,16 @@ impl OrchestrationRuntimeState {
                     );
 
                     self.add_event(&send_event_event, true)?;
+                    // The target receives the event as an external event
                     self.pending_messages.push(OrchestratorMessage {
-                        history_event: Some(send_event_event),
+                        history_event: Some(internal::new_event_raised_event(
+                            &send_event.name,
+                            send_event.data.as_deref(),
+                        )),
                         target_instance_id: send_event.instance.clone().unwrap().instance_id,
+                        target_execution_id: None,
+                        reuse_policy: None,
+                        priority: None,
                     });
                 }
                 Some(OrchestratorActionType::TerminateOrchestration(terminate)) => {
@@ -268,10 +347,16 @@ impl OrchestrationRuntimeState {
                     self.pending_messages.push(OrchestratorMessage {
                         history_event: Some(terminate_event),
                         target_instance_id: terminate.instance_id.clone(),
+                        target_execution_id: None,
+                        reuse_policy: None,
+                        priority: None,
                     });
                 }
                 _ => {
-                    return Err(format!("Unknown action type: {:?}", action).into());
+                    return Err(Error::InvalidHistory(format!(
+                        "unknown action type: {:?}",
+                        action
+                    )));
                 }
             }
         }
@@ -283,27 +368,38 @@ impl OrchestrationRuntimeState {
         self.instance_id.to_owned().0
     }
 
-    pub fn name(&self) -> Result<&str, Box<dyn Error>> {
+    /// The ID of the current execution, which changes each time the orchestration continues
+    /// as new.
+    pub fn execution_id(&self) -> Option<&str> {
+        self.start_event
+            .as_ref()?
+            .orchestration_instance
+            .as_ref()?
+            .execution_id
+            .as_deref()
+    }
+
+    pub fn name(&self) -> Result<&str, Error> {
         if let Some(start_event) = &self.start_event {
             Ok(&start_event.name)
         } else {
-            Err(api::ERR_NOT_STARTED.into())
+            Err(Error::NotStarted)
         }
     }
 
-    pub fn input(&self) -> Result<&str, Box<dyn Error>> {
+    pub fn input(&self) -> Result<&str, Error> {
         if let Some(start_event) = &self.start_event {
             Ok(start_event.input.as_ref().unwrap())
         } else {
-            Err(api::ERR_NOT_STARTED.into())
+            Err(Error::NotStarted)
         }
     }
 
-    pub fn output(&self) -> Result<&str, Box<dyn Error>> {
+    pub fn output(&self) -> Result<&str, Error> {
         if let Some(completed_event) = &self.completed_event {
             Ok(completed_event.result.as_ref().unwrap())
         } else {
-            Err(api::ERR_NOT_COMPLETED.into())
+            Err(Error::NotCompleted)
         }
     }
 
@@ -319,27 +415,27 @@ impl OrchestrationRuntimeState {
         }
     }
 
-    pub fn created_time(&self) -> Result<SystemTime, Box<dyn Error>> {
+    pub fn created_time(&self) -> Result<SystemTime, Error> {
         if let Some(_start_event) = &self.start_event {
             Ok(self.created_time.expect("created time"))
         } else {
-            Err(api::ERR_NOT_STARTED.into())
+            Err(Error::NotStarted)
         }
     }
 
-    pub fn last_updated_time(&self) -> Result<SystemTime, Box<dyn Error>> {
+    pub fn last_updated_time(&self) -> Result<SystemTime, Error> {
         if let Some(_start_event) = &self.start_event {
             Ok(self.last_updated_time.expect("last updated time"))
         } else {
-            Err(api::ERR_NOT_STARTED.into())
+            Err(Error::NotStarted)
         }
     }
 
-    pub fn completed_time(&self) -> Result<SystemTime, Box<dyn Error>> {
+    pub fn completed_time(&self) -> Result<SystemTime, Error> {
         if let Some(_completed_event) = &self.completed_event {
             Ok(self.completed_time.expect("system time"))
         } else {
-            Err(api::ERR_NOT_COMPLETED.into())
+            Err(Error::NotCompleted)
         }
     }
 
@@ -355,15 +451,15 @@ impl OrchestrationRuntimeState {
         &self.new_events
     }
 
-    pub fn failure_details(&self) -> Result<&TaskFailureDetails, Box<dyn Error>> {
+    pub fn failure_details(&self) -> Result<&TaskFailureDetails, Error> {
         if let Some(completed_event) = &self.completed_event {
             if let Some(failure_details) = &completed_event.failure_details {
                 Ok(failure_details)
             } else {
-                Err(api::ERR_NO_FAILURES.into())
+                Err(Error::NoFailures)
             }
         } else {
-            Err(api::ERR_NOT_COMPLETED.into())
+            Err(Error::NotCompleted)
         }
     }
 
@@ -375,6 +471,23 @@ impl OrchestrationRuntimeState {
         &self.pending_tasks
     }
 
+    /// The options the pending task `task_id` was scheduled with.
+    pub fn pending_task_options(&self, task_id: i32) -> Option<&ActivityOptions> {
+        self.pending_task_options.get(&task_id)
+    }
+
+    /// Attaches the options of the activities scheduled by the last applied actions.
+    pub(crate) fn set_pending_task_options(&mut self, mut options: HashMap<i32, ActivityOptions>) {
+        options.retain(|id, _| self.pending_tasks.iter().any(|e| e.event_id == *id));
+        self.pending_task_options.extend(options);
+    }
+
+    /// Marks the `CreateSubOrchestration` actions applied next that start detached
+    /// orchestrations.
+    pub(crate) fn set_detached_starts(&mut self, starts: HashMap<i32, DetachedStart>) {
+        self.detached_starts = starts;
+    }
+
     pub fn pending_messages(&self) -> &[OrchestratorMessage] {
         &self.pending_messages
     }
@@ -383,6 +496,129 @@ impl OrchestrationRuntimeState {
         self.continued_as_new
     }
 
+    /// The uncommitted events of the executions that ended by continuing as new, which precede
+    /// the new events in history. Each ends with an `ExecutionCompleted` event.
+    pub fn ended_executions(&self) -> &[HistoryEvent] {
+        &self.ended_executions
+    }
+
+    pub fn custom_status(&self) -> Option<&str> {
+        self.custom_status.as_deref()
+    }
+
+    pub(crate) fn set_custom_status(&mut self, custom_status: Option<String>) {
+        self.custom_status = custom_status;
+    }
+
+    /// Returns the compacted history that replaces the committed events, if one was taken.
+    pub fn snapshot(&self) -> Option<&[HistoryEvent]> {
+        self.snapshot.as_deref()
+    }
+
+    /// The number of committed events after the most recent snapshot.
+    pub fn events_since_snapshot(&self) -> usize {
+        self.old_events
+            .iter()
+            .rev()
+            .take_while(|e| !matches!(e.event_type, Some(EventType::HistoryState(_))))
+            .count()
+    }
+
+    /// Compacts the committed history into a snapshot that ends with a `HistoryState` event.
+    ///
+    /// The snapshot keeps the state replay needs: the `ExecutionStarted` event, the results of
+    /// resolved tasks, the events that scheduled the tasks still pending, every `EventRaised`
+    /// event, whether or not the orchestrator received it yet, and suspensions. It drops the
+    /// events that scheduled resolved tasks, sent events, payloads orchestrators never read
+    /// and `OrchestratorStarted` events no other event followed. The `HistoryState` event holds
+    /// the custom status, and its event ID is the ID of the next task: tasks with lower IDs were
+    /// scheduled before the snapshot, even if their events were dropped.
+    ///
+    /// Replay runs the orchestrator over the snapshot, then picks up after the `HistoryState`
+    /// event. The results of resolved tasks are still kept, so orchestrations that run forever
+    /// should continue as new periodically.
+    pub(crate) fn take_snapshot(&mut self) {
+        let mut resolved = HashSet::new();
+        let mut next_task_id = 0;
+        for e in &self.old_events {
+            match &e.event_type {
+                Some(EventType::TaskCompleted(completed)) => {
+                    resolved.insert(completed.task_scheduled_id);
+                }
+                Some(EventType::TaskFailed(failed)) => {
+                    resolved.insert(failed.task_scheduled_id);
+                }
+                Some(EventType::SubOrchestrationInstanceCompleted(completed)) => {
+                    resolved.insert(completed.task_scheduled_id);
+                }
+                Some(EventType::SubOrchestrationInstanceFailed(failed)) => {
+                    resolved.insert(failed.task_scheduled_id);
+                }
+                Some(EventType::TimerFired(fired)) => {
+                    resolved.insert(fired.timer_id);
+                }
+                Some(EventType::HistoryState(_)) => next_task_id = next_task_id.max(e.event_id),
+                Some(
+                    EventType::TaskScheduled(_)
+                    | EventType::TimerCreated(_)
+                    | EventType::SubOrchestrationInstanceCreated(_)
+                    | EventType::EventSent(_),
+                ) => next_task_id = next_task_id.max(e.event_id + 1),
+                _ => {}
+            }
+        }
+
+        let mut snapshot: Vec<HistoryEvent> = Vec::with_capacity(self.old_events.len() + 1);
+        for e in &self.old_events {
+            let Some(e) = compact_event(e, &resolved) else {
+                continue;
+            };
+            if let Some(EventType::OrchestratorStarted(_)) =
+                snapshot.last().and_then(|last| last.event_type.as_ref())
+            {
+                if let Some(EventType::OrchestratorStarted(_)) = e.event_type {
+                    snapshot.pop();
+                }
+            }
+            snapshot.push(e);
+        }
+        if let Some(EventType::OrchestratorStarted(_)) =
+            snapshot.last().and_then(|last| last.event_type.as_ref())
+        {
+            snapshot.pop();
+        }
+
+        let start_event = self.start_event.as_ref();
+        let mut history_state = internal::new_history_state_event(OrchestrationState {
+            instance_id: self.instance_id.0.clone(),
+            name: start_event.map(|e| e.name.clone()).unwrap_or_default(),
+            version: start_event.and_then(|e| e.version.clone()),
+            orchestration_status: self.runtime_status() as i32,
+            created_timestamp: self.created_time.map(Timestamp::from),
+            last_updated_timestamp: self.last_updated_time.map(Timestamp::from),
+            custom_status: self.custom_status.clone(),
+            ..Default::default()
+        });
+        history_state.event_id = next_task_id;
+        snapshot.push(history_state);
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Moves the new events into the committed history once the backend has saved them.
+    pub(crate) fn commit(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.old_events = snapshot;
+        }
+        self.old_events.append(&mut self.new_events);
+        self.pending_tasks.clear();
+        self.pending_task_options.clear();
+        self.pending_timers.clear();
+        self.pending_messages.clear();
+        self.detached_starts.clear();
+        self.ended_executions.clear();
+        self.continued_as_new = false;
+    }
+
     #[allow(dead_code)] // TODO: Remove dead_code exception
     pub(crate) fn get_started_time(&self) -> SystemTime {
         if !self.old_events().is_empty() {
@@ -395,6 +631,57 @@ impl OrchestrationRuntimeState {
     }
 }
 
+/// The failure reported to the parent of a sub-orchestration that didn't complete successfully.
+fn sub_orchestration_failure_details(
+    completed_action: &CompleteOrchestrationAction,
+) -> TaskFailureDetails {
+    completed_action
+        .failure_details
+        .clone()
+        .unwrap_or_else(|| TaskFailureDetails {
+            error_type: "SubOrchestrationFailed".to_string(),
+            error_message: format!(
+                "sub-orchestration ended with status {}",
+                to_runtime_status_string(completed_action.orchestration_status())
+            ),
+            ..Default::default()
+        })
+}
+
+/// Returns `e` without the payloads replay doesn't need, or `None` if replay doesn't need it
+/// because it's a sent event or schedules one of the `resolved` tasks.
+fn compact_event(e: &HistoryEvent, resolved: &HashSet<i32>) -> Option<HistoryEvent> {
+    let mut e = e.clone();
+    match e.event_type.as_mut()? {
+        EventType::HistoryState(_) | EventType::EventSent(_) => return None,
+        EventType::TaskScheduled(_)
+        | EventType::TimerCreated(_)
+        | EventType::SubOrchestrationInstanceCreated(_)
+            if resolved.contains(&e.event_id) =>
+        {
+            return None
+        }
+        EventType::TaskScheduled(scheduled) => {
+            scheduled.input = None;
+            scheduled.parent_trace_context = None;
+        }
+        EventType::SubOrchestrationInstanceCreated(created) => {
+            created.input = None;
+            created.parent_trace_context = None;
+        }
+        _ => {}
+    }
+    Some(e)
+}
+
+fn event_time(event: &HistoryEvent) -> Result<SystemTime, Error> {
+    let timestamp = event
+        .timestamp
+        .clone()
+        .ok_or_else(|| Error::InvalidHistory("history event has no timestamp".to_string()))?;
+    SystemTime::try_from(timestamp).map_err(|e| Error::InvalidHistory(e.to_string()))
+}
+
 impl fmt::Display for OrchestrationRuntimeState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -405,3 +692,191 @@ impl fmt::Display for OrchestrationRuntimeState {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::{
+        new_complete_orchestration_action, new_create_sub_orchestration_action,
+        new_event_raised_event, new_event_sent_event, new_execution_started_event,
+        new_orchestrator_started_event, new_task_completed_event, new_task_scheduled_event,
+        new_timer_created_event, new_timer_fired_event,
+    };
+
+    #[test]
+    fn test_take_snapshot() {
+        let id = api::InstanceID("abc".to_string());
+        let fire_at = Timestamp::from(SystemTime::now());
+        let history = [
+            new_orchestrator_started_event(),
+            new_execution_started_event("greet", "abc", Some("1"), None, None, None),
+            new_task_scheduled_event(0, "say_hello", None, Some("large input"), None),
+            new_orchestrator_started_event(),
+            new_orchestrator_started_event(),
+            new_task_completed_event(0, Some("2")),
+            new_task_scheduled_event(1, "say_hello", None, Some("large input"), None),
+            new_event_sent_event(2, "other", "greeted", Some("large input")),
+            new_event_raised_event("go", Some("3")),
+            new_timer_created_event(3, &fire_at),
+        ];
+        let mut state = OrchestrationRuntimeState::new(&id, &history);
+        state.set_custom_status(Some("greeting".to_string()));
+        assert_eq!(state.events_since_snapshot(), 10);
+        state.take_snapshot();
+
+        // The resolved task is only kept as its result, the pending ones without their inputs
+        let snapshot = state.snapshot().unwrap().to_vec();
+        assert_eq!(snapshot.len(), 8);
+        assert_eq!(snapshot[..2], history[..2]);
+        assert_eq!(snapshot[2..4], history[4..6]);
+        match &snapshot[4].event_type {
+            Some(EventType::TaskScheduled(scheduled)) => {
+                assert_eq!(snapshot[4].event_id, 1);
+                assert_eq!(scheduled.input, None);
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+        assert_eq!(snapshot[5..7], history[8..10]);
+        match &snapshot[7].event_type {
+            Some(EventType::HistoryState(history_state)) => {
+                assert_eq!(snapshot[7].event_id, 4);
+                let orchestration_state = history_state.orchestration_state.as_ref().unwrap();
+                assert_eq!(orchestration_state.name, "greet");
+                assert_eq!(
+                    orchestration_state.custom_status.as_deref(),
+                    Some("greeting")
+                );
+                assert_eq!(
+                    orchestration_state.orchestration_status(),
+                    OrchestrationStatus::Running
+                );
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+
+        state
+            .add_event(&new_timer_fired_event(3, &fire_at), true)
+            .unwrap();
+        state.commit();
+        assert_eq!(state.snapshot(), None);
+        assert_eq!(state.old_events().len(), 9);
+        assert_eq!(state.events_since_snapshot(), 1);
+
+        // Taking another snapshot replaces the previous one, and keeps its next task ID
+        state.take_snapshot();
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 8);
+        assert!(!snapshot
+            .iter()
+            .any(|e| matches!(e.event_type, Some(EventType::TimerCreated(_)))));
+        let history_states: Vec<_> = snapshot
+            .iter()
+            .filter(|e| matches!(e.event_type, Some(EventType::HistoryState(_))))
+            .collect();
+        assert_eq!(history_states.len(), 1);
+        assert_eq!(history_states[0].event_id, 4);
+    }
+
+    #[test]
+    fn test_sub_orchestration_linkage() {
+        let parent_id = api::InstanceID("parent".to_string());
+        let mut parent = OrchestrationRuntimeState::new(
+            &parent_id,
+            &[new_execution_started_event(
+                "parent", "parent", None, None, None, None,
+            )],
+        );
+        parent
+            .apply_actions(&[new_create_sub_orchestration_action(3, "child", "", None)])
+            .unwrap();
+
+        match &parent.new_events()[0].event_type {
+            Some(EventType::SubOrchestrationInstanceCreated(created)) => {
+                assert_eq!(created.instance_id, "parent:0003");
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+        let message = &parent.pending_messages()[0];
+        assert_eq!(message.target_instance_id(), "parent:0003");
+        let start_event = message.history_event().unwrap().clone();
+        let parent_info = match &start_event.event_type {
+            Some(EventType::ExecutionStarted(started)) => started.parent_instance.clone().unwrap(),
+            e => panic!("unexpected event: {e:?}"),
+        };
+        assert_eq!(parent_info.task_scheduled_id, 3);
+        let parent_instance = parent_info.orchestration_instance.unwrap();
+        assert_eq!(parent_instance.instance_id, "parent");
+        assert_eq!(
+            parent_instance.execution_id.as_deref(),
+            parent.execution_id()
+        );
+
+        let details = TaskFailureDetails {
+            error_type: "Boom".to_string(),
+            ..Default::default()
+        };
+        let mut child = OrchestrationRuntimeState::new(
+            &api::InstanceID("parent:0003".to_string()),
+            &[start_event],
+        );
+        child
+            .apply_actions(&[new_complete_orchestration_action(
+                0,
+                OrchestrationStatus::Failed,
+                None,
+                &[],
+                Some(&details),
+            )])
+            .unwrap();
+        let message = &child.pending_messages()[0];
+        assert_eq!(message.target_instance_id(), "parent");
+        assert_eq!(message.target_execution_id(), parent.execution_id());
+        match &message.history_event().unwrap().event_type {
+            Some(EventType::SubOrchestrationInstanceFailed(failed)) => {
+                assert_eq!(failed.task_scheduled_id, 3);
+                assert_eq!(failed.failure_details.as_ref(), Some(&details));
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn test_detached_start() {
+        let mut state = OrchestrationRuntimeState::new(
+            &api::InstanceID("abc".to_string()),
+            &[new_execution_started_event(
+                "starter", "abc", None, None, None, None,
+            )],
+        );
+        let policy = OrchestrationIdReusePolicy {
+            action: crate::api::REUSE_ID_ACTION_IGNORE as i32,
+            ..Default::default()
+        };
+        state.set_detached_starts(HashMap::from([(
+            0,
+            DetachedStart {
+                reuse_policy: Some(policy.clone()),
+                ..Default::default()
+            },
+        )]));
+        state
+            .apply_actions(&[new_create_sub_orchestration_action(
+                0,
+                "worker",
+                "detached",
+                Some("42"),
+            )])
+            .unwrap();
+
+        let message = &state.pending_messages()[0];
+        assert_eq!(message.target_instance_id(), "detached");
+        assert_eq!(message.reuse_policy(), Some(&policy));
+        match &message.history_event().unwrap().event_type {
+            Some(EventType::ExecutionStarted(started)) => {
+                assert!(started.parent_instance.is_none());
+                assert_eq!(started.input.as_deref(), Some("42"));
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+    }
+}
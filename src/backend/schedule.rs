@@ -0,0 +1,469 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{required, InstanceID, OrchestrationIdReusePolicy};
+use crate::backend::BackendError;
+use crate::durabletask_pb::{CreateOrchestrationAction, OrchestrationStatus};
+use crate::payload::{JsonCodec, Payload, PayloadCodec};
+use crate::Error;
+
+/// How often a schedule ticks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleInterval {
+    /// A cron expression, either in the standard five-field form
+    /// (`min hour day month weekday`) or with leading seconds and trailing
+    /// years as accepted by the `cron` crate.
+    Cron(String),
+    /// A fixed interval measured from the schedule's start time.
+    Every(Duration),
+}
+
+impl ScheduleInterval {
+    /// Returns the first tick strictly after `after`, or `None` if the schedule
+    /// will never fire again.
+    pub fn next_after(&self, start_at: SystemTime, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            ScheduleInterval::Cron(expression) => {
+                let schedule = parse_cron(expression).ok()?;
+                let after: DateTime<Utc> = after.into();
+                schedule.after(&after).next().map(SystemTime::from)
+            }
+            ScheduleInterval::Every(interval) => {
+                if interval.is_zero() {
+                    return None;
+                }
+                if after < start_at {
+                    return Some(start_at);
+                }
+                let elapsed = after.duration_since(start_at).ok()?;
+                let ticks = elapsed.as_nanos() / interval.as_nanos() + 1;
+                let offset = interval.as_nanos().checked_mul(ticks)?;
+                let offset = Duration::new(
+                    u64::try_from(offset / 1_000_000_000).ok()?,
+                    (offset % 1_000_000_000) as u32,
+                );
+                start_at.checked_add(offset)
+            }
+        }
+    }
+}
+
+fn parse_cron(expression: &str) -> Result<cron::Schedule, cron::error::Error> {
+    // The cron crate expects a leading seconds field, accept the more common
+    // five-field form by pinning seconds to zero.
+    if expression.split_whitespace().count() == 5 {
+        cron::Schedule::from_str(&format!("0 {}", expression))
+    } else {
+        cron::Schedule::from_str(expression)
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleStatus {
+    #[default]
+    Active,
+    Paused,
+}
+
+/// A recurring orchestration schedule.
+///
+/// Every tick starts a new orchestration instance whose ID is derived from the
+/// schedule ID and the tick time, so a tick that is processed twice maps onto
+/// the same instance and is resolved by the schedule's reuse policy.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    pub orchestration_name: String,
+    pub input: Option<String>,
+    pub interval: ScheduleInterval,
+    pub status: ScheduleStatus,
+    pub start_at: SystemTime,
+    pub next_run_at: Option<SystemTime>,
+    pub last_run_at: Option<SystemTime>,
+    pub last_instance_id: Option<InstanceID>,
+    pub orchestration_id_reuse_policy: OrchestrationIdReusePolicy,
+}
+
+impl Schedule {
+    pub fn builder() -> ScheduleBuilder {
+        ScheduleBuilder::new()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status == ScheduleStatus::Paused
+    }
+
+    /// Returns true if the schedule is active and its next tick is at or before `now`.
+    pub fn is_due(&self, now: SystemTime) -> bool {
+        !self.is_paused() && self.next_run_at.is_some_and(|next| next <= now)
+    }
+
+    /// The deterministic instance ID of the orchestration started for the tick at `tick`. Ticks
+    /// that aren't on a whole second keep their fractional seconds, so no two ticks share an ID.
+    pub fn instance_id_for(&self, tick: SystemTime) -> InstanceID {
+        let tick: DateTime<Utc> = tick.into();
+        InstanceID(format!("{}-{}", self.id, tick.format("%Y%m%dT%H%M%S%.fZ")))
+    }
+
+    /// Moves the schedule past `now`, skipping any ticks that were missed.
+    pub(crate) fn advance(&mut self, now: SystemTime) {
+        self.next_run_at = self.interval.next_after(self.start_at, now);
+    }
+}
+
+/// The outcome of one call to
+/// [`TaskHubClient::process_due_schedules`](crate::backend::client::TaskHubClient::process_due_schedules).
+#[derive(Debug, Default)]
+pub struct ProcessedSchedules {
+    /// The instances started for due schedules.
+    pub started: Vec<InstanceID>,
+    /// The IDs of the schedules whose tick failed, with the error. They're retried by the
+    /// next call.
+    pub failed: Vec<(String, Error)>,
+}
+
+#[derive(Default)]
+pub struct ScheduleBuilder {
+    id: Option<String>,
+    orchestration_name: Option<String>,
+    input: Option<Payload>,
+    interval: Option<ScheduleInterval>,
+    start_at: Option<SystemTime>,
+    orchestration_id_reuse_policy: Option<OrchestrationIdReusePolicy>,
+}
+
+impl ScheduleBuilder {
+    pub fn new() -> Self {
+        ScheduleBuilder {
+            ..Default::default()
+        }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn orchestration_name(mut self, name: impl Into<String>) -> Self {
+        self.orchestration_name = Some(name.into());
+        self
+    }
+
+    pub fn input<T: Serialize>(mut self, input: &T) -> Self {
+        self.input = Some(Payload::from_value(input));
+        self
+    }
+
+    pub fn raw_input(mut self, input: String) -> Self {
+        self.input = Some(Payload::Raw(input));
+        self
+    }
+
+    pub fn cron(mut self, expression: impl Into<String>) -> Self {
+        self.interval = Some(ScheduleInterval::Cron(expression.into()));
+        self
+    }
+
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.interval = Some(ScheduleInterval::Every(interval));
+        self
+    }
+
+    pub fn start_at(mut self, time: SystemTime) -> Self {
+        self.start_at = Some(time);
+        self
+    }
+
+    pub fn orchestration_id_reuse_policy(mut self, policy: OrchestrationIdReusePolicy) -> Self {
+        self.orchestration_id_reuse_policy = Some(policy);
+        self
+    }
+
+    /// Builds the schedule, serializing the input as JSON.
+    pub fn build(self) -> Result<Schedule, Error> {
+        self.build_with(&JsonCodec)
+    }
+
+    pub(crate) fn build_with(self, codec: &dyn PayloadCodec) -> Result<Schedule, Error> {
+        let id = self.id.ok_or(required("id"))?;
+        let orchestration_name = self
+            .orchestration_name
+            .ok_or(required("orchestration_name"))?;
+        let interval = self.interval.ok_or(required("cron or interval"))?;
+        if let ScheduleInterval::Cron(expression) = &interval {
+            parse_cron(expression)
+                .map_err(|e| Error::InvalidArgument(format!("invalid cron expression: {}", e)))?;
+        }
+
+        let start_at = self.start_at.unwrap_or_else(SystemTime::now);
+        // A start time that lands exactly on a tick should fire on that tick.
+        let next_run_at = start_at
+            .checked_sub(Duration::from_secs(1))
+            .and_then(|before| interval.next_after(start_at, before));
+
+        let input = match self.input {
+            None => None,
+            Some(input) => input.encode(codec)?,
+        };
+
+        Ok(Schedule {
+            id,
+            orchestration_name,
+            input,
+            interval,
+            status: ScheduleStatus::Active,
+            start_at,
+            next_run_at,
+            last_run_at: None,
+            last_instance_id: None,
+            orchestration_id_reuse_policy: self
+                .orchestration_id_reuse_policy
+                .unwrap_or_else(default_schedule_reuse_policy),
+        })
+    }
+}
+
+/// Duplicate ticks are ignored regardless of the state of the existing instance.
+fn default_schedule_reuse_policy() -> OrchestrationIdReusePolicy {
+    OrchestrationIdReusePolicy {
+        operation_status: vec![
+            OrchestrationStatus::Running as i32,
+            OrchestrationStatus::Completed as i32,
+            OrchestrationStatus::ContinuedAsNew as i32,
+            OrchestrationStatus::Failed as i32,
+            OrchestrationStatus::Canceled as i32,
+            OrchestrationStatus::Terminated as i32,
+            OrchestrationStatus::Pending as i32,
+            OrchestrationStatus::Suspended as i32,
+        ],
+        action: CreateOrchestrationAction::Ignore as i32,
+    }
+}
+
+#[async_trait]
+pub trait ScheduleStore: Send + Sync {
+    /// Saves a new schedule, returning `false` if one with the same ID already exists.
+    async fn create_schedule(&self, schedule: &Schedule) -> Result<bool, BackendError>;
+    /// Replaces `current` with `schedule` if it wasn't changed or deleted since it was read,
+    /// returning whether it was replaced.
+    async fn update_schedule(
+        &self,
+        current: &Schedule,
+        schedule: &Schedule,
+    ) -> Result<bool, BackendError>;
+    async fn get_schedule(&self, id: &str) -> Result<Option<Schedule>, BackendError>;
+    async fn list_schedules(&self) -> Result<Vec<Schedule>, BackendError>;
+    async fn delete_schedule(&self, id: &str) -> Result<bool, BackendError>;
+}
+
+/// A non-durable [`ScheduleStore`] for local development and tests.
+#[derive(Default)]
+pub struct InMemoryScheduleStore {
+    schedules: Mutex<HashMap<String, Schedule>>,
+}
+
+#[async_trait]
+impl ScheduleStore for InMemoryScheduleStore {
+    async fn create_schedule(&self, schedule: &Schedule) -> Result<bool, BackendError> {
+        let mut schedules = self.schedules.lock().unwrap();
+        if schedules.contains_key(&schedule.id) {
+            return Ok(false);
+        }
+        schedules.insert(schedule.id.clone(), schedule.clone());
+        Ok(true)
+    }
+
+    async fn update_schedule(
+        &self,
+        current: &Schedule,
+        schedule: &Schedule,
+    ) -> Result<bool, BackendError> {
+        let mut schedules = self.schedules.lock().unwrap();
+        match schedules.get_mut(&schedule.id) {
+            Some(stored) if stored == current => {
+                *stored = schedule.clone();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_schedule(&self, id: &str) -> Result<Option<Schedule>, BackendError> {
+        let schedules = self.schedules.lock().unwrap();
+        Ok(schedules.get(id).cloned())
+    }
+
+    async fn list_schedules(&self) -> Result<Vec<Schedule>, BackendError> {
+        let schedules = self.schedules.lock().unwrap();
+        let mut list: Vec<Schedule> = schedules.values().cloned().collect();
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(list)
+    }
+
+    async fn delete_schedule(&self, id: &str) -> Result<bool, BackendError> {
+        let mut schedules = self.schedules.lock().unwrap();
+        Ok(schedules.remove(id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_interval_next_after() {
+        let interval = ScheduleInterval::Every(Duration::from_secs(60));
+        let start = at(1_000);
+
+        assert_eq!(interval.next_after(start, at(10)), Some(start));
+        assert_eq!(interval.next_after(start, start), Some(at(1_060)));
+        assert_eq!(interval.next_after(start, at(1_059)), Some(at(1_060)));
+        assert_eq!(interval.next_after(start, at(1_130)), Some(at(1_180)));
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        // 2024-01-01T00:00:00Z
+        let midnight = at(1_704_067_200);
+        let five_field = ScheduleInterval::Cron("30 2 * * *".to_string());
+        let six_field = ScheduleInterval::Cron("0 30 2 * * *".to_string());
+
+        let expected = Some(midnight + Duration::from_secs(2 * 3600 + 30 * 60));
+        assert_eq!(five_field.next_after(midnight, midnight), expected);
+        assert_eq!(six_field.next_after(midnight, midnight), expected);
+    }
+
+    #[test]
+    fn test_schedule_builder() {
+        let start = at(1_704_067_200);
+        let schedule = Schedule::builder()
+            .id("nightly")
+            .orchestration_name("reconcile")
+            .raw_input("{}".to_string())
+            .cron("0 0 * * *")
+            .start_at(start)
+            .build()
+            .unwrap();
+
+        assert_eq!(schedule.status, ScheduleStatus::Active);
+        assert_eq!(schedule.next_run_at, Some(start));
+        assert_eq!(
+            schedule.orchestration_id_reuse_policy.action,
+            CreateOrchestrationAction::Ignore as i32
+        );
+        assert!(schedule.is_due(start));
+        assert!(!schedule.is_due(start - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_schedule_builder_validation() {
+        let invalid_cron = Schedule::builder()
+            .id("nightly")
+            .orchestration_name("reconcile")
+            .cron("not a cron")
+            .build();
+        assert!(matches!(invalid_cron, Err(Error::InvalidArgument(_))));
+
+        let missing_interval = Schedule::builder()
+            .id("nightly")
+            .orchestration_name("reconcile")
+            .build();
+        match missing_interval {
+            Err(Error::InvalidArgument(message)) => {
+                assert_eq!(message, "cron or interval is required")
+            }
+            _ => panic!("expected an invalid argument error"),
+        }
+    }
+
+    #[test]
+    fn test_schedule_instance_id_is_deterministic() {
+        let schedule = Schedule::builder()
+            .id("nightly")
+            .orchestration_name("reconcile")
+            .every(Duration::from_secs(3600))
+            .start_at(at(1_704_067_200))
+            .build()
+            .unwrap();
+
+        let tick = at(1_704_070_800);
+        assert_eq!(
+            schedule.instance_id_for(tick),
+            InstanceID("nightly-20240101T010000Z".to_string())
+        );
+        assert_eq!(
+            schedule.instance_id_for(tick),
+            schedule.instance_id_for(tick)
+        );
+    }
+
+    #[test]
+    fn test_schedule_sub_second_ticks_have_distinct_instance_ids() {
+        let mut schedule = Schedule::builder()
+            .id("fast")
+            .orchestration_name("poll")
+            .every(Duration::from_millis(250))
+            .start_at(at(1_704_067_200))
+            .build()
+            .unwrap();
+
+        let first = schedule.next_run_at.unwrap();
+        schedule.advance(first);
+        let second = schedule.next_run_at.unwrap();
+        assert_eq!(second, first + Duration::from_millis(250));
+        assert_eq!(
+            schedule.instance_id_for(first),
+            InstanceID("fast-20240101T000000Z".to_string())
+        );
+        assert_eq!(
+            schedule.instance_id_for(second),
+            InstanceID("fast-20240101T000000.250Z".to_string())
+        );
+    }
+
+    #[test]
+    fn test_schedule_advance_skips_missed_ticks() {
+        let mut schedule = Schedule::builder()
+            .id("frequent")
+            .orchestration_name("poll")
+            .every(Duration::from_secs(60))
+            .start_at(at(0))
+            .build()
+            .unwrap();
+
+        schedule.advance(at(605));
+        assert_eq!(schedule.next_run_at, Some(at(660)));
+
+        // More ticks than fit in a u32 have passed since the start
+        schedule.interval = ScheduleInterval::Every(Duration::from_secs(1));
+        schedule.advance(at(5_000_000_000));
+        assert_eq!(schedule.next_run_at, Some(at(5_000_000_001)));
+    }
+}
@@ -0,0 +1,886 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+use crate::api::{InstanceID, OrchestrationMetadata};
+use crate::backend::commit::{HistoryUpdate, OrchestrationCommit, Outbox, OutboxMessage};
+use crate::backend::deadletter::{DeadLetter, WorkItemKind};
+use crate::backend::lock::{
+    check_work_item_lock, is_lock_available, new_lock_token, LockOptions, WorkItemLock,
+};
+use crate::backend::routing::{Priority, TaskRoute, WorkItemFilter};
+use crate::backend::runtimestate::OrchestrationRuntimeState;
+use crate::backend::throttle::TaskHubLimits;
+use crate::backend::workitem::{ActivityWorkItem, OrchestrationWorkItem};
+use crate::backend::{
+    current_execution, evaluate_orchestration_id_reuse_policy, filter_history_by_execution,
+    replaced_execution_events, resolve_orchestration_id_reuse_policy,
+    with_orchestration_id_reuse_policy, Backend, BackendError, CreateInstanceDecision,
+    OrchestrationIdReusePolicyOptions,
+};
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::{
+    HistoryEvent, OrchestrationStatus, ParentInstanceInfo, TaskFailureDetails,
+};
+use crate::task::ActivityOptions;
+
+/// An in-memory [`Backend`] for unit tests. It keeps the history, pending events and lease of
+/// each instance and a queue of activities, and records the work item operations tests assert
+/// on.
+#[derive(Default)]
+pub(crate) struct TestBackend {
+    pub instances: Mutex<HashMap<String, TestInstance>>,
+    /// The names of the work item operations called, in order.
+    pub calls: Mutex<Vec<String>>,
+    /// Instance IDs whose creation fails with an error.
+    pub failing_instances: Mutex<HashSet<String>>,
+    /// Instance IDs whose orchestration work items fail to complete with an error.
+    pub failing_completions: Mutex<HashSet<String>>,
+    /// Activities that weren't completed yet, in the order they were enqueued.
+    pub activities: Mutex<Vec<TestActivity>>,
+    dead_letters: Mutex<Vec<TestDeadLetter>>,
+    outbox: Option<TestOutbox>,
+    lock_options: LockOptions,
+    next_sequence_number: AtomicI64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TestInstance {
+    pub history: Vec<HistoryEvent>,
+    pub inbox: Vec<HistoryEvent>,
+    /// The execution each inbox event is addressed to.
+    inbox_targets: Vec<Option<String>>,
+    /// `TimerFired` events that are moved to the inbox once they fire.
+    timers: Vec<HistoryEvent>,
+    lock: Option<TestLock>,
+    /// When the inbox may be fetched again after it was abandoned or deferred.
+    visible_at: Option<SystemTime>,
+    retry_count: i32,
+    /// Why a work item of the instance was dead-lettered, which fails the instance and stops
+    /// it from being fetched until the work item is requeued.
+    dead_lettered: Option<TaskFailureDetails>,
+}
+
+#[derive(Clone, Debug)]
+struct TestLock {
+    token: String,
+    expires_at: SystemTime,
+}
+
+/// The outbox of a backend created with [`TestBackend::with_outbox`].
+#[derive(Default)]
+pub(crate) struct TestOutbox {
+    pub messages: Mutex<Vec<OutboxMessage>>,
+    /// The IDs of the messages deleted once they were delivered, in order.
+    pub deleted: Mutex<Vec<String>>,
+}
+
+/// A dead-lettered work item, with what's needed to requeue it: the inbox targets of an
+/// orchestration's events, or the activity.
+struct TestDeadLetter {
+    dead_letter: DeadLetter,
+    inbox_targets: Vec<Option<String>>,
+    activity: Option<TestActivity>,
+}
+
+/// A queued activity. Its work item holds the lease of the fetch that locked it, if any.
+pub(crate) struct TestActivity {
+    pub route: TaskRoute,
+    pub work_item: ActivityWorkItem,
+    visible_at: Option<SystemTime>,
+    /// Set when the instance was terminated while the activity was locked. It's dropped once
+    /// it's completed or its lease expires.
+    cancelled: bool,
+}
+
+impl TestInstance {
+    fn push(&mut self, event: &HistoryEvent, execution_id: Option<&str>) {
+        self.inbox.push(event.clone());
+        self.inbox_targets.push(execution_id.map(str::to_string));
+    }
+
+    /// Loads the current execution, which is a new one while its `ExecutionStarted` event is
+    /// still in the inbox.
+    fn state(&self, instance_id: &str) -> OrchestrationRuntimeState {
+        let starting = matches!(
+            self.inbox.first().and_then(|e| e.event_type.as_ref()),
+            Some(EventType::ExecutionStarted(_))
+        );
+        let history = if starting {
+            &[]
+        } else {
+            current_execution(&self.history)
+        };
+        OrchestrationRuntimeState::new(&InstanceID(instance_id.to_string()), history)
+    }
+
+    fn is_fetchable(&self, now: SystemTime) -> bool {
+        !self.inbox.is_empty()
+            && self.dead_lettered.is_none()
+            && is_lock_available(self.lock.as_ref().map(|lock| lock.expires_at), now)
+            && self.visible_at.is_none_or(|at| at <= now)
+    }
+
+    fn fire_timers(&mut self, now: SystemTime) {
+        let (fired, pending) = std::mem::take(&mut self.timers)
+            .into_iter()
+            .partition(|e| fire_at(e) <= now);
+        self.timers = pending;
+        for e in fired {
+            self.push(&e, None);
+        }
+    }
+
+    fn check_lock(&self, lock_token: &str, now: SystemTime) -> Result<(), BackendError> {
+        let lock = self.lock.as_ref();
+        check_work_item_lock(
+            lock.map(|lock| lock.token.as_str()),
+            lock.map(|lock| lock.expires_at),
+            lock_token,
+            now,
+        )
+    }
+
+    /// Releases the lock of the fetch with `lock_token`, keeping the inbox for `delay`.
+    fn release(&mut self, lock_token: &str, delay: Duration) -> Result<(), BackendError> {
+        let now = SystemTime::now();
+        self.check_lock(lock_token, now)?;
+        self.lock = None;
+        self.visible_at = Some(now + delay);
+        Ok(())
+    }
+}
+
+impl TestActivity {
+    fn is_fetchable(&self, now: SystemTime) -> bool {
+        is_lock_available(self.work_item.lock_expires_at, now)
+            && self.visible_at.is_none_or(|at| at <= now)
+    }
+
+    fn check_lock(&self, lock_token: &str, now: SystemTime) -> Result<(), BackendError> {
+        check_work_item_lock(
+            Some(&self.work_item.lock_token),
+            self.work_item.lock_expires_at,
+            lock_token,
+            now,
+        )?;
+        if self.cancelled {
+            return Err(BackendError::WorkItemCancelled);
+        }
+        Ok(())
+    }
+
+    /// Releases the lock of the fetch with `lock_token`, keeping the activity for `delay`.
+    fn release(&mut self, lock_token: &str, delay: Duration) -> Result<(), BackendError> {
+        let now = SystemTime::now();
+        self.check_lock(lock_token, now)?;
+        self.work_item.lock_expires_at = None;
+        self.visible_at = Some(now + delay);
+        Ok(())
+    }
+}
+
+impl TestBackend {
+    /// Leases work items for the durations of `options` instead of the defaults.
+    pub(crate) fn with_lock_options(mut self, options: LockOptions) -> Self {
+        self.lock_options = options;
+        self
+    }
+
+    /// Saves the messages of completed work items in an outbox for workers to relay, instead
+    /// of delivering them.
+    pub(crate) fn with_outbox(mut self) -> Self {
+        self.outbox = Some(TestOutbox::default());
+        self
+    }
+
+    pub(crate) fn test_outbox(&self) -> Option<&TestOutbox> {
+        self.outbox.as_ref()
+    }
+
+    pub(crate) fn state(&self, instance_id: &str) -> Option<OrchestrationRuntimeState> {
+        let instances = self.instances.lock().unwrap();
+        Some(instances.get(instance_id)?.state(instance_id))
+    }
+
+    pub(crate) fn enqueue_activity(
+        &self,
+        instance_id: &str,
+        event: HistoryEvent,
+        route: TaskRoute,
+    ) {
+        self.enqueue(instance_id, None, event, ActivityOptions::new(), route);
+    }
+
+    fn enqueue(
+        &self,
+        instance_id: &str,
+        execution_id: Option<String>,
+        event: HistoryEvent,
+        options: ActivityOptions,
+        route: TaskRoute,
+    ) {
+        let wi = ActivityWorkItem {
+            sequence_number: self.next_sequence_number.fetch_add(1, Ordering::SeqCst),
+            instance_id: InstanceID(instance_id.to_string()),
+            execution_id,
+            new_event: event,
+            result: None,
+            locked_by: String::new(),
+            lock_token: String::new(),
+            lock_expires_at: None,
+            retry_count: 0,
+            dispatch_count: 0,
+            options,
+            heartbeat_details: None,
+            properties: HashMap::new(),
+        };
+        self.activities.lock().unwrap().push(TestActivity {
+            route,
+            work_item: wi,
+            visible_at: None,
+            cancelled: false,
+        });
+    }
+
+    fn record(&self, call: &str) {
+        self.calls.lock().unwrap().push(call.to_string());
+    }
+
+    /// Applies `f` to the queued activity `sequence_number`.
+    fn with_activity<T>(
+        &self,
+        sequence_number: i64,
+        f: impl FnOnce(&mut TestActivity) -> Result<T, BackendError>,
+    ) -> Result<T, BackendError> {
+        let mut activities = self.activities.lock().unwrap();
+        let activity = activities
+            .iter_mut()
+            .find(|a| a.work_item.sequence_number == sequence_number)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        f(activity)
+    }
+
+    /// Removes the activity locked by `wi`, if its lease is still held.
+    fn take_activity(&self, wi: &ActivityWorkItem) -> Result<TestActivity, BackendError> {
+        let mut activities = self.activities.lock().unwrap();
+        let i = activities
+            .iter()
+            .position(|a| a.work_item.sequence_number == wi.sequence_number)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        match activities[i].check_lock(&wi.lock_token, SystemTime::now()) {
+            Ok(()) => Ok(activities.remove(i)),
+            Err(BackendError::WorkItemCancelled) => {
+                activities.remove(i);
+                Err(BackendError::WorkItemCancelled)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Creates the instance started by `event`, applying its reuse policy to an existing one.
+fn create_instance(
+    instances: &mut HashMap<String, TestInstance>,
+    activities: &Mutex<Vec<TestActivity>>,
+    event: &HistoryEvent,
+    options: &[OrchestrationIdReusePolicyOptions],
+) -> Result<(), BackendError> {
+    let instance_id = started_instance_id(event)?;
+    let policy = resolve_orchestration_id_reuse_policy(options)?;
+    let existing = instances
+        .get(&instance_id)
+        .map(|instance| instance.state(&instance_id));
+    let decision = evaluate_orchestration_id_reuse_policy(
+        existing
+            .as_ref()
+            .map(OrchestrationRuntimeState::runtime_status),
+        &policy,
+    )?;
+    let instance = instances.entry(instance_id.clone()).or_default();
+    if decision == CreateInstanceDecision::Replace {
+        let state = existing.expect("only existing instances are replaced");
+        instance.history.extend(replaced_execution_events(&state));
+        // A worker still processing the old execution loses its lock
+        *instance = TestInstance {
+            history: std::mem::take(&mut instance.history),
+            ..Default::default()
+        };
+        activities
+            .lock()
+            .unwrap()
+            .retain(|a| a.work_item.instance_id.0 != instance_id);
+    }
+    instance.push(event, None);
+    Ok(())
+}
+
+fn started_instance_id(event: &HistoryEvent) -> Result<String, BackendError> {
+    let Some(EventType::ExecutionStarted(started)) = &event.event_type else {
+        return Err(BackendError::Other(
+            "expected an ExecutionStarted event".into(),
+        ));
+    };
+    Ok(started
+        .orchestration_instance
+        .as_ref()
+        .map(|instance| instance.instance_id.clone())
+        .unwrap_or_default())
+}
+
+fn fire_at(e: &HistoryEvent) -> SystemTime {
+    match &e.event_type {
+        Some(EventType::TimerFired(fired)) => fired
+            .fire_at
+            .clone()
+            .and_then(|fire_at| SystemTime::try_from(fire_at).ok())
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+        _ => SystemTime::UNIX_EPOCH,
+    }
+}
+
+fn activity_name(wi: &ActivityWorkItem) -> &str {
+    match &wi.new_event.event_type {
+        Some(EventType::TaskScheduled(scheduled)) => &scheduled.name,
+        _ => "",
+    }
+}
+
+/// A copy of the queued `wi` to hand to a worker.
+fn copy_activity(wi: &ActivityWorkItem) -> ActivityWorkItem {
+    ActivityWorkItem {
+        sequence_number: wi.sequence_number,
+        instance_id: wi.instance_id.clone(),
+        execution_id: wi.execution_id.clone(),
+        new_event: wi.new_event.clone(),
+        result: None,
+        locked_by: wi.locked_by.clone(),
+        lock_token: wi.lock_token.clone(),
+        lock_expires_at: wi.lock_expires_at,
+        retry_count: wi.retry_count,
+        dispatch_count: wi.dispatch_count,
+        options: wi.options.clone(),
+        heartbeat_details: wi.heartbeat_details.clone(),
+        properties: HashMap::new(),
+    }
+}
+
+#[async_trait]
+impl Backend for TestBackend {
+    async fn create_task_hub(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn delete_task_hub(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn start(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn create_orchestration_instance(
+        &self,
+        event: &HistoryEvent,
+        _priority: Priority,
+        options: Vec<OrchestrationIdReusePolicyOptions>,
+    ) -> Result<(), BackendError> {
+        let instance_id = started_instance_id(event)?;
+        if self
+            .failing_instances
+            .lock()
+            .unwrap()
+            .contains(&instance_id)
+        {
+            return Err(BackendError::Other("instance can't be created".into()));
+        }
+        create_instance(
+            &mut self.instances.lock().unwrap(),
+            &self.activities,
+            event,
+            &options,
+        )
+    }
+
+    async fn add_new_orchestration_event(
+        &self,
+        instance_id: &str,
+        event: &HistoryEvent,
+    ) -> Result<(), BackendError> {
+        self.add_orchestration_message(instance_id, event, None)
+            .await
+    }
+
+    async fn add_orchestration_message(
+        &self,
+        instance_id: &str,
+        event: &HistoryEvent,
+        execution_id: Option<&str>,
+    ) -> Result<(), BackendError> {
+        let mut instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get_mut(instance_id)
+            .ok_or_else(|| BackendError::Other("no such instance".into()))?;
+        instance.push(event, execution_id);
+        Ok(())
+    }
+
+    async fn get_orchestration_work_item(&self) -> Result<OrchestrationWorkItem, BackendError> {
+        let now = SystemTime::now();
+        let mut instances = self.instances.lock().unwrap();
+        for instance in instances.values_mut() {
+            instance.fire_timers(now);
+        }
+        let (instance_id, instance) = instances
+            .iter_mut()
+            .filter(|(_, instance)| instance.is_fetchable(now))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .ok_or(BackendError::NoWorkItems)?;
+        let lock = TestLock {
+            token: new_lock_token(),
+            expires_at: self.lock_options.orchestration_lock_expiry(now),
+        };
+        let wi = OrchestrationWorkItem {
+            instance_id: InstanceID(instance_id.clone()),
+            new_events: instance.inbox.clone(),
+            target_execution_ids: instance.inbox_targets.clone(),
+            lock_token: lock.token.clone(),
+            lock_expires_at: Some(lock.expires_at),
+            retry_count: instance.retry_count,
+            ..Default::default()
+        };
+        instance.lock = Some(lock);
+        Ok(wi)
+    }
+
+    async fn get_orchestration_runtime_state(
+        &self,
+        work_item: &OrchestrationWorkItem,
+    ) -> Result<OrchestrationRuntimeState, BackendError> {
+        Ok(self
+            .state(&work_item.instance_id.0)
+            .unwrap_or_else(|| OrchestrationRuntimeState::new(&work_item.instance_id, &[])))
+    }
+
+    async fn get_orchestration_metadata(
+        &self,
+        instance_id: &str,
+    ) -> Result<OrchestrationMetadata, BackendError> {
+        let instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get(instance_id)
+            .ok_or_else(|| BackendError::Other("no such instance".into()))?;
+        let state = instance.state(instance_id);
+        Ok(OrchestrationMetadata {
+            instance_id: InstanceID(instance_id.to_string()),
+            name: state.name().unwrap_or_default().to_string(),
+            runtime_status: match instance.dead_lettered {
+                Some(_) => OrchestrationStatus::Failed,
+                None => state.runtime_status(),
+            },
+            failure_details: instance.dead_lettered.clone(),
+            ..Default::default()
+        })
+    }
+
+    async fn get_orchestration_parent(
+        &self,
+        instance_id: &str,
+    ) -> Result<Option<ParentInstanceInfo>, BackendError> {
+        let instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get(instance_id)
+            .ok_or_else(|| BackendError::Other("no such instance".into()))?;
+        Ok(instance
+            .history
+            .iter()
+            .chain(&instance.inbox)
+            .find_map(|e| match &e.event_type {
+                Some(EventType::ExecutionStarted(started)) => Some(started.parent_instance.clone()),
+                _ => None,
+            })
+            .flatten())
+    }
+
+    async fn get_orchestration_history(
+        &self,
+        instance_id: &str,
+        execution_id: Option<&str>,
+    ) -> Result<Vec<HistoryEvent>, BackendError> {
+        let instances = self.instances.lock().unwrap();
+        let Some(instance) = instances.get(instance_id) else {
+            return Ok(vec![]);
+        };
+        Ok(match execution_id {
+            Some(execution_id) => filter_history_by_execution(&instance.history, execution_id),
+            None => instance.history.clone(),
+        })
+    }
+
+    async fn complete_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        commit: &OrchestrationCommit,
+    ) -> Result<(), BackendError> {
+        self.record("complete_orchestration_work_item");
+        let mut instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get_mut(&work_item.instance_id.0)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        instance.check_lock(&work_item.lock_token, SystemTime::now())?;
+        if self
+            .failing_completions
+            .lock()
+            .unwrap()
+            .contains(&work_item.instance_id.0)
+        {
+            return Err(BackendError::Other("work item can't be completed".into()));
+        }
+        match &commit.history {
+            HistoryUpdate::Append(events) => instance.history.extend_from_slice(events),
+            HistoryUpdate::Replace(events) => {
+                let ended = instance.history.len() - current_execution(&instance.history).len();
+                instance.history.truncate(ended);
+                instance.history.extend_from_slice(events);
+            }
+        }
+        // Events that arrived while the work item was processed stay in the inbox
+        let processed = work_item.new_events.len().min(instance.inbox.len());
+        instance.inbox.drain(..processed);
+        instance.inbox_targets.drain(..processed);
+        instance.timers.extend_from_slice(&commit.timers);
+        instance.lock = None;
+        instance.retry_count = 0;
+
+        if let Some(outbox) = &self.outbox {
+            let messages = commit.outbox_messages();
+            outbox.messages.lock().unwrap().extend(messages);
+        } else {
+            for route in &commit.messages {
+                let messages = route
+                    .events
+                    .iter()
+                    .zip(&route.target_execution_ids)
+                    .zip(&route.reuse_policies);
+                for ((event, execution_id), reuse_policy) in messages {
+                    if matches!(event.event_type, Some(EventType::ExecutionStarted(_))) {
+                        // Starts rejected by the reuse policy are dropped
+                        let policy = with_orchestration_id_reuse_policy(reuse_policy.clone());
+                        let _ = create_instance(&mut instances, &self.activities, event, &[policy]);
+                    } else if let Some(target) = instances.get_mut(&route.instance_id) {
+                        target.push(event, execution_id.as_deref());
+                    }
+                }
+            }
+        }
+        drop(instances);
+
+        if commit.cancels_activities() {
+            let now = SystemTime::now();
+            let mut activities = self.activities.lock().unwrap();
+            activities.retain(|a| {
+                a.work_item.instance_id != work_item.instance_id
+                    || !is_lock_available(a.work_item.lock_expires_at, now)
+            });
+            for a in activities.iter_mut() {
+                if a.work_item.instance_id == work_item.instance_id {
+                    a.cancelled = true;
+                }
+            }
+        }
+        for e in &commit.activities {
+            self.enqueue(
+                &work_item.instance_id.0,
+                commit.execution_id.clone(),
+                e.clone(),
+                commit
+                    .activity_options
+                    .get(&e.event_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                commit
+                    .activity_routes
+                    .get(&e.event_id)
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn abandon_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.record("abandon_orchestration_work_item");
+        let mut instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get_mut(&work_item.instance_id.0)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        instance.release(&work_item.lock_token, delay)?;
+        instance.retry_count += 1;
+        Ok(())
+    }
+
+    async fn defer_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.record("defer_orchestration_work_item");
+        let mut instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get_mut(&work_item.instance_id.0)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        instance.release(&work_item.lock_token, delay)
+    }
+
+    async fn get_activity_work_item(
+        &self,
+        filter: &WorkItemFilter,
+        limits: &TaskHubLimits,
+    ) -> Result<ActivityWorkItem, BackendError> {
+        let now = SystemTime::now();
+        let mut activities = self.activities.lock().unwrap();
+        activities.retain(|a| !a.cancelled || !is_lock_available(a.work_item.lock_expires_at, now));
+        let mut running: HashMap<&str, usize> = HashMap::new();
+        for a in activities.iter().filter(|a| !a.is_fetchable(now)) {
+            *running.entry(activity_name(&a.work_item)).or_default() += 1;
+        }
+        let position = activities
+            .iter()
+            .position(|a| {
+                let name = activity_name(&a.work_item);
+                let count = running.get(name).copied().unwrap_or_default();
+                a.is_fetchable(now)
+                    && filter.matches(name, &a.route)
+                    && limits.is_activity_available(name, count)
+            })
+            .ok_or(BackendError::NoWorkItems)?;
+        let stored = &mut activities[position].work_item;
+        stored.lock_token = new_lock_token();
+        stored.lock_expires_at = Some(self.lock_options.activity_lock_expiry(now));
+        let wi = copy_activity(stored);
+        stored.dispatch_count += 1;
+        Ok(wi)
+    }
+
+    async fn complete_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+    ) -> Result<(), BackendError> {
+        self.record("complete_activity_work_item");
+        self.take_activity(work_item)?;
+        if let Some(result) = &work_item.result {
+            let mut instances = self.instances.lock().unwrap();
+            if let Some(instance) = instances.get_mut(&work_item.instance_id.0) {
+                instance.push(result, work_item.execution_id.as_deref());
+            }
+        }
+        Ok(())
+    }
+
+    async fn defer_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.record("defer_activity_work_item");
+        self.with_activity(work_item.sequence_number, |activity| {
+            activity.release(&work_item.lock_token, delay)?;
+            activity.work_item.dispatch_count -= 1;
+            Ok(())
+        })
+    }
+
+    async fn abandon_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError> {
+        self.record("abandon_activity_work_item");
+        self.with_activity(work_item.sequence_number, |activity| {
+            activity.release(&work_item.lock_token, delay)?;
+            activity.work_item.retry_count += 1;
+            Ok(())
+        })
+    }
+
+    async fn purge_orchestration_state(
+        &self,
+        instance_id: &InstanceID,
+    ) -> Result<(), BackendError> {
+        self.instances.lock().unwrap().remove(&instance_id.0);
+        self.activities
+            .lock()
+            .unwrap()
+            .retain(|a| a.work_item.instance_id != *instance_id);
+        Ok(())
+    }
+
+    async fn renew_work_item_lock(&self, lock: &WorkItemLock) -> Result<SystemTime, BackendError> {
+        let now = SystemTime::now();
+        match lock.sequence_number {
+            Some(sequence_number) => self.with_activity(sequence_number, |activity| {
+                activity.check_lock(&lock.lock_token, now)?;
+                let expires_at = self.lock_options.activity_lock_expiry(now);
+                activity.work_item.lock_expires_at = Some(expires_at);
+                Ok(expires_at)
+            }),
+            None => {
+                let mut instances = self.instances.lock().unwrap();
+                let instance = instances
+                    .get_mut(&lock.instance_id.0)
+                    .ok_or(BackendError::WorkItemLockLost)?;
+                instance.check_lock(&lock.lock_token, now)?;
+                let expires_at = self.lock_options.orchestration_lock_expiry(now);
+                instance.lock = Some(TestLock {
+                    token: lock.lock_token.clone(),
+                    expires_at,
+                });
+                Ok(expires_at)
+            }
+        }
+    }
+
+    async fn record_activity_heartbeat(
+        &self,
+        lock: &WorkItemLock,
+        details: Option<&str>,
+    ) -> Result<SystemTime, BackendError> {
+        let expires_at = self.renew_work_item_lock(lock).await?;
+        if let Some(sequence_number) = lock.sequence_number {
+            self.with_activity(sequence_number, |activity| {
+                activity.work_item.heartbeat_details = details.map(str::to_string);
+                Ok(())
+            })?;
+        }
+        Ok(expires_at)
+    }
+
+    async fn dead_letter_orchestration_work_item(
+        &self,
+        work_item: &OrchestrationWorkItem,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), BackendError> {
+        self.record("dead_letter_orchestration_work_item");
+        let mut instances = self.instances.lock().unwrap();
+        let instance = instances
+            .get_mut(&work_item.instance_id.0)
+            .ok_or(BackendError::WorkItemLockLost)?;
+        instance.check_lock(&work_item.lock_token, SystemTime::now())?;
+        let processed = work_item.new_events.len().min(instance.inbox.len());
+        instance.inbox.drain(..processed);
+        let inbox_targets = instance.inbox_targets.drain(..processed).collect();
+        instance.lock = None;
+        instance.retry_count = 0;
+        instance.dead_lettered = Some(dead_letter.failure_details.clone());
+        self.dead_letters.lock().unwrap().push(TestDeadLetter {
+            dead_letter: dead_letter.clone(),
+            inbox_targets,
+            activity: None,
+        });
+        Ok(())
+    }
+
+    async fn dead_letter_activity_work_item(
+        &self,
+        work_item: &ActivityWorkItem,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), BackendError> {
+        self.record("dead_letter_activity_work_item");
+        let activity = self.take_activity(work_item)?;
+        let mut instances = self.instances.lock().unwrap();
+        if let Some(instance) = instances.get_mut(&work_item.instance_id.0) {
+            instance.dead_lettered = Some(dead_letter.failure_details.clone());
+        }
+        self.dead_letters.lock().unwrap().push(TestDeadLetter {
+            dead_letter: dead_letter.clone(),
+            inbox_targets: Vec::new(),
+            activity: Some(activity),
+        });
+        Ok(())
+    }
+
+    fn outbox(&self) -> Option<&dyn Outbox> {
+        self.outbox.as_ref().map(|outbox| outbox as &dyn Outbox)
+    }
+
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, BackendError> {
+        let dead_letters = self.dead_letters.lock().unwrap();
+        Ok(dead_letters.iter().map(|d| d.dead_letter.clone()).collect())
+    }
+
+    async fn get_dead_letter(&self, id: &str) -> Result<Option<DeadLetter>, BackendError> {
+        let dead_letters = self.dead_letters.lock().unwrap();
+        Ok(dead_letters
+            .iter()
+            .find(|d| d.dead_letter.id == id)
+            .map(|d| d.dead_letter.clone()))
+    }
+
+    async fn requeue_dead_letter(&self, id: &str) -> Result<bool, BackendError> {
+        let mut instances = self.instances.lock().unwrap();
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        let Some(i) = dead_letters.iter().position(|d| d.dead_letter.id == id) else {
+            return Ok(false);
+        };
+        let TestDeadLetter {
+            dead_letter,
+            inbox_targets,
+            activity,
+        } = dead_letters.remove(i);
+        if let Some(mut activity) = activity {
+            activity.work_item.retry_count = 0;
+            activity.work_item.lock_expires_at = None;
+            activity.visible_at = None;
+            self.activities.lock().unwrap().push(activity);
+        }
+        if let Some(instance) = instances.get_mut(&dead_letter.instance_id.0) {
+            if dead_letter.kind == WorkItemKind::Orchestration {
+                // The events were received before the ones still in the inbox
+                instance.inbox.splice(..0, dead_letter.events);
+                instance.inbox_targets.splice(..0, inbox_targets);
+            }
+            instance.dead_lettered = None;
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Outbox for TestOutbox {
+    async fn get_outbox_messages(&self, max: usize) -> Result<Vec<OutboxMessage>, BackendError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages.iter().take(max).cloned().collect())
+    }
+
+    async fn delete_outbox_message(&self, id: &str) -> Result<(), BackendError> {
+        self.messages.lock().unwrap().retain(|m| m.id != id);
+        self.deleted.lock().unwrap().push(id.to_string());
+        Ok(())
+    }
+}
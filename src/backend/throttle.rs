@@ -0,0 +1,241 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits how often a worker runs an activity.
+///
+/// Activities over their limit are deferred: their work items are released without counting
+/// as a failed attempt, and fetched again once the activity is likely to have capacity.
+#[derive(Clone, Debug, Default)]
+pub struct ActivityLimit {
+    max_concurrency: Option<usize>,
+    rate: Option<(u32, Duration)>,
+}
+
+impl ActivityLimit {
+    /// Sets the most executions of the activity the worker runs at a time. A `max` of 0 is
+    /// treated as 1, since an activity that can never run would be deferred forever.
+    pub fn with_max_concurrency(mut self, max: usize) -> Self {
+        self.max_concurrency = Some(max.max(1));
+        self
+    }
+
+    /// Limits executions to `executions` per `period` with a token bucket, which allows bursts
+    /// of up to `executions`. As with the concurrency, 0 `executions` are treated as 1.
+    pub fn with_rate(mut self, executions: u32, period: Duration) -> Self {
+        self.rate = Some((executions.max(1), period));
+        self
+    }
+}
+
+/// Limits enforced by the backend across all workers of a task hub.
+///
+/// Workers pass their limits to the backend each time they fetch an activity, so every worker
+/// of a task hub should be configured with the same limits.
+#[derive(Clone, Debug, Default)]
+pub struct TaskHubLimits {
+    activity_concurrency: HashMap<String, usize>,
+}
+
+impl TaskHubLimits {
+    /// Sets the most executions of the activity `name` the task hub runs at a time. A `max` of
+    /// 0 is treated as 1.
+    pub fn with_activity_concurrency(mut self, name: &str, max: usize) -> Self {
+        self.activity_concurrency
+            .insert(name.to_string(), max.max(1));
+        self
+    }
+
+    /// Whether another activity `name` may be locked while `running` are.
+    pub fn is_activity_available(&self, name: &str, running: usize) -> bool {
+        match self.activity_concurrency.get(name) {
+            Some(max) => running < *max,
+            None => true,
+        }
+    }
+}
+
+/// How long to defer an activity that's at its concurrency limit, since there's no telling when
+/// a running execution finishes.
+const CONCURRENCY_DEFER_DELAY: Duration = Duration::from_secs(1);
+
+/// Capacity held for one execution of a limited activity, released when dropped.
+pub(crate) struct ThrottlePermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ThrottlePermit {
+    /// A permit for work that isn't limited.
+    pub(crate) fn unlimited() -> Self {
+        ThrottlePermit { _permit: None }
+    }
+}
+
+/// Applies the [`ActivityLimit`]s of a worker.
+#[derive(Default)]
+pub(crate) struct ActivityThrottle {
+    limiters: HashMap<String, Limiter>,
+}
+
+struct Limiter {
+    semaphore: Option<Arc<Semaphore>>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl ActivityThrottle {
+    pub(crate) fn new(limits: &HashMap<String, ActivityLimit>) -> Self {
+        let limiters = limits
+            .iter()
+            .map(|(name, limit)| {
+                let limiter = Limiter {
+                    semaphore: limit
+                        .max_concurrency
+                        .map(|max| Arc::new(Semaphore::new(max))),
+                    bucket: limit.rate.map(|(executions, period)| {
+                        Mutex::new(TokenBucket::new(executions, period))
+                    }),
+                };
+                (name.clone(), limiter)
+            })
+            .collect();
+        ActivityThrottle { limiters }
+    }
+
+    /// Takes capacity for one execution of the activity `name`, or returns how long to defer it.
+    pub(crate) fn try_acquire(&self, name: &str) -> Result<ThrottlePermit, Duration> {
+        let Some(limiter) = self.limiters.get(name) else {
+            return Ok(ThrottlePermit::unlimited());
+        };
+        let permit = match &limiter.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| CONCURRENCY_DEFER_DELAY)?,
+            ),
+            None => None,
+        };
+        // A deferred execution releases its concurrency permit along with the work item
+        if let Some(bucket) = &limiter.bucket {
+            bucket.lock().unwrap().try_take(Instant::now())?;
+        }
+        Ok(ThrottlePermit { _permit: permit })
+    }
+}
+
+/// A token bucket holding up to `capacity` tokens, refilled continuously at `capacity` tokens
+/// per `period`.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, period: Duration) -> Self {
+        let capacity = f64::from(capacity);
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / period.as_secs_f64().max(f64::EPSILON),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(10));
+        bucket.refilled_at = start;
+        assert!(bucket.try_take(start).is_ok());
+        assert!(bucket.try_take(start).is_ok());
+        let wait = bucket.try_take(start).unwrap_err();
+        assert_eq!(wait.as_secs(), 5);
+
+        assert!(bucket.try_take(start + Duration::from_secs(5)).is_ok());
+        assert!(bucket.try_take(start + Duration::from_secs(5)).is_err());
+        // Idle time never refills more than the capacity
+        let later = start + Duration::from_secs(100);
+        assert!(bucket.try_take(later).is_ok());
+        assert!(bucket.try_take(later).is_ok());
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test]
+    fn test_activity_throttle() {
+        let limits = HashMap::from([
+            (
+                "charge".to_string(),
+                ActivityLimit::default().with_max_concurrency(1),
+            ),
+            (
+                "geocode".to_string(),
+                ActivityLimit::default().with_rate(1, Duration::from_secs(60)),
+            ),
+        ]);
+        let throttle = ActivityThrottle::new(&limits);
+
+        let permit = throttle.try_acquire("charge").ok().unwrap();
+        assert_eq!(
+            throttle.try_acquire("charge").err(),
+            Some(CONCURRENCY_DEFER_DELAY)
+        );
+        drop(permit);
+        assert!(throttle.try_acquire("charge").is_ok());
+
+        assert!(throttle.try_acquire("geocode").is_ok());
+        let wait = throttle.try_acquire("geocode").err().unwrap();
+        assert!(wait > Duration::from_secs(59));
+
+        assert!(throttle.try_acquire("unlimited").is_ok());
+
+        let hub = TaskHubLimits::default()
+            .with_activity_concurrency("charge", 2)
+            .with_activity_concurrency("paused", 0);
+        assert!(hub.is_activity_available("charge", 1));
+        assert!(!hub.is_activity_available("charge", 2));
+        assert!(hub.is_activity_available("geocode", 100));
+        assert!(hub.is_activity_available("paused", 0));
+        assert!(!hub.is_activity_available("paused", 1));
+    }
+}
@@ -0,0 +1,1652 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use backon::{BackoffBuilder, ExponentialBuilder};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::backend::activity::ActivityProcessor;
+use crate::backend::backoff::{AbandonBackoff, LinearBuilder};
+use crate::backend::cache::ExtendedSessionOptions;
+use crate::backend::commit::relay_outbox_messages;
+use crate::backend::deadletter::poison_work_item_failure_details;
+use crate::backend::executor::Executor;
+use crate::backend::lock::WorkItemLock;
+use crate::backend::orchestration::OrchestrationProcessor;
+use crate::backend::routing::{TaskRouting, WorkItemFilter};
+use crate::backend::throttle::{ActivityLimit, TaskHubLimits, ThrottlePermit};
+use crate::backend::{Backend, BackendError};
+use crate::durabletask_pb::TaskFailureDetails;
+use crate::payload::{JsonCodec, PayloadCodec, PayloadPipeline, PayloadTransformer};
+use crate::task::cancellation::CancellationSource;
+use crate::task::executor::TaskExecutor;
+use crate::task::TaskRegistry;
+use crate::Error;
+
+/// Fetches, processes and completes work items of one kind.
+#[async_trait]
+pub(crate) trait TaskProcessor: Send + Sync {
+    type WorkItem: Send + fmt::Display + 'static;
+
+    /// Returns [`BackendError::NoWorkItems`] when there's nothing to process.
+    async fn fetch_work_item(&self) -> Result<Self::WorkItem, BackendError>;
+    async fn process_work_item(&self, wi: &mut Self::WorkItem) -> Result<(), Error>;
+    async fn complete_work_item(&self, wi: &Self::WorkItem) -> Result<(), BackendError>;
+    /// Releases `wi` so it's retried after `delay`.
+    async fn abandon_work_item(
+        &self,
+        wi: &Self::WorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError>;
+
+    /// Takes the capacity needed to process `wi`, or returns how long to defer it.
+    fn try_acquire(&self, _wi: &Self::WorkItem) -> Result<ThrottlePermit, Duration> {
+        Ok(ThrottlePermit::unlimited())
+    }
+    /// Releases `wi` so it's fetched again after `delay`, without counting as a failed attempt.
+    async fn defer_work_item(
+        &self,
+        wi: &Self::WorkItem,
+        delay: Duration,
+    ) -> Result<(), BackendError>;
+
+    /// The number of times `wi` was abandoned before.
+    fn retry_count(&self, wi: &Self::WorkItem) -> i32;
+    /// Moves `wi` to the dead-letter store, failing its instance with `failure_details`.
+    async fn dead_letter_work_item(
+        &self,
+        wi: &Self::WorkItem,
+        failure_details: &TaskFailureDetails,
+    ) -> Result<(), BackendError>;
+
+    /// The lease held on `wi`, which is renewed while it's processed.
+    fn work_item_lock(&self, wi: &Self::WorkItem) -> Option<WorkItemLock>;
+    async fn renew_work_item_lock(&self, lock: &WorkItemLock) -> Result<SystemTime, BackendError>;
+    /// The expiry of `lock` if processing its work item renewed it since it was fetched, e.g.
+    /// with an activity heartbeat.
+    fn renewed_lock_expiry(&self, _lock: &WorkItemLock) -> Option<SystemTime> {
+        None
+    }
+    /// Asks the processing of the work item holding `lock` to stop early, because the backend
+    /// reported it as [cancelled](BackendError::WorkItemCancelled).
+    fn cancel_work_item(&self, _lock: &WorkItemLock) {}
+
+    /// Called with work items that were completed successfully.
+    async fn release_work_item(&self, _wi: Self::WorkItem) {}
+}
+
+/// Options for a [`TaskHubWorker`].
+pub struct WorkerOptions {
+    codec: Arc<dyn PayloadCodec>,
+    payloads: PayloadPipeline,
+    max_concurrent_orchestrations: usize,
+    max_concurrent_activities: usize,
+    max_poll_delay: Duration,
+    extended_sessions: Option<ExtendedSessionOptions>,
+    history_snapshot_threshold: Option<usize>,
+    max_work_item_retries: Option<u32>,
+    orchestration_backoff: Arc<dyn AbandonBackoff>,
+    activity_backoff: Arc<dyn AbandonBackoff>,
+    activity_filter: WorkItemFilter,
+    activity_limits: HashMap<String, ActivityLimit>,
+    task_hub_limits: TaskHubLimits,
+    task_routing: TaskRouting,
+    drain_timeout: Duration,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptionsBuilder::default().build()
+    }
+}
+
+impl WorkerOptions {
+    pub fn builder() -> WorkerOptionsBuilder {
+        WorkerOptionsBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct WorkerOptionsBuilder {
+    codec: Option<Arc<dyn PayloadCodec>>,
+    payloads: PayloadPipeline,
+    max_concurrent_orchestrations: Option<usize>,
+    max_concurrent_activities: Option<usize>,
+    max_poll_delay: Option<Duration>,
+    extended_sessions: Option<ExtendedSessionOptions>,
+    history_snapshot_threshold: Option<usize>,
+    max_work_item_retries: Option<u32>,
+    orchestration_backoff: Option<Arc<dyn AbandonBackoff>>,
+    activity_backoff: Option<Arc<dyn AbandonBackoff>>,
+    activity_filter: Option<WorkItemFilter>,
+    activity_limits: HashMap<String, ActivityLimit>,
+    task_hub_limits: Option<TaskHubLimits>,
+    task_routing: Option<TaskRouting>,
+    drain_timeout: Option<Duration>,
+}
+
+impl WorkerOptionsBuilder {
+    /// Sets the codec used for orchestration and activity payloads and custom status. This
+    /// must match the codec used by clients of the same task hub.
+    pub fn codec(mut self, codec: Arc<dyn PayloadCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Adds a transformer applied to payloads after they're encoded, e.g. to encrypt them.
+    /// Transformers are applied in the order they're added.
+    pub fn payload_transformer(mut self, transformer: Arc<dyn PayloadTransformer>) -> Self {
+        self.payloads.push(transformer);
+        self
+    }
+
+    pub fn max_concurrent_orchestrations(mut self, max: usize) -> Self {
+        self.max_concurrent_orchestrations = Some(max.max(1));
+        self
+    }
+
+    pub fn max_concurrent_activities(mut self, max: usize) -> Self {
+        self.max_concurrent_activities = Some(max.max(1));
+        self
+    }
+
+    /// Sets the longest delay between polls while the backend has no work items.
+    pub fn max_poll_delay(mut self, delay: Duration) -> Self {
+        self.max_poll_delay = Some(delay);
+        self
+    }
+
+    /// Enables extended sessions, keeping recently active orchestrations in memory between
+    /// work items. Each instance must be processed by one worker at a time while it's cached,
+    /// unless the backend reports history lengths on its work items.
+    pub fn extended_sessions(mut self, options: ExtendedSessionOptions) -> Self {
+        self.extended_sessions = Some(options);
+        self
+    }
+
+    /// Compacts the history of long-running orchestrations into a snapshot once `threshold`
+    /// events were added since the previous one, so backends can drop the events it replaces.
+    /// Snapshots keep the result of every resolved task, so they don't replace continue-as-new
+    /// for unbounded orchestrations.
+    pub fn history_snapshot_threshold(mut self, threshold: usize) -> Self {
+        self.history_snapshot_threshold = Some(threshold.max(1));
+        self
+    }
+
+    /// Moves work items that failed after `max` retries to the backend's dead-letter store and
+    /// fails their instance, instead of retrying them forever.
+    pub fn max_work_item_retries(mut self, max: u32) -> Self {
+        self.max_work_item_retries = Some(max);
+        self
+    }
+
+    /// Sets how long failed orchestration work items wait before they're retried. Defaults to
+    /// a [`LinearBuilder`] backoff.
+    pub fn orchestration_backoff(mut self, backoff: Arc<dyn AbandonBackoff>) -> Self {
+        self.orchestration_backoff = Some(backoff);
+        self
+    }
+
+    /// Sets how long failed activity work items wait before they're retried. Defaults to a
+    /// [`LinearBuilder`] backoff.
+    pub fn activity_backoff(mut self, backoff: Arc<dyn AbandonBackoff>) -> Self {
+        self.activity_backoff = Some(backoff);
+        self
+    }
+
+    /// Sets the task queues and activity names the worker fetches activities for, so pools of
+    /// workers can be dedicated to specific activities.
+    pub fn activity_filter(mut self, filter: WorkItemFilter) -> Self {
+        self.activity_filter = Some(filter);
+        self
+    }
+
+    /// Limits how often the worker runs the activity `name`.
+    pub fn activity_limit(mut self, name: &str, limit: ActivityLimit) -> Self {
+        self.activity_limits.insert(name.to_string(), limit);
+        self
+    }
+
+    /// Sets the limits the backend enforces across all workers of the task hub. Every worker of
+    /// the task hub should use the same limits.
+    pub fn task_hub_limits(mut self, limits: TaskHubLimits) -> Self {
+        self.task_hub_limits = Some(limits);
+        self
+    }
+
+    /// Sets the task queues and priorities that the activities and orchestrations scheduled by
+    /// this worker are enqueued with.
+    pub fn task_routing(mut self, routing: TaskRouting) -> Self {
+        self.task_routing = Some(routing);
+        self
+    }
+
+    /// Sets how long shutting down waits for in-flight work items before releasing them to
+    /// other workers.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> WorkerOptions {
+        WorkerOptions {
+            codec: self.codec.unwrap_or_else(|| Arc::new(JsonCodec)),
+            payloads: self.payloads,
+            max_concurrent_orchestrations: self.max_concurrent_orchestrations.unwrap_or(1),
+            max_concurrent_activities: self.max_concurrent_activities.unwrap_or(1),
+            max_poll_delay: self.max_poll_delay.unwrap_or(Duration::from_secs(5)),
+            extended_sessions: self.extended_sessions,
+            history_snapshot_threshold: self.history_snapshot_threshold,
+            max_work_item_retries: self.max_work_item_retries,
+            orchestration_backoff: self
+                .orchestration_backoff
+                .unwrap_or_else(|| Arc::new(LinearBuilder::default())),
+            activity_backoff: self
+                .activity_backoff
+                .unwrap_or_else(|| Arc::new(LinearBuilder::default())),
+            activity_filter: self.activity_filter.unwrap_or_default(),
+            activity_limits: self.activity_limits,
+            task_hub_limits: self.task_hub_limits.unwrap_or_default(),
+            task_routing: self.task_routing.unwrap_or_default(),
+            drain_timeout: self.drain_timeout.unwrap_or(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// The work items a worker released unfinished when it shut down, to be retried by other
+/// workers.
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    pub abandoned_orchestrations: Vec<String>,
+    pub abandoned_activities: Vec<String>,
+}
+
+/// Runs the orchestrators and activities of a [`TaskRegistry`] against a [`Backend`].
+pub struct TaskHubWorker {
+    be: Arc<dyn Backend>,
+    orchestration_processor: Arc<OrchestrationProcessor>,
+    activity_processor: Arc<ActivityProcessor>,
+    options: WorkerOptions,
+    shutdown: watch::Sender<bool>,
+    activity_cancellation: Arc<CancellationSource>,
+    running: Mutex<Option<RunningTasks>>,
+}
+
+/// The tasks spawned by [`TaskHubWorker::start`].
+struct RunningTasks {
+    orchestrations: JoinHandle<Vec<String>>,
+    activities: JoinHandle<Vec<String>>,
+    outbox_relay: Option<JoinHandle<()>>,
+}
+
+impl TaskHubWorker {
+    pub fn new(be: Arc<dyn Backend>, registry: TaskRegistry, options: WorkerOptions) -> Self {
+        let mut executor = TaskExecutor::new(
+            Arc::new(registry),
+            options.codec.clone(),
+            options.payloads.clone(),
+        );
+        if let Some(sessions) = &options.extended_sessions {
+            executor = executor.with_sessions(sessions.clone());
+        }
+        let executor: Arc<dyn Executor> = Arc::new(executor);
+        let mut orchestration_processor = OrchestrationProcessor::new(be.clone(), executor.clone())
+            .with_routing(options.task_routing.clone());
+        if let Some(sessions) = &options.extended_sessions {
+            orchestration_processor = orchestration_processor.with_sessions(sessions.clone());
+        }
+        if let Some(threshold) = options.history_snapshot_threshold {
+            orchestration_processor = orchestration_processor.with_snapshot_threshold(threshold);
+        }
+        let activity_cancellation = Arc::new(CancellationSource::default());
+        TaskHubWorker {
+            orchestration_processor: Arc::new(orchestration_processor),
+            activity_processor: Arc::new(
+                ActivityProcessor::new(be.clone(), executor)
+                    .with_filter(options.activity_filter.clone())
+                    .with_limits(&options.activity_limits)
+                    .with_task_hub_limits(options.task_hub_limits.clone())
+                    .with_cancellation(activity_cancellation.token()),
+            ),
+            be,
+            options,
+            shutdown: watch::channel(false).0,
+            activity_cancellation,
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Starts the backend and begins polling for work items.
+    pub async fn start(&self) -> Result<(), Error> {
+        self.be.start().await?;
+        let orchestrations = tokio::spawn(run_processor(
+            self.orchestration_processor.clone(),
+            self.options.max_concurrent_orchestrations,
+            RetryPolicy {
+                max_retries: self.options.max_work_item_retries,
+                backoff: self.options.orchestration_backoff.clone(),
+            },
+            self.options.max_poll_delay,
+            Drain {
+                timeout: self.options.drain_timeout,
+                cancellation: None,
+            },
+            self.shutdown.subscribe(),
+        ));
+        let activities = tokio::spawn(run_processor(
+            self.activity_processor.clone(),
+            self.options.max_concurrent_activities,
+            RetryPolicy {
+                max_retries: self.options.max_work_item_retries,
+                backoff: self.options.activity_backoff.clone(),
+            },
+            self.options.max_poll_delay,
+            Drain {
+                timeout: self.options.drain_timeout,
+                cancellation: Some(self.activity_cancellation.clone()),
+            },
+            self.shutdown.subscribe(),
+        ));
+        let outbox_relay = self.be.outbox().map(|_| {
+            tokio::spawn(run_outbox_relay(
+                self.be.clone(),
+                self.options.max_poll_delay,
+                self.shutdown.subscribe(),
+            ))
+        });
+        *self.running.lock().unwrap() = Some(RunningTasks {
+            orchestrations,
+            activities,
+            outbox_relay,
+        });
+        Ok(())
+    }
+
+    /// Stops polling, then waits up to the drain timeout for in-flight work items before
+    /// stopping the backend. Activities still running at the deadline have their
+    /// [cancellation tokens](crate::task::CancellationToken) cancelled, and get
+    /// [`CANCELLATION_GRACE_PERIOD`] to stop on their own.
+    ///
+    /// Work items that didn't finish in time, or whose activity stopped because it was
+    /// cancelled, are released without counting as a failed attempt and are reported.
+    pub async fn shutdown(&self) -> Result<ShutdownReport, Error> {
+        self.shutdown.send_replace(true);
+        let running = self.running.lock().unwrap().take();
+        let mut report = ShutdownReport::default();
+        if let Some(running) = running {
+            report.abandoned_orchestrations = running.orchestrations.await.unwrap_or_default();
+            report.abandoned_activities = running.activities.await.unwrap_or_default();
+            if let Some(outbox_relay) = running.outbox_relay {
+                let _ = outbox_relay.await;
+            }
+        }
+        self.be.stop().await?;
+        Ok(report)
+    }
+}
+
+/// How long activities cancelled at the drain deadline get to stop before they're aborted.
+pub const CANCELLATION_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How a processor waits for its in-flight work items on shutdown.
+struct Drain {
+    timeout: Duration,
+    /// Cancelled once `timeout` passes, so work items can stop on their own before they're
+    /// aborted.
+    cancellation: Option<Arc<CancellationSource>>,
+}
+
+/// How a processor retries the work items that failed.
+#[derive(Clone)]
+struct RetryPolicy {
+    max_retries: Option<u32>,
+    backoff: Arc<dyn AbandonBackoff>,
+}
+
+async fn run_processor<P: TaskProcessor + 'static>(
+    processor: Arc<P>,
+    max_concurrency: usize,
+    retry: RetryPolicy,
+    max_poll_delay: Duration,
+    drain: Drain,
+    mut shutdown: watch::Receiver<bool>,
+) -> Vec<String> {
+    let (abort, _) = watch::channel(false);
+    let abandoned = Arc::new(Mutex::new(Vec::new()));
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+    let idle_backoff = || {
+        ExponentialBuilder::default()
+            .with_min_delay(Duration::from_millis(50))
+            .with_max_delay(max_poll_delay)
+            .with_max_times(usize::MAX)
+            .build()
+    };
+    let mut backoff = idle_backoff();
+
+    while !*shutdown.borrow() {
+        let permit = tokio::select! {
+            _ = shutdown.changed() => break,
+            permit = semaphore.clone().acquire_owned() => permit.expect("semaphore is never closed"),
+        };
+        let result = tokio::select! {
+            _ = shutdown.changed() => break,
+            result = processor.fetch_work_item() => result,
+        };
+
+        match result {
+            Ok(wi) => {
+                backoff = idle_backoff();
+                let processor = processor.clone();
+                let retry = retry.clone();
+                let mut abort = abort.subscribe();
+                let abandoned = abandoned.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let released =
+                        process_work_item(processor.as_ref(), wi, &retry, &mut abort).await;
+                    if let Some(wi) = released {
+                        abandoned.lock().unwrap().push(wi);
+                    }
+                });
+            }
+            // Backend errors are retried with the same backoff as an empty queue
+            Err(_) => {
+                drop(permit);
+                let delay = backoff.next().unwrap_or(max_poll_delay);
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+        }
+    }
+
+    // Wait for in-flight work items to finish, then release those that are still running
+    let max_concurrency = max_concurrency as u32;
+    let drained = tokio::time::timeout(drain.timeout, semaphore.acquire_many(max_concurrency));
+    let mut drained = drained.await.is_ok();
+    if let (false, Some(cancellation)) = (drained, &drain.cancellation) {
+        cancellation.cancel();
+        let stopped = tokio::time::timeout(
+            CANCELLATION_GRACE_PERIOD,
+            semaphore.acquire_many(max_concurrency),
+        );
+        drained = stopped.await.is_ok();
+    }
+    if !drained {
+        abort.send_replace(true);
+        let _ = semaphore.acquire_many(max_concurrency).await;
+    }
+    let abandoned = std::mem::take(&mut *abandoned.lock().unwrap());
+    abandoned
+}
+
+/// The most outbox messages relayed per batch.
+const OUTBOX_BATCH_SIZE: usize = 100;
+
+/// Relays the messages of the backend's outbox until shutdown, polling again right away while
+/// full batches are delivered.
+async fn run_outbox_relay(
+    be: Arc<dyn Backend>,
+    max_poll_delay: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    while !*shutdown.borrow() {
+        let Some(outbox) = be.outbox() else {
+            return;
+        };
+        let delay = match relay_outbox_messages(be.as_ref(), outbox, OUTBOX_BATCH_SIZE).await {
+            Ok(relayed) if relayed == OUTBOX_BATCH_SIZE => continue,
+            Ok(_) | Err(_) => max_poll_delay,
+        };
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+/// The shortest delay between lock renewals, so failing renewals aren't retried in a loop.
+const MIN_RENEW_DELAY: Duration = Duration::from_millis(100);
+
+/// How processing a work item ended.
+enum Processed {
+    Finished(Result<(), Error>),
+    LockLost,
+    Aborted,
+}
+
+/// Processes, then completes or abandons `wi`. Returns the description of the work item if it
+/// was released unfinished because the worker is shutting down.
+async fn process_work_item<P: TaskProcessor>(
+    processor: &P,
+    mut wi: P::WorkItem,
+    retry: &RetryPolicy,
+    abort: &mut watch::Receiver<bool>,
+) -> Option<String> {
+    let _permit = match processor.try_acquire(&wi) {
+        Ok(permit) => permit,
+        Err(delay) => {
+            let _ = processor.defer_work_item(&wi, delay).await;
+            return None;
+        }
+    };
+    let processed = tokio::select! {
+        processed = process_with_lock(processor, &mut wi) => processed,
+        _ = aborted(abort) => Processed::Aborted,
+    };
+    let result = match processed {
+        Processed::Finished(Err(Error::Cancelled)) | Processed::Aborted => {
+            let description = wi.to_string();
+            let _ = processor.defer_work_item(&wi, Duration::ZERO).await;
+            return Some(description);
+        }
+        // Another worker may already be processing the work item, and this worker's results
+        // would be rejected
+        Processed::LockLost => return None,
+        Processed::Finished(result) => result,
+    };
+    let error = match result {
+        Ok(()) => match processor.complete_work_item(&wi).await {
+            Ok(()) => {
+                processor.release_work_item(wi).await;
+                return None;
+            }
+            // The results of cancelled work items are dropped by the backend
+            Err(BackendError::WorkItemLockLost | BackendError::WorkItemCancelled) => return None,
+            Err(e) => e.to_string(),
+        },
+        Err(e) => e.to_string(),
+    };
+
+    let retry_count = processor.retry_count(&wi);
+    let retries = u32::try_from(retry_count).unwrap_or(0);
+    if retry.max_retries.is_some_and(|max| retries >= max) {
+        let details = poison_work_item_failure_details(retry_count + 1, &error);
+        if processor.dead_letter_work_item(&wi, &details).await.is_ok() {
+            return None;
+        }
+    }
+    let _ = processor
+        .abandon_work_item(&wi, retry.backoff.delay(retries))
+        .await;
+    None
+}
+
+/// Processes `wi` while renewing its lock.
+async fn process_with_lock<P: TaskProcessor>(processor: &P, wi: &mut P::WorkItem) -> Processed {
+    match processor.work_item_lock(wi) {
+        Some(lock) => tokio::select! {
+            result = processor.process_work_item(wi) => Processed::Finished(result),
+            _ = renew_lock_until_lost(processor, lock) => Processed::LockLost,
+        },
+        None => Processed::Finished(processor.process_work_item(wi).await),
+    }
+}
+
+/// Waits until `abort` is set.
+async fn aborted(abort: &mut watch::Receiver<bool>) {
+    if abort.wait_for(|aborted| *aborted).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Renews `lock` before it expires, returning once it was lost. Stops renewing it once the
+/// work item was cancelled, which the processor finishes on its own.
+async fn renew_lock_until_lost<P: TaskProcessor>(processor: &P, mut lock: WorkItemLock) {
+    loop {
+        let delay = lock.renew_delay(SystemTime::now()).max(MIN_RENEW_DELAY);
+        tokio::time::sleep(delay).await;
+        // A lease extended in the meantime is renewed once half of its extra time passed
+        if let Some(expires_at) = processor.renewed_lock_expiry(&lock) {
+            if expires_at > lock.expires_at {
+                lock.expires_at = expires_at;
+                continue;
+            }
+        }
+        match processor.renew_work_item_lock(&lock).await {
+            Ok(expires_at) => lock.expires_at = expires_at,
+            Err(BackendError::WorkItemLockLost) => return,
+            Err(BackendError::WorkItemCancelled) => {
+                processor.cancel_work_item(&lock);
+                return std::future::pending().await;
+            }
+            // Other errors are retried until the lock expires
+            Err(_) if lock.expires_at <= SystemTime::now() => return,
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::api::{InstanceID, NewOrchestration, RaiseEventBuilder, TerminateBuilder};
+    use crate::backend::client::TaskHubClient;
+    use crate::backend::lock::LockOptions;
+    use crate::backend::routing::{Priority, TaskRoute};
+    use crate::backend::testing::TestBackend;
+    use crate::durabletask_pb::history_event::EventType;
+    use crate::durabletask_pb::{
+        CreateOrchestrationAction, HistoryEvent, OrchestrationIdReusePolicy, OrchestrationStatus,
+    };
+    use crate::internal::{new_execution_started_event, new_task_scheduled_event};
+    use crate::payload::store::{FileSystemBlobStore, PayloadOffloader};
+    use crate::task::{ActivityContext, OrchestrationContext};
+
+    struct TestWorkItem;
+
+    impl fmt::Display for TestWorkItem {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "test")
+        }
+    }
+
+    /// Processes work items for `process_time`, with a lease the backend renews `renewals`
+    /// times before reporting it lost, or cancelled if `cancel` is set. Failing processors
+    /// don't lease their work items. A `heartbeat_lease` extends the lease while processing.
+    struct TestProcessor {
+        process_time: Duration,
+        renewals: usize,
+        heartbeat_lease: Option<Duration>,
+        retry_count: i32,
+        fail: bool,
+        cancel: bool,
+        cancellation: Arc<CancellationSource>,
+        renewed: AtomicUsize,
+        completed: AtomicUsize,
+        abandoned: Mutex<Vec<Duration>>,
+        deferred: Mutex<Vec<Duration>>,
+        fetchable: AtomicUsize,
+        dead_lettered: Mutex<Option<TaskFailureDetails>>,
+    }
+
+    impl TestProcessor {
+        fn new(process_time: Duration, renewals: usize) -> Self {
+            TestProcessor {
+                process_time,
+                renewals,
+                heartbeat_lease: None,
+                retry_count: 0,
+                fail: false,
+                cancel: false,
+                cancellation: Arc::default(),
+                renewed: AtomicUsize::new(0),
+                completed: AtomicUsize::new(0),
+                abandoned: Mutex::new(Vec::new()),
+                deferred: Mutex::new(Vec::new()),
+                fetchable: AtomicUsize::new(0),
+                dead_lettered: Mutex::new(None),
+            }
+        }
+
+        fn failing(retry_count: i32) -> Self {
+            TestProcessor {
+                retry_count,
+                fail: true,
+                ..TestProcessor::new(Duration::ZERO, usize::MAX)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TaskProcessor for TestProcessor {
+        type WorkItem = TestWorkItem;
+
+        async fn fetch_work_item(&self) -> Result<TestWorkItem, BackendError> {
+            let fetchable = self.fetchable.load(Ordering::SeqCst);
+            if fetchable == 0 {
+                return Err(BackendError::NoWorkItems);
+            }
+            self.fetchable.store(fetchable - 1, Ordering::SeqCst);
+            Ok(TestWorkItem)
+        }
+
+        async fn process_work_item(&self, _wi: &mut TestWorkItem) -> Result<(), Error> {
+            let cancelled = self.cancellation.token();
+            tokio::select! {
+                _ = tokio::time::sleep(self.process_time) => {}
+                _ = cancelled.cancelled() => {}
+            }
+            if self.fail {
+                return Err(Error::InvalidArgument("boom".to_string()));
+            }
+            Ok(())
+        }
+
+        async fn complete_work_item(&self, _wi: &TestWorkItem) -> Result<(), BackendError> {
+            if self.cancel {
+                return Err(BackendError::WorkItemCancelled);
+            }
+            self.completed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn abandon_work_item(
+            &self,
+            _wi: &TestWorkItem,
+            delay: Duration,
+        ) -> Result<(), BackendError> {
+            self.abandoned.lock().unwrap().push(delay);
+            Ok(())
+        }
+
+        async fn defer_work_item(
+            &self,
+            _wi: &TestWorkItem,
+            delay: Duration,
+        ) -> Result<(), BackendError> {
+            self.deferred.lock().unwrap().push(delay);
+            Ok(())
+        }
+
+        fn retry_count(&self, _wi: &TestWorkItem) -> i32 {
+            self.retry_count
+        }
+
+        async fn dead_letter_work_item(
+            &self,
+            _wi: &TestWorkItem,
+            failure_details: &TaskFailureDetails,
+        ) -> Result<(), BackendError> {
+            *self.dead_lettered.lock().unwrap() = Some(failure_details.clone());
+            Ok(())
+        }
+
+        fn work_item_lock(&self, _wi: &TestWorkItem) -> Option<WorkItemLock> {
+            if self.fail {
+                return None;
+            }
+            Some(WorkItemLock {
+                instance_id: InstanceID("abc".to_string()),
+                sequence_number: None,
+                locked_by: "worker".to_string(),
+                lock_token: "token".to_string(),
+                expires_at: SystemTime::now() + Duration::from_millis(200),
+            })
+        }
+
+        async fn renew_work_item_lock(
+            &self,
+            _lock: &WorkItemLock,
+        ) -> Result<SystemTime, BackendError> {
+            if self.renewed.fetch_add(1, Ordering::SeqCst) < self.renewals {
+                Ok(SystemTime::now() + Duration::from_millis(200))
+            } else if self.cancel {
+                Err(BackendError::WorkItemCancelled)
+            } else {
+                Err(BackendError::WorkItemLockLost)
+            }
+        }
+
+        fn renewed_lock_expiry(&self, _lock: &WorkItemLock) -> Option<SystemTime> {
+            self.heartbeat_lease.map(|lease| SystemTime::now() + lease)
+        }
+
+        fn cancel_work_item(&self, _lock: &WorkItemLock) {
+            self.cancellation.cancel();
+        }
+    }
+
+    fn retry_policy(max_retries: Option<u32>) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff: Arc::new(LinearBuilder::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_renewed_while_processing() {
+        let processor = TestProcessor::new(Duration::from_millis(350), usize::MAX);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(None),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert!(processor.renewed.load(Ordering::SeqCst) >= 2);
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_extends_lock() {
+        // The backend would report the lease lost on the first renewal
+        let processor = TestProcessor {
+            heartbeat_lease: Some(Duration::from_millis(200)),
+            ..TestProcessor::new(Duration::from_millis(350), 0)
+        };
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(None),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(processor.renewed.load(Ordering::SeqCst), 0);
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lost_lock_discards_results() {
+        let processor = TestProcessor::new(Duration::from_secs(10), 1);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(None),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(processor.renewed.load(Ordering::SeqCst), 2);
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 0);
+        assert!(processor.abandoned.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_work_item_stopped() {
+        let processor = TestProcessor {
+            cancel: true,
+            ..TestProcessor::new(Duration::from_secs(10), 1)
+        };
+        let retry = retry_policy(None);
+        let (_abort_tx, mut abort) = watch::channel(false);
+        let processed = process_work_item(&processor, TestWorkItem, &retry, &mut abort);
+        let released = tokio::time::timeout(Duration::from_secs(5), processed)
+            .await
+            .unwrap();
+        assert_eq!(released, None);
+        assert_eq!(processor.renewed.load(Ordering::SeqCst), 2);
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 0);
+        assert!(processor.abandoned.lock().unwrap().is_empty());
+        assert!(processor.deferred.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_poison_work_item_dead_lettered() {
+        let processor = TestProcessor::failing(2);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(Some(3)),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(processor.abandoned.lock().unwrap().len(), 1);
+        assert!(processor.dead_lettered.lock().unwrap().is_none());
+
+        let processor = TestProcessor::failing(3);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(Some(3)),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert!(processor.abandoned.lock().unwrap().is_empty());
+        let details = processor.dead_lettered.lock().unwrap().clone().unwrap();
+        assert_eq!(details.error_type, "PoisonWorkItem");
+        assert!(details.error_message.contains("4 failed attempts"));
+        assert!(details.error_message.contains("boom"));
+
+        // Without a limit work items are retried forever
+        let processor = TestProcessor::failing(1000);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(None),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(processor.abandoned.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_abandon_backoff() {
+        let retry = RetryPolicy {
+            max_retries: None,
+            backoff: Arc::new(
+                backon::ConstantBuilder::default().with_delay(Duration::from_secs(7)),
+            ),
+        };
+        let processor = TestProcessor::failing(5);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry,
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(
+            *processor.abandoned.lock().unwrap(),
+            [Duration::from_secs(7)]
+        );
+
+        let processor = TestProcessor::failing(2);
+        process_work_item(
+            &processor,
+            TestWorkItem,
+            &retry_policy(None),
+            &mut watch::channel(false).1,
+        )
+        .await;
+        assert_eq!(
+            *processor.abandoned.lock().unwrap(),
+            [Duration::from_secs(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_releases_unfinished_work_items() {
+        let processor = Arc::new(TestProcessor::new(Duration::from_secs(10), usize::MAX));
+        processor.fetchable.store(1, Ordering::SeqCst);
+        let (shutdown, receiver) = watch::channel(false);
+        let running = tokio::spawn(run_processor(
+            processor.clone(),
+            2,
+            retry_policy(None),
+            Duration::from_millis(10),
+            Drain {
+                timeout: Duration::from_millis(50),
+                cancellation: None,
+            },
+            receiver,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.send_replace(true);
+
+        let abandoned = running.await.unwrap();
+        assert_eq!(abandoned, ["test"]);
+        assert_eq!(*processor.deferred.lock().unwrap(), [Duration::ZERO]);
+        assert!(processor.abandoned.lock().unwrap().is_empty());
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_at_drain_deadline() {
+        let processor = Arc::new(TestProcessor::new(Duration::from_secs(10), usize::MAX));
+        processor.fetchable.store(1, Ordering::SeqCst);
+        let (shutdown, receiver) = watch::channel(false);
+        let running = tokio::spawn(run_processor(
+            processor.clone(),
+            2,
+            retry_policy(None),
+            Duration::from_millis(10),
+            Drain {
+                timeout: Duration::from_millis(200),
+                cancellation: Some(processor.cancellation.clone()),
+            },
+            receiver,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.send_replace(true);
+
+        // In-flight work items keep running until the deadline
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!processor.cancellation.token().is_cancelled());
+
+        // and are then cancelled, stopping before they'd be released
+        assert!(running.await.unwrap().is_empty());
+        assert!(processor.cancellation.token().is_cancelled());
+        assert_eq!(processor.completed.load(Ordering::SeqCst), 1);
+        assert!(processor.deferred.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_rejects_completion() {
+        // Leases expire before the worker's first renewal
+        let lock_options =
+            LockOptions::default().with_activity_lock_timeout(Duration::from_millis(20));
+        let be = Arc::new(TestBackend::default().with_lock_options(lock_options));
+        let start = new_execution_started_event("greet", "abc", None, None, None, None);
+        be.create_orchestration_instance(&start, Priority::default(), Vec::new())
+            .await
+            .unwrap();
+        be.enqueue_activity(
+            "abc",
+            new_task_scheduled_event(0, "slow", None, None, None),
+            TaskRoute::default(),
+        );
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut registry = TaskRegistry::new();
+        let counter = attempts.clone();
+        registry
+            .add_activity("slow", move |_ctx: ActivityContext| {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    // The first attempt outlives its lease
+                    if attempt == 0 {
+                        tokio::time::sleep(Duration::from_millis(60)).await;
+                    }
+                    Ok::<_, Error>(attempt)
+                }
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let processor = ActivityProcessor::new(be.clone(), Arc::new(executor));
+        let retry = retry_policy(None);
+        let (_abort_tx, mut abort) = watch::channel(false);
+
+        let wi = processor.fetch_work_item().await.unwrap();
+        assert_eq!(
+            process_work_item(&processor, wi, &retry, &mut abort).await,
+            None
+        );
+        // The zombie's result was rejected, and the activity is delivered again
+        let redelivered = processor.fetch_work_item().await.unwrap();
+        assert_eq!(redelivered.dispatch_count, 1);
+        assert_eq!(
+            process_work_item(&processor, redelivered, &retry, &mut abort).await,
+            None
+        );
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            *be.calls.lock().unwrap(),
+            ["complete_activity_work_item"; 2]
+        );
+        assert!(be.activities.lock().unwrap().is_empty());
+        let instances = be.instances.lock().unwrap();
+        let results: Vec<_> = instances["abc"]
+            .inbox
+            .iter()
+            .filter_map(|e| match &e.event_type {
+                Some(EventType::TaskCompleted(completed)) => completed.result.clone(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results, ["1"]);
+    }
+
+    /// Starts a worker running `registry` against `be`, polling often.
+    async fn start_worker(
+        be: Arc<TestBackend>,
+        registry: TaskRegistry,
+        options: WorkerOptionsBuilder,
+    ) -> TaskHubWorker {
+        let options = options.max_poll_delay(Duration::from_millis(10)).build();
+        let worker = TaskHubWorker::new(be, registry, options);
+        worker.start().await.unwrap();
+        worker
+    }
+
+    async fn wait_for_status(client: &TaskHubClient, id: &InstanceID, status: OrchestrationStatus) {
+        let reached = async {
+            loop {
+                let metadata = client.fetch_orchestration_metadata(id, false).await;
+                if metadata.is_ok_and(|metadata| metadata.runtime_status == status) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reached)
+            .await
+            .expect("the orchestration didn't reach the expected status");
+    }
+
+    fn counter_registry() -> TaskRegistry {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("counter", |ctx: OrchestrationContext| async move {
+                let count: u32 = ctx.get_input()?;
+                if count < 2 {
+                    ctx.continue_as_new(&(count + 1), true)?;
+                    return Ok(String::new());
+                }
+                let stop: String = ctx.wait_for_external_event("stop").await?;
+                Ok::<_, Error>(format!("{stop} at {count}"))
+            })
+            .unwrap();
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_continue_as_new_runs_next_execution() {
+        let registry = counter_registry();
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone());
+        let id = client
+            .schedule_new_orchestration(
+                "counter",
+                NewOrchestration::builder()
+                    .instance_id(InstanceID("abc".to_string()))
+                    .input(&0),
+            )
+            .await
+            .unwrap();
+        // Received by the first execution, and carried over to the last one
+        client
+            .raise_event(
+                &id,
+                "stop",
+                RaiseEventBuilder::new().event_payload(&"stopped"),
+            )
+            .await
+            .unwrap();
+
+        let worker = start_worker(be.clone(), registry, WorkerOptions::builder()).await;
+        wait_for_status(&client, &id, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        let state = be.state("abc").unwrap();
+        assert_eq!(state.input().unwrap(), "2");
+        assert_eq!(state.output().unwrap(), r#""stopped at 2""#);
+        assert_eq!(
+            *be.calls.lock().unwrap(),
+            ["complete_orchestration_work_item"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_filtered_by_execution() {
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone());
+        let id = client
+            .schedule_new_orchestration("counter", NewOrchestration::builder().input(&0))
+            .await
+            .unwrap();
+        client
+            .raise_event(
+                &id,
+                "stop",
+                RaiseEventBuilder::new().event_payload(&"stopped"),
+            )
+            .await
+            .unwrap();
+        let worker = start_worker(be.clone(), counter_registry(), WorkerOptions::builder()).await;
+        wait_for_status(&client, &id, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        let history = client.get_orchestration_history(&id, None).await.unwrap();
+        let execution_ids: Vec<_> = history
+            .iter()
+            .filter_map(|e| match &e.event_type {
+                Some(EventType::ExecutionStarted(started)) => started
+                    .orchestration_instance
+                    .as_ref()
+                    .and_then(|instance| instance.execution_id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(execution_ids.len(), 3);
+
+        let mut executions = Vec::new();
+        for (i, execution_id) in execution_ids.iter().enumerate() {
+            let events = client
+                .get_orchestration_history(&id, Some(execution_id))
+                .await
+                .unwrap();
+            let started = events
+                .iter()
+                .find_map(|e| match &e.event_type {
+                    Some(EventType::ExecutionStarted(started)) => Some(started),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(started.input.as_deref(), Some(i.to_string().as_str()));
+            let completed = match &events.last().unwrap().event_type {
+                Some(EventType::ExecutionCompleted(completed)) => completed.orchestration_status,
+                e => panic!("unexpected event: {e:?}"),
+            };
+            let expected = if i < 2 {
+                OrchestrationStatus::ContinuedAsNew
+            } else {
+                OrchestrationStatus::Completed
+            };
+            assert_eq!(completed, expected as i32);
+            executions.extend(events);
+        }
+        assert_eq!(executions, history);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_policy_replaces_running_instance() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("waiter", |ctx: OrchestrationContext| async move {
+                let input: u32 = ctx.get_input()?;
+                let stop: String = ctx.wait_for_external_event("stop").await?;
+                Ok::<_, Error>(format!("{stop} {input}"))
+            })
+            .unwrap();
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone());
+        let start = |input: u32| {
+            NewOrchestration::builder()
+                .instance_id(InstanceID("abc".to_string()))
+                .input(&input)
+                .orchestration_id_reuse_policy(OrchestrationIdReusePolicy {
+                    operation_status: vec![OrchestrationStatus::Running as i32],
+                    action: CreateOrchestrationAction::Terminate as i32,
+                })
+        };
+        let id = client
+            .schedule_new_orchestration("waiter", start(1))
+            .await
+            .unwrap();
+        let worker = start_worker(be.clone(), registry, WorkerOptions::builder()).await;
+        wait_for_status(&client, &id, OrchestrationStatus::Running).await;
+
+        client
+            .schedule_new_orchestration("waiter", start(2))
+            .await
+            .unwrap();
+        client
+            .raise_event(
+                &id,
+                "stop",
+                RaiseEventBuilder::new().event_payload(&"stopped"),
+            )
+            .await
+            .unwrap();
+        wait_for_status(&client, &id, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        let state = be.state("abc").unwrap();
+        assert_eq!(state.output().unwrap(), r#""stopped 2""#);
+        let history = client.get_orchestration_history(&id, None).await.unwrap();
+        let statuses: Vec<_> = history
+            .iter()
+            .filter_map(|e| match &e.event_type {
+                Some(EventType::ExecutionCompleted(completed)) => {
+                    Some(completed.orchestration_status())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                OrchestrationStatus::Terminated,
+                OrchestrationStatus::Completed
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_lettered_orchestration_requeued() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("hello", |_ctx: OrchestrationContext| async move {
+                Ok::<_, Error>("done".to_string())
+            })
+            .unwrap();
+        let be = Arc::new(TestBackend::default());
+        be.failing_completions
+            .lock()
+            .unwrap()
+            .insert("abc".to_string());
+        let client = TaskHubClient::new(be.clone());
+        let id = client
+            .schedule_new_orchestration(
+                "hello",
+                NewOrchestration::builder().instance_id(InstanceID("abc".to_string())),
+            )
+            .await
+            .unwrap();
+        let options = WorkerOptions::builder()
+            .max_work_item_retries(2)
+            .orchestration_backoff(Arc::new(
+                backon::ConstantBuilder::default().with_delay(Duration::ZERO),
+            ));
+        let worker = start_worker(be.clone(), registry, options).await;
+        wait_for_status(&client, &id, OrchestrationStatus::Failed).await;
+
+        let dead_letters = client.list_dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].instance_id, id);
+        assert_eq!(dead_letters[0].retry_count, 2);
+        assert_eq!(
+            *be.calls.lock().unwrap(),
+            [
+                "complete_orchestration_work_item",
+                "abandon_orchestration_work_item",
+                "complete_orchestration_work_item",
+                "abandon_orchestration_work_item",
+                "complete_orchestration_work_item",
+                "dead_letter_orchestration_work_item",
+            ]
+        );
+        let metadata = client
+            .fetch_orchestration_metadata(&id, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            metadata.failure_details.unwrap().error_type,
+            "PoisonWorkItem"
+        );
+
+        be.failing_completions.lock().unwrap().clear();
+        client
+            .requeue_dead_letter(&dead_letters[0].id)
+            .await
+            .unwrap();
+        wait_for_status(&client, &id, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        assert!(client.list_dead_letters().await.unwrap().is_empty());
+        assert_eq!(be.state("abc").unwrap().output().unwrap(), r#""done""#);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_messages_relayed_once() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("parent", |ctx: OrchestrationContext| async move {
+                ctx.send_event(&InstanceID("listener".to_string()), "ping", &"hello")?;
+                let output: String = ctx
+                    .call_sub_orchestrator_with_id("child", "child", &1)
+                    .await?;
+                Ok::<_, Error>(output)
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("child", |ctx: OrchestrationContext| async move {
+                let input: u32 = ctx.get_input()?;
+                Ok::<_, Error>(format!("child {input}"))
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("listener", |ctx: OrchestrationContext| async move {
+                let ping: String = ctx.wait_for_external_event("ping").await?;
+                Ok::<_, Error>(ping)
+            })
+            .unwrap();
+        let be = Arc::new(TestBackend::default().with_outbox());
+        let client = TaskHubClient::new(be.clone());
+        let schedule = |name: &'static str| {
+            client.schedule_new_orchestration(
+                name,
+                NewOrchestration::builder().instance_id(InstanceID(name.to_string())),
+            )
+        };
+        let listener = schedule("listener").await.unwrap();
+        let parent = schedule("parent").await.unwrap();
+        let worker = start_worker(be.clone(), registry, WorkerOptions::builder()).await;
+        wait_for_status(&client, &parent, OrchestrationStatus::Completed).await;
+        wait_for_status(&client, &listener, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        let outbox = be.test_outbox().unwrap();
+        assert!(outbox.messages.lock().unwrap().is_empty());
+        // Starting the child, its result and the event
+        let deleted = outbox.deleted.lock().unwrap().clone();
+        assert_eq!(deleted.len(), 3);
+        assert_eq!(deleted.iter().collect::<HashSet<_>>().len(), 3);
+
+        let count = |instance_id: &str, matches: fn(&EventType) -> bool| {
+            let history = be.instances.lock().unwrap()[instance_id].history.clone();
+            history
+                .iter()
+                .filter(|e| e.event_type.as_ref().is_some_and(matches))
+                .count()
+        };
+        assert_eq!(
+            count("child", |e| matches!(e, EventType::ExecutionStarted(_))),
+            1
+        );
+        assert_eq!(
+            count("parent", |e| matches!(
+                e,
+                EventType::SubOrchestrationInstanceCompleted(_)
+            )),
+            1
+        );
+        assert_eq!(
+            count("listener", |e| matches!(e, EventType::EventRaised(_))),
+            1
+        );
+        assert_eq!(
+            be.state("parent").unwrap().output().unwrap(),
+            r#""child 1""#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purged_child_keeps_parent_history() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("parent", |ctx: OrchestrationContext| async move {
+                let output: String = ctx
+                    .call_sub_orchestrator_with_id("child", "child", &"large input")
+                    .await?;
+                Ok::<_, Error>(output)
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("child", |ctx: OrchestrationContext| async move {
+                let input: String = ctx.get_input()?;
+                Ok::<_, Error>(format!("{input} done"))
+            })
+            .unwrap();
+        let root = std::env::temp_dir().join(format!("durabletask-blobs-{}", uuid::Uuid::new_v4()));
+        let offloader = Arc::new(PayloadOffloader::new(
+            Arc::new(FileSystemBlobStore::new(&root)),
+            0,
+        ));
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone()).with_payload_transformer(offloader.clone());
+        let parent = client
+            .schedule_new_orchestration(
+                "parent",
+                NewOrchestration::builder().instance_id(InstanceID("parent".to_string())),
+            )
+            .await
+            .unwrap();
+        let options = WorkerOptions::builder().payload_transformer(offloader);
+        let worker = start_worker(be.clone(), registry, options).await;
+        wait_for_status(&client, &parent, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        let child = InstanceID("child".to_string());
+        assert_eq!(
+            client
+                .purge_orchestration_state(&child, false)
+                .await
+                .unwrap(),
+            1
+        );
+        let payloads = |history: &[HistoryEvent]| {
+            let input = history.iter().find_map(|e| match &e.event_type {
+                Some(EventType::SubOrchestrationInstanceCreated(c)) => c.input.clone(),
+                _ => None,
+            });
+            let result = history.iter().find_map(|e| match &e.event_type {
+                Some(EventType::SubOrchestrationInstanceCompleted(c)) => c.result.clone(),
+                _ => None,
+            });
+            (input.unwrap(), result.unwrap())
+        };
+        let history = client
+            .get_orchestration_history(&parent, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            payloads(&history),
+            (
+                r#""large input""#.to_string(),
+                r#""large input done""#.to_string()
+            )
+        );
+
+        // Blobs that are gone leave their references in place rather than failing the read
+        std::fs::remove_dir_all(&root).unwrap();
+        let history = client
+            .get_orchestration_history(&parent, None)
+            .await
+            .unwrap();
+        let (input, result) = payloads(&history);
+        assert!(PayloadOffloader::is_reference(&input));
+        assert!(PayloadOffloader::is_reference(&result));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_payloads_readable_by_sender_and_receiver() {
+        use crate::payload::encryption::{AesGcmEncryptor, LocalKeyring};
+        use crate::payload::PayloadField;
+
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("parent", |ctx: OrchestrationContext| async move {
+                ctx.send_event(&InstanceID("listener".to_string()), "ping", &"hello")?;
+                let output: String = ctx
+                    .call_sub_orchestrator_with_id("child", "child", &"secret")
+                    .await?;
+                Ok::<_, Error>(output)
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("child", |ctx: OrchestrationContext| async move {
+                let input: String = ctx.get_input()?;
+                Ok::<_, Error>(format!("{input} done"))
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("listener", |ctx: OrchestrationContext| async move {
+                let ping: String = ctx.wait_for_external_event("ping").await?;
+                Ok::<_, Error>(ping)
+            })
+            .unwrap();
+        let keyring = Arc::new(LocalKeyring::new());
+        keyring.add_key("k1", [7; 32]).unwrap();
+        let encryptor = Arc::new(AesGcmEncryptor::new(keyring));
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone()).with_payload_transformer(encryptor.clone());
+        let schedule = |name: &'static str| {
+            client.schedule_new_orchestration(
+                name,
+                NewOrchestration::builder().instance_id(InstanceID(name.to_string())),
+            )
+        };
+        let listener = schedule("listener").await.unwrap();
+        let parent = schedule("parent").await.unwrap();
+        let options = WorkerOptions::builder().payload_transformer(encryptor.clone());
+        let worker = start_worker(be.clone(), registry, options).await;
+        wait_for_status(&client, &parent, OrchestrationStatus::Completed).await;
+        wait_for_status(&client, &listener, OrchestrationStatus::Completed).await;
+        worker.shutdown().await.unwrap();
+
+        // The child's result is shared with its parent, and the event with its receiver
+        let output = |id: &str| be.state(id).unwrap().output().unwrap().to_string();
+        for (reader, id, expected) in [
+            ("child", "child", r#""secret done""#),
+            ("parent", "child", r#""secret done""#),
+            ("parent", "parent", r#""secret done""#),
+            ("listener", "listener", r#""hello""#),
+        ] {
+            let stored = output(id);
+            assert!(stored.starts_with("enc:v1:"));
+            let unsealed = encryptor.unseal(reader, PayloadField::Output, stored);
+            assert_eq!(unsealed.await.unwrap(), expected);
+        }
+        assert!(encryptor
+            .unseal("listener", PayloadField::Output, output("child"))
+            .await
+            .is_err());
+        let history = client
+            .get_orchestration_history(&parent, None)
+            .await
+            .unwrap();
+        let payloads = history
+            .iter()
+            .filter_map(|e| match &e.event_type {
+                Some(EventType::SubOrchestrationInstanceCreated(c)) => c.input.clone(),
+                Some(EventType::EventSent(sent)) => sent.input.clone(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec![r#""hello""#, r#""secret""#]);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_cancels_running_activity() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("caller", |ctx: OrchestrationContext| async move {
+                let output: String = ctx.call_activity("wait", &()).await?;
+                Ok::<_, Error>(output)
+            })
+            .unwrap();
+        let (started_count, cancelled_count) = (started.clone(), cancelled.clone());
+        registry
+            .add_activity("wait", move |ctx: ActivityContext| {
+                let (started, cancelled) = (started_count.clone(), cancelled_count.clone());
+                async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    // Heartbeats report the cancellation to the activity
+                    while !ctx.is_cancelled() {
+                        ctx.heartbeat(&())?;
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                    cancelled.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, Error>("finished".to_string())
+                }
+            })
+            .unwrap();
+        let be = Arc::new(TestBackend::default());
+        let client = TaskHubClient::new(be.clone());
+        let id = client
+            .schedule_new_orchestration("caller", NewOrchestration::builder())
+            .await
+            .unwrap();
+        let wait_until = |count: Arc<AtomicUsize>| async move {
+            let reached = async {
+                while count.load(Ordering::SeqCst) == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), reached)
+                .await
+                .expect("the activity didn't get there");
+        };
+        let worker = start_worker(be.clone(), registry, WorkerOptions::builder()).await;
+        wait_until(started.clone()).await;
+
+        client
+            .terminate_orchestration(&id, TerminateBuilder::new())
+            .await
+            .unwrap();
+        wait_for_status(&client, &id, OrchestrationStatus::Terminated).await;
+        wait_until(cancelled.clone()).await;
+        worker.shutdown().await.unwrap();
+
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert!(be.activities.lock().unwrap().is_empty());
+        // The activity's result is dropped
+        let instances = be.instances.lock().unwrap();
+        let instance = &instances[&id.0];
+        assert!(instance.inbox.is_empty());
+        assert!(!instance.history.iter().any(|e| matches!(
+            e.event_type,
+            Some(EventType::TaskCompleted(_) | EventType::TaskFailed(_))
+        )));
+    }
+}
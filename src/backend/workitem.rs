@@ -14,26 +14,15 @@
   limitations under the License.
 */
 use std::collections::HashMap;
-use std::error::Error;
 use std::fmt;
-use std::time::Duration;
+use std::time::SystemTime;
 
 use crate::api::InstanceID;
+use crate::backend::lock::WorkItemLock;
 use crate::backend::runtimestate::OrchestrationRuntimeState;
 use crate::durabletask_pb::history_event::EventType;
 use crate::durabletask_pb::HistoryEvent;
-
-#[allow(dead_code)] // TODO: Remove
-#[derive(Debug)]
-struct NoWorkItemsError;
-
-impl fmt::Display for NoWorkItemsError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "no work items were found")
-    }
-}
-
-impl Error for NoWorkItemsError {}
+use crate::task::ActivityOptions;
 
 #[allow(dead_code)] // TODO: Remove
 trait WorkItem: fmt::Display {
@@ -42,15 +31,25 @@ trait WorkItem: fmt::Display {
     }
 }
 
-#[allow(dead_code)] // TODO: Remove
 #[derive(Default)]
-pub(crate) struct OrchestrationWorkItem {
+pub struct OrchestrationWorkItem {
     pub instance_id: InstanceID,
     pub new_events: Vec<HistoryEvent>,
+    /// The execution each of the new events is addressed to, if any. Backends that don't track
+    /// it leave this empty, and events are applied to the current execution.
+    pub target_execution_ids: Vec<Option<String>>,
     pub locked_by: String,
+    /// Identifies the fetch that locked the work item, see [`WorkItemLock::lock_token`].
+    pub lock_token: String,
+    /// When the lock of `locked_by` expires, for backends that lease work items.
+    pub lock_expires_at: Option<SystemTime>,
     pub retry_count: i32,
     pub state: OrchestrationRuntimeState,
-    pub properties: HashMap<String, Box<dyn std::any::Any>>,
+    /// The number of committed history events, for backends that know it when locking the
+    /// instance. When set, a worker's cached session is only reused if its history has the same
+    /// length; otherwise the worker assumes no other worker processed the instance meanwhile.
+    pub history_length: Option<usize>,
+    pub properties: HashMap<String, Box<dyn std::any::Any + Send + Sync>>,
 }
 
 impl fmt::Display for OrchestrationWorkItem {
@@ -61,25 +60,41 @@ impl fmt::Display for OrchestrationWorkItem {
 
 impl WorkItem for OrchestrationWorkItem {}
 
-#[allow(dead_code)] // TODO: Remove
 impl OrchestrationWorkItem {
-    fn abandon_delay(&self) -> Duration {
-        match self.retry_count {
-            0 => Duration::from_secs(0),
-            retry_count if retry_count > 100 => Duration::from_secs(5 * 60),
-            retry_count => Duration::from_secs(retry_count as u64),
-        }
+    /// The lease held on the work item, if the backend expires its locks.
+    pub(crate) fn lock(&self) -> Option<WorkItemLock> {
+        Some(WorkItemLock {
+            instance_id: self.instance_id.clone(),
+            sequence_number: None,
+            locked_by: self.locked_by.clone(),
+            lock_token: self.lock_token.clone(),
+            expires_at: self.lock_expires_at?,
+        })
     }
 }
 
-#[allow(dead_code)] // TODO: Remove
-pub(crate) struct ActivityWorkItem {
+pub struct ActivityWorkItem {
     pub sequence_number: i64,
     pub instance_id: InstanceID,
+    /// The execution that scheduled the activity. Its result is addressed to this execution.
+    pub execution_id: Option<String>,
     pub new_event: HistoryEvent,
     pub result: Option<HistoryEvent>,
     pub locked_by: String,
-    pub properties: HashMap<String, Box<dyn std::any::Any>>,
+    /// Identifies the fetch that locked the work item, see [`WorkItemLock::lock_token`].
+    pub lock_token: String,
+    /// When the lock of `locked_by` expires, for backends that lease work items.
+    pub lock_expires_at: Option<SystemTime>,
+    pub retry_count: i32,
+    /// How many times the work item was fetched before, not counting fetches that were
+    /// deferred. Unlike `retry_count`, this includes attempts whose lease expired.
+    pub dispatch_count: i32,
+    /// The options the activity was scheduled with, as saved from the commit that scheduled it.
+    pub options: ActivityOptions,
+    /// The details of the last heartbeat of a previous attempt, see
+    /// [`Backend::record_activity_heartbeat`](crate::backend::Backend::record_activity_heartbeat).
+    pub heartbeat_details: Option<String>,
+    pub properties: HashMap<String, Box<dyn std::any::Any + Send + Sync>>,
 }
 
 impl fmt::Display for ActivityWorkItem {
@@ -94,3 +109,16 @@ impl fmt::Display for ActivityWorkItem {
 }
 
 impl WorkItem for ActivityWorkItem {}
+
+impl ActivityWorkItem {
+    /// The lease held on the work item, if the backend expires its locks.
+    pub(crate) fn lock(&self) -> Option<WorkItemLock> {
+        Some(WorkItemLock {
+            instance_id: self.instance_id.clone(),
+            sequence_number: Some(self.sequence_number),
+            locked_by: self.locked_by.clone(),
+            lock_token: self.lock_token.clone(),
+            expires_at: self.lock_expires_at?,
+        })
+    }
+}
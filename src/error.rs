@@ -0,0 +1,236 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::error::Error as StdError;
+use std::fmt;
+
+use tonic::{Code, Status};
+
+use crate::backend::BackendError;
+use crate::durabletask_pb::TaskFailureDetails;
+
+/// Errors returned by the durabletask client, worker and runtime state.
+#[derive(Debug)]
+pub enum Error {
+    /// No orchestration instance with the requested ID exists.
+    InstanceNotFound,
+    /// The orchestration has no `ExecutionStarted` event yet.
+    NotStarted,
+    /// The orchestration hasn't reached a terminal state.
+    NotCompleted,
+    /// The orchestration completed without reporting failure details.
+    NoFailures,
+    /// An orchestration instance with the requested ID already exists.
+    DuplicateInstance,
+    /// The reuse policy chose to keep the existing instance instead of creating a new one.
+    IgnoreInstance,
+    /// No schedule with the requested ID exists.
+    ScheduleNotFound,
+    /// A schedule with the requested ID already exists.
+    ScheduleExists,
+    /// No dead-lettered work item with the requested ID exists.
+    DeadLetterNotFound,
+    /// The orchestration completed with a failure.
+    OrchestrationFailed(TaskFailureDetails),
+    /// An activity or other durable task scheduled by an orchestrator failed.
+    TaskFailed(TaskFailureDetails),
+    /// An orchestrator stopped waiting for something, e.g. an external event, after its timeout.
+    Timeout(String),
+    /// Work was cancelled before it finished, e.g. because the worker shut down.
+    Cancelled,
+    /// A request or builder was missing a required value or had an invalid one.
+    InvalidArgument(String),
+    /// The orchestration history or actions are inconsistent.
+    InvalidHistory(String),
+    /// A payload could not be serialized or deserialized.
+    Serialization(Box<dyn StdError + Send + Sync>),
+    /// A payload could not be encrypted or decrypted.
+    Encryption(String),
+    /// The gRPC transport or the remote sidecar returned an error.
+    Transport(Box<Status>),
+    /// The storage backend returned an error.
+    Backend(Box<dyn StdError + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InstanceNotFound => write!(f, "no such instance exists"),
+            Error::NotStarted => write!(f, "orchestration has not started"),
+            Error::NotCompleted => write!(f, "orchestration has not yet completed"),
+            Error::NoFailures => write!(f, "orchestration did not report failure details"),
+            Error::DuplicateInstance => write!(f, "orchestration instance already exists"),
+            Error::IgnoreInstance => write!(f, "ignore creating orchestration instance"),
+            Error::ScheduleNotFound => write!(f, "no such schedule exists"),
+            Error::ScheduleExists => write!(f, "schedule already exists"),
+            Error::DeadLetterNotFound => write!(f, "no such dead letter exists"),
+            Error::OrchestrationFailed(details) => write!(
+                f,
+                "orchestration failed: {}: {}",
+                details.error_type, details.error_message
+            ),
+            Error::TaskFailed(details) => write!(
+                f,
+                "task failed: {}: {}",
+                details.error_type, details.error_message
+            ),
+            Error::Timeout(message) => write!(f, "timed out: {}", message),
+            Error::Cancelled => write!(f, "operation was cancelled"),
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::InvalidHistory(message) => write!(f, "invalid history: {}", message),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::Encryption(message) => write!(f, "encryption error: {}", message),
+            Error::Transport(status) => write!(f, "transport error: {}", status),
+            Error::Backend(e) => write!(f, "backend error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Serialization(e) | Error::Backend(e) => Some(e.as_ref()),
+            Error::Transport(status) => Some(status.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<BackendError> for Error {
+    fn from(error: BackendError) -> Self {
+        match error {
+            BackendError::DuplicateInstance => Error::DuplicateInstance,
+            BackendError::IgnoreInstance => Error::IgnoreInstance,
+            e => Error::Backend(Box::new(e)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Serialization(Box::new(error))
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(error: tonic::transport::Error) -> Self {
+        Error::Transport(Box::new(Status::unavailable(error.to_string())))
+    }
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        match status.code() {
+            Code::NotFound => Error::InstanceNotFound,
+            Code::AlreadyExists => Error::DuplicateInstance,
+            Code::InvalidArgument => Error::InvalidArgument(status.message().to_string()),
+            _ => Error::Transport(Box::new(status)),
+        }
+    }
+}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        let message = error.to_string();
+        match error {
+            Error::InstanceNotFound | Error::ScheduleNotFound | Error::DeadLetterNotFound => {
+                Status::not_found(message)
+            }
+            Error::DuplicateInstance | Error::ScheduleExists => Status::already_exists(message),
+            Error::NotStarted | Error::NotCompleted | Error::NoFailures => {
+                Status::failed_precondition(message)
+            }
+            Error::InvalidArgument(_) | Error::Serialization(_) => {
+                Status::invalid_argument(message)
+            }
+            Error::Transport(status) => *status,
+            Error::Timeout(_) => Status::deadline_exceeded(message),
+            Error::Cancelled => Status::cancelled(message),
+            Error::IgnoreInstance
+            | Error::OrchestrationFailed(_)
+            | Error::TaskFailed(_)
+            | Error::Encryption(_)
+            | Error::InvalidHistory(_)
+            | Error::Backend(_) => Status::internal(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_to_status() {
+        assert_eq!(Status::from(Error::InstanceNotFound).code(), Code::NotFound);
+        assert_eq!(
+            Status::from(Error::DuplicateInstance).code(),
+            Code::AlreadyExists
+        );
+        assert_eq!(
+            Status::from(Error::NotCompleted).code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            Status::from(Error::InvalidArgument("name is required".to_string())).code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            Status::from(Error::Backend("disk full".into())).code(),
+            Code::Internal
+        );
+    }
+
+    #[test]
+    fn test_status_to_error() {
+        assert!(matches!(
+            Error::from(Status::not_found("missing")),
+            Error::InstanceNotFound
+        ));
+        assert!(matches!(
+            Error::from(Status::already_exists("exists")),
+            Error::DuplicateInstance
+        ));
+        match Error::from(Status::unavailable("sidecar down")) {
+            Error::Transport(status) => assert_eq!(status.code(), Code::Unavailable),
+            e => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_backend_error_conversion() {
+        assert!(matches!(
+            Error::from(BackendError::IgnoreInstance),
+            Error::IgnoreInstance
+        ));
+        assert!(matches!(
+            Error::from(BackendError::TaskHubNotFound),
+            Error::Backend(_)
+        ));
+    }
+
+    #[test]
+    fn test_orchestration_failed_display() {
+        let error = Error::OrchestrationFailed(TaskFailureDetails {
+            error_type: "TimeoutError".to_string(),
+            error_message: "took too long".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            error.to_string(),
+            "orchestration failed: TimeoutError: took too long"
+        );
+    }
+}
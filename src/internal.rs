@@ -33,11 +33,13 @@ use crate::durabletask_pb::{
     history_event::EventType, orchestrator_action::OrchestratorActionType,
     CompleteOrchestrationAction, CreateSubOrchestrationAction, CreateTimerAction, EventRaisedEvent,
     EventSentEvent, ExecutionCompletedEvent, ExecutionResumedEvent, ExecutionStartedEvent,
-    ExecutionSuspendedEvent, ExecutionTerminatedEvent, HistoryEvent, OrchestrationInstance,
-    OrchestrationStatus, OrchestratorAction, OrchestratorStartedEvent, ParentInstanceInfo,
-    ScheduleTaskAction, SendEventAction, SubOrchestrationInstanceCreatedEvent, TaskCompletedEvent,
-    TaskFailedEvent, TaskFailureDetails, TaskScheduledEvent, TerminateOrchestrationAction,
-    TimerCreatedEvent, TimerFiredEvent, TraceContext,
+    ExecutionSuspendedEvent, ExecutionTerminatedEvent, HistoryEvent, HistoryStateEvent,
+    OrchestrationInstance, OrchestrationState, OrchestrationStatus, OrchestratorAction,
+    OrchestratorStartedEvent, ParentInstanceInfo, ScheduleTaskAction, SendEventAction,
+    SubOrchestrationInstanceCompletedEvent, SubOrchestrationInstanceCreatedEvent,
+    SubOrchestrationInstanceFailedEvent, TaskCompletedEvent, TaskFailedEvent, TaskFailureDetails,
+    TaskScheduledEvent, TerminateOrchestrationAction, TimerCreatedEvent, TimerFiredEvent,
+    TraceContext,
 };
 
 pub(crate) fn new_execution_started_event(
@@ -201,6 +203,43 @@ pub(crate) fn new_sub_orchestration_created_event(
     }
 }
 
+pub(crate) fn new_sub_orchestration_completed_event(
+    task_id: i32,
+    result: Option<&str>,
+) -> HistoryEvent {
+    HistoryEvent {
+        event_id: -1,
+        timestamp: Some(Timestamp::from(SystemTime::now())),
+        event_type: Some(EventType::SubOrchestrationInstanceCompleted(
+            SubOrchestrationInstanceCompletedEvent {
+                task_scheduled_id: task_id,
+                result: result.map(str::to_string),
+            },
+        )),
+    }
+}
+
+pub(crate) fn new_sub_orchestration_failed_event(
+    task_id: i32,
+    failure_details: Option<&TaskFailureDetails>,
+) -> HistoryEvent {
+    HistoryEvent {
+        event_id: -1,
+        timestamp: Some(Timestamp::from(SystemTime::now())),
+        event_type: Some(EventType::SubOrchestrationInstanceFailed(
+            SubOrchestrationInstanceFailedEvent {
+                task_scheduled_id: task_id,
+                failure_details: failure_details.cloned(),
+            },
+        )),
+    }
+}
+
+/// The default instance ID of the sub-orchestration an orchestration creates with `task_id`.
+pub(crate) fn sub_orchestration_instance_id(parent_instance_id: &str, task_id: i32) -> String {
+    format!("{}:{:04x}", parent_instance_id, task_id)
+}
+
 pub(crate) fn new_event_sent_event(
     event_id: i32,
     instance_id: &str,
@@ -238,13 +277,28 @@ pub(crate) fn new_resume_orchestration_event(reason: Option<&str>) -> HistoryEve
     }
 }
 
-pub(crate) fn new_parent_info(task_id: i32, name: &str, instance_id: &str) -> ParentInstanceInfo {
+pub(crate) fn new_history_state_event(state: OrchestrationState) -> HistoryEvent {
+    HistoryEvent {
+        event_id: -1,
+        timestamp: Some(Timestamp::from(SystemTime::now())),
+        event_type: Some(EventType::HistoryState(HistoryStateEvent {
+            orchestration_state: Some(state),
+        })),
+    }
+}
+
+pub(crate) fn new_parent_info(
+    task_id: i32,
+    name: &str,
+    instance_id: &str,
+    execution_id: Option<&str>,
+) -> ParentInstanceInfo {
     ParentInstanceInfo {
         task_scheduled_id: task_id,
         name: Some(name.to_string()),
         orchestration_instance: Some(OrchestrationInstance {
             instance_id: instance_id.to_string(),
-            ..Default::default()
+            execution_id: execution_id.map(str::to_string),
         }),
         ..Default::default()
     }
@@ -275,12 +329,13 @@ pub(crate) fn new_create_timer_action(task_id: i32, fire_at: &Timestamp) -> Orch
 }
 
 pub(crate) fn new_send_event_action(
+    task_id: i32,
     instance_id: &str,
     name: &str,
     data: Option<&str>,
 ) -> OrchestratorAction {
     OrchestratorAction {
-        id: -1,
+        id: task_id,
         orchestrator_action_type: Some(OrchestratorActionType::SendEvent(SendEventAction {
             instance: Some(OrchestrationInstance {
                 instance_id: instance_id.to_string(),
@@ -15,7 +15,12 @@
 */
 pub mod api;
 pub mod backend;
+mod error;
 mod internal;
+pub mod payload;
+pub mod task;
+
+pub use error::Error;
 
 #[path = "genproto/microsoft.durabletask.implementation.protobuf.rs"]
 pub mod durabletask_pb;
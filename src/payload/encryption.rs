@@ -0,0 +1,418 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+//! AES-256-GCM encryption of payloads at rest.
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+
+use crate::payload::{decode_base64, encode_base64, PayloadField, PayloadTransformer};
+use crate::Error;
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// Supplies the keys used by an [`AesGcmEncryptor`].
+pub trait KeyProvider: Send + Sync {
+    /// The ID of the key new payloads are encrypted with. IDs can't contain `:`.
+    fn current_key_id(&self) -> Result<String, Error>;
+    /// Looks up a 256-bit key by ID, including keys that have been rotated out.
+    fn key(&self, key_id: &str) -> Result<[u8; 32], Error>;
+}
+
+#[derive(Default)]
+struct Keyring {
+    keys: HashMap<String, [u8; 32]>,
+    current: Option<String>,
+}
+
+/// An in-memory [`KeyProvider`] for tests and local development.
+#[derive(Default)]
+pub struct LocalKeyring {
+    inner: RwLock<Keyring>,
+}
+
+impl LocalKeyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key that can decrypt payloads. The first key added becomes the current key.
+    pub fn add_key(&self, key_id: &str, key: [u8; 32]) -> Result<(), Error> {
+        if key_id.is_empty() || key_id.contains(':') {
+            return Err(Error::InvalidArgument(format!("invalid key ID '{key_id}'")));
+        }
+        let mut keyring = self.inner.write().unwrap();
+        keyring.keys.insert(key_id.to_string(), key);
+        keyring.current.get_or_insert_with(|| key_id.to_string());
+        Ok(())
+    }
+
+    /// Adds a key and encrypts new payloads with it. Existing payloads stay readable as long
+    /// as their keys aren't removed.
+    pub fn rotate(&self, key_id: &str, key: [u8; 32]) -> Result<(), Error> {
+        self.add_key(key_id, key)?;
+        self.inner.write().unwrap().current = Some(key_id.to_string());
+        Ok(())
+    }
+
+    pub fn remove_key(&self, key_id: &str) {
+        let mut keyring = self.inner.write().unwrap();
+        keyring.keys.remove(key_id);
+        if keyring.current.as_deref() == Some(key_id) {
+            keyring.current = None;
+        }
+    }
+}
+
+impl KeyProvider for LocalKeyring {
+    fn current_key_id(&self) -> Result<String, Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .current
+            .clone()
+            .ok_or_else(|| Error::Encryption("no current encryption key".to_string()))
+    }
+
+    fn key(&self, key_id: &str) -> Result<[u8; 32], Error> {
+        self.inner
+            .read()
+            .unwrap()
+            .keys
+            .get(key_id)
+            .copied()
+            .ok_or_else(|| Error::Encryption(format!("unknown encryption key '{key_id}'")))
+    }
+}
+
+/// Encrypts payloads with AES-256-GCM.
+///
+/// Sealed payloads look like `enc:v1:<key id>:<readers>:<base64 nonce and ciphertext>`, so
+/// they can be decrypted after the current key is rotated. The readers are the base64 IDs of
+/// the instances that can read the payload, separated by `,`: the one it was sealed for, and
+/// its sender if it's shared. The key ID, readers and [field](PayloadField) are authenticated
+/// along with the payload, so it can't be moved to another instance or field.
+///
+/// Payloads without the prefix are rejected unless
+/// [`with_plaintext_migration`](Self::with_plaintext_migration) is set. Failure messages are
+/// always accepted, as failures the framework reports itself aren't encrypted.
+pub struct AesGcmEncryptor {
+    keys: Arc<dyn KeyProvider>,
+    plaintext_migration: bool,
+}
+
+impl AesGcmEncryptor {
+    pub fn new(keys: Arc<dyn KeyProvider>) -> Self {
+        AesGcmEncryptor {
+            keys,
+            plaintext_migration: false,
+        }
+    }
+
+    /// Accepts payloads without the prefix as plain text, e.g. ones written before encryption
+    /// was enabled, while their instances are migrated.
+    pub fn with_plaintext_migration(mut self) -> Self {
+        self.plaintext_migration = true;
+        self
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<Aes256Gcm, Error> {
+        let key = self.keys.key(key_id)?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    fn encrypt(
+        &self,
+        readers: &[&str],
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        let key_id = self.keys.current_key_id()?;
+        if key_id.contains(':') {
+            return Err(Error::Encryption(format!("invalid key ID '{key_id}'")));
+        }
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(&key_id)?
+            .encrypt(
+                &nonce,
+                aes_gcm::aead::Payload {
+                    msg: payload.as_bytes(),
+                    aad: &associated_data(&key_id, field, readers),
+                },
+            )
+            .map_err(|e| Error::Encryption(e.to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        let readers = readers
+            .iter()
+            .map(|reader| encode_base64(reader.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(format!(
+            "{PREFIX}{key_id}:{readers}:{}",
+            encode_base64(&sealed)
+        ))
+    }
+}
+
+/// The data authenticated along with a payload. Each part is length-prefixed, so no two
+/// contexts share an encoding.
+fn associated_data(key_id: &str, field: PayloadField, readers: &[&str]) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for part in [key_id, field.as_str()].iter().chain(readers) {
+        aad.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        aad.extend_from_slice(part.as_bytes());
+    }
+    aad
+}
+
+fn malformed() -> Error {
+    Error::Encryption("malformed encrypted payload".to_string())
+}
+
+#[async_trait]
+impl PayloadTransformer for AesGcmEncryptor {
+    async fn seal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        self.encrypt(&[instance_id], field, payload)
+    }
+
+    async fn seal_shared(
+        &self,
+        instance_id: &str,
+        sender_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        self.encrypt(&[instance_id, sender_id], field, payload)
+    }
+
+    async fn unseal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        let Some(sealed) = payload.strip_prefix(PREFIX) else {
+            if self.plaintext_migration || field == PayloadField::Failure {
+                return Ok(payload);
+            }
+            return Err(Error::Encryption(format!(
+                "the {} payload of instance '{instance_id}' isn't encrypted",
+                field.as_str()
+            )));
+        };
+        let mut parts = sealed.splitn(3, ':');
+        let (Some(key_id), Some(readers), Some(data)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(malformed());
+        };
+        let readers = readers
+            .split(',')
+            .map(|reader| {
+                let reader = decode_base64(reader).map_err(|_| malformed())?;
+                String::from_utf8(reader).map_err(|_| malformed())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        if !readers.iter().any(|reader| reader == instance_id) {
+            return Err(Error::Encryption(format!(
+                "payload wasn't encrypted for instance '{instance_id}'"
+            )));
+        }
+        let data = decode_base64(data)?;
+        if data.len() < NONCE_LEN {
+            return Err(malformed());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        let readers = readers.iter().map(String::as_str).collect::<Vec<_>>();
+        let plaintext = self
+            .cipher(key_id)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                aes_gcm::aead::Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(key_id, field, &readers),
+                },
+            )
+            .map_err(|_| Error::Encryption(format!("failed to decrypt with key '{key_id}'")))?;
+        String::from_utf8(plaintext).map_err(|e| Error::Encryption(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::PayloadField::{EventData, Failure, Input, Output};
+
+    fn encryptor() -> (Arc<LocalKeyring>, AesGcmEncryptor) {
+        let keyring = Arc::new(LocalKeyring::new());
+        keyring.add_key("k1", [1; 32]).unwrap();
+        (keyring.clone(), AesGcmEncryptor::new(keyring))
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_roundtrip() {
+        let (_, encryptor) = encryptor();
+        let sealed = encryptor
+            .seal("abc", Input, r#"{"ssn":"123"}"#.to_string())
+            .await
+            .unwrap();
+        assert!(sealed.starts_with("enc:v1:k1:"));
+        assert!(!sealed.contains("ssn"));
+        assert_eq!(
+            encryptor.unseal("abc", Input, sealed).await.unwrap(),
+            r#"{"ssn":"123"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plain_text_rejected_unless_migrating() {
+        let (keyring, encryptor) = encryptor();
+        assert!(matches!(
+            encryptor.unseal("abc", Input, "42".to_string()).await,
+            Err(Error::Encryption(_))
+        ));
+        // Failures reported by the framework itself aren't encrypted
+        assert_eq!(
+            encryptor
+                .unseal("abc", Failure, "timed out".to_string())
+                .await
+                .unwrap(),
+            "timed out"
+        );
+
+        let migrating = AesGcmEncryptor::new(keyring).with_plaintext_migration();
+        assert_eq!(
+            migrating
+                .unseal("abc", Input, "42".to_string())
+                .await
+                .unwrap(),
+            "42"
+        );
+        let sealed = migrating
+            .seal("abc", Input, "42".to_string())
+            .await
+            .unwrap();
+        assert!(sealed.starts_with("enc:v1:"));
+    }
+
+    #[tokio::test]
+    async fn test_payload_bound_to_instance_and_field() {
+        let (_, encryptor) = encryptor();
+        let sealed = encryptor
+            .seal("abc", Input, "secret".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            encryptor.unseal("xyz", Input, sealed.clone()).await,
+            Err(Error::Encryption(_))
+        ));
+        assert!(matches!(
+            encryptor.unseal("abc", Output, sealed.clone()).await,
+            Err(Error::Encryption(_))
+        ));
+
+        // Claiming another reader fails authentication
+        let (prefix, data) = sealed.rsplit_once(':').unwrap();
+        let readers = format!("{},{}", encode_base64(b"abc"), encode_base64(b"xyz"));
+        let relabelled = format!("{}:{readers}:{data}", prefix.rsplit_once(':').unwrap().0);
+        assert!(matches!(
+            encryptor.unseal("xyz", Input, relabelled).await,
+            Err(Error::Encryption(_))
+        ));
+
+        // A shared payload can be read by both its receiver and its sender
+        let shared = encryptor
+            .seal_shared("child", "parent", EventData, "hello".to_string())
+            .await
+            .unwrap();
+        for instance_id in ["child", "parent"] {
+            assert_eq!(
+                encryptor
+                    .unseal(instance_id, EventData, shared.clone())
+                    .await
+                    .unwrap(),
+                "hello"
+            );
+        }
+        assert!(encryptor.unseal("other", EventData, shared).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let (keyring, encryptor) = encryptor();
+        let old = encryptor
+            .seal("abc", Input, "old".to_string())
+            .await
+            .unwrap();
+
+        keyring.rotate("k2", [2; 32]).unwrap();
+        let new = encryptor
+            .seal("abc", Input, "new".to_string())
+            .await
+            .unwrap();
+        assert!(new.starts_with("enc:v1:k2:"));
+        assert_eq!(
+            encryptor.unseal("abc", Input, old.clone()).await.unwrap(),
+            "old"
+        );
+        assert_eq!(encryptor.unseal("abc", Input, new).await.unwrap(), "new");
+
+        keyring.remove_key("k1");
+        assert!(matches!(
+            encryptor.unseal("abc", Input, old).await,
+            Err(Error::Encryption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_payload_is_rejected() {
+        let (keyring, encryptor) = encryptor();
+        keyring.add_key("k2", [2; 32]).unwrap();
+        let sealed = encryptor
+            .seal("abc", Input, "secret".to_string())
+            .await
+            .unwrap();
+
+        // Relabelling the key ID fails authentication
+        let relabelled = sealed.replacen("k1", "k2", 1);
+        assert!(matches!(
+            encryptor.unseal("abc", Input, relabelled).await,
+            Err(Error::Encryption(_))
+        ));
+        let readers = encode_base64(b"abc");
+        for malformed in [
+            format!("enc:v1:k1:{readers}:AAAA"),
+            "enc:v1:k1:AAAA".to_string(),
+        ] {
+            assert!(matches!(
+                encryptor.unseal("abc", Input, malformed).await,
+                Err(Error::Encryption(_))
+            ));
+        }
+        assert!(keyring.add_key("a:b", [3; 32]).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+//! Mutable access to the payloads carried by history events and orchestrator actions.
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::orchestrator_action::OrchestratorActionType;
+use crate::durabletask_pb::{
+    HistoryEvent, OrchestrationStatus, OrchestratorAction, TaskFailureDetails,
+};
+use crate::payload::PayloadField::{self, *};
+
+/// Returns the inputs, outputs, event data and failure messages of `e`, with their kinds.
+pub(crate) fn event_payloads_mut(e: &mut HistoryEvent) -> Vec<(PayloadField, &mut String)> {
+    let mut payloads = Vec::new();
+    match &mut e.event_type {
+        Some(EventType::ExecutionStarted(started)) => {
+            payloads.extend(tagged(Input, started.input.as_mut()))
+        }
+        Some(EventType::ExecutionCompleted(completed)) => {
+            let field = result_field(completed.orchestration_status());
+            payloads.extend(tagged(field, completed.result.as_mut()));
+            payloads.extend(failure_payloads_mut(completed.failure_details.as_mut()));
+        }
+        Some(EventType::ExecutionTerminated(terminated)) => {
+            payloads.extend(tagged(Reason, terminated.input.as_mut()))
+        }
+        Some(EventType::TaskScheduled(scheduled)) => {
+            payloads.extend(tagged(Input, scheduled.input.as_mut()))
+        }
+        Some(EventType::TaskCompleted(completed)) => {
+            payloads.extend(tagged(Output, completed.result.as_mut()))
+        }
+        Some(EventType::TaskFailed(failed)) => {
+            payloads.extend(failure_payloads_mut(failed.failure_details.as_mut()))
+        }
+        Some(EventType::SubOrchestrationInstanceCreated(created)) => {
+            payloads.extend(tagged(Input, created.input.as_mut()))
+        }
+        Some(EventType::SubOrchestrationInstanceCompleted(completed)) => {
+            payloads.extend(tagged(Output, completed.result.as_mut()))
+        }
+        Some(EventType::SubOrchestrationInstanceFailed(failed)) => {
+            payloads.extend(failure_payloads_mut(failed.failure_details.as_mut()))
+        }
+        Some(EventType::EventSent(sent)) => payloads.extend(tagged(EventData, sent.input.as_mut())),
+        Some(EventType::EventRaised(raised)) => {
+            payloads.extend(tagged(EventData, raised.input.as_mut()))
+        }
+        Some(EventType::GenericEvent(generic)) => {
+            payloads.extend(tagged(EventData, generic.data.as_mut()))
+        }
+        Some(EventType::HistoryState(history_state)) => {
+            if let Some(state) = history_state.orchestration_state.as_mut() {
+                payloads.extend(tagged(Input, state.input.as_mut()));
+                payloads.extend(tagged(Output, state.output.as_mut()));
+                payloads.extend(tagged(CustomStatus, state.custom_status.as_mut()));
+                payloads.extend(failure_payloads_mut(state.failure_details.as_mut()));
+            }
+        }
+        Some(EventType::ContinueAsNew(continued)) => {
+            payloads.extend(tagged(Input, continued.input.as_mut()))
+        }
+        Some(EventType::ExecutionSuspended(suspended)) => {
+            payloads.extend(tagged(Reason, suspended.input.as_mut()))
+        }
+        Some(EventType::ExecutionResumed(resumed)) => {
+            payloads.extend(tagged(Reason, resumed.input.as_mut()))
+        }
+        Some(EventType::TimerCreated(_))
+        | Some(EventType::TimerFired(_))
+        | Some(EventType::OrchestratorStarted(_))
+        | Some(EventType::OrchestratorCompleted(_))
+        | None => {}
+    }
+    payloads
+}
+
+/// Returns the payloads of `action`, including those of any carried-over events.
+pub(crate) fn action_payloads_mut(
+    action: &mut OrchestratorAction,
+) -> Vec<(PayloadField, &mut String)> {
+    let mut payloads = Vec::new();
+    match &mut action.orchestrator_action_type {
+        Some(OrchestratorActionType::ScheduleTask(task)) => {
+            payloads.extend(tagged(Input, task.input.as_mut()))
+        }
+        Some(OrchestratorActionType::CreateSubOrchestration(create)) => {
+            payloads.extend(tagged(Input, create.input.as_mut()))
+        }
+        Some(OrchestratorActionType::SendEvent(send)) => {
+            payloads.extend(tagged(EventData, send.data.as_mut()))
+        }
+        Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+            let field = result_field(complete.orchestration_status());
+            payloads.extend(tagged(field, complete.result.as_mut()));
+            payloads.extend(tagged(Output, complete.details.as_mut()));
+            payloads.extend(failure_payloads_mut(complete.failure_details.as_mut()));
+            for e in complete.carryover_events.iter_mut() {
+                payloads.extend(event_payloads_mut(e));
+            }
+        }
+        Some(OrchestratorActionType::TerminateOrchestration(terminate)) => {
+            payloads.extend(tagged(Reason, terminate.reason.as_mut()))
+        }
+        Some(OrchestratorActionType::CreateTimer(_)) | None => {}
+    }
+    payloads
+}
+
+fn tagged(
+    field: PayloadField,
+    payload: Option<&mut String>,
+) -> Option<(PayloadField, &mut String)> {
+    payload.map(|payload| (field, payload))
+}
+
+/// The kind of an orchestration's result, which is the next execution's input when it
+/// continues as new.
+fn result_field(status: OrchestrationStatus) -> PayloadField {
+    match status {
+        OrchestrationStatus::ContinuedAsNew => Input,
+        _ => Output,
+    }
+}
+
+/// Returns the ID of the instance whose history receives the payloads of `action`, taken by
+/// `instance_id`. A completed sub-orchestration's result is delivered to its parent.
+pub(crate) fn action_payload_reader(
+    action: &OrchestratorAction,
+    instance_id: &str,
+    parent_instance_id: Option<&str>,
+) -> String {
+    let reader = match &action.orchestrator_action_type {
+        Some(OrchestratorActionType::CreateSubOrchestration(create)) => &create.instance_id,
+        Some(OrchestratorActionType::SendEvent(send)) => send
+            .instance
+            .as_ref()
+            .map_or(instance_id, |instance| &instance.instance_id),
+        Some(OrchestratorActionType::TerminateOrchestration(terminate)) => &terminate.instance_id,
+        Some(OrchestratorActionType::CompleteOrchestration(complete))
+            if complete.orchestration_status() != OrchestrationStatus::ContinuedAsNew =>
+        {
+            parent_instance_id.unwrap_or(instance_id)
+        }
+        _ => instance_id,
+    };
+    reader.to_string()
+}
+
+/// Returns the messages and stack traces of `details` and its inner failures. Error types
+/// are left alone so they can still be matched on.
+pub(crate) fn failure_payloads_mut(
+    details: Option<&mut TaskFailureDetails>,
+) -> Vec<(PayloadField, &mut String)> {
+    let mut payloads = Vec::new();
+    let mut next = details;
+    while let Some(details) = next {
+        payloads.push((Failure, &mut details.error_message));
+        payloads.extend(details.stack_trace.as_mut().map(|trace| (Failure, trace)));
+        next = details.inner_failure.as_deref_mut();
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::{
+        new_complete_orchestration_action, new_event_raised_event, new_task_failed_event,
+        new_timer_created_event,
+    };
+
+    use super::*;
+
+    fn owned(payloads: Vec<(PayloadField, &mut String)>) -> Vec<(PayloadField, String)> {
+        payloads
+            .into_iter()
+            .map(|(field, payload)| (field, payload.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_event_payloads() {
+        let mut raised = new_event_raised_event("approval", Some("yes"));
+        assert_eq!(
+            owned(event_payloads_mut(&mut raised)),
+            vec![(EventData, "yes".to_string())]
+        );
+
+        let details = TaskFailureDetails {
+            error_type: "Error".to_string(),
+            error_message: "outer".to_string(),
+            inner_failure: Some(Box::new(TaskFailureDetails {
+                error_message: "inner".to_string(),
+                stack_trace: Some("trace".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut failed = new_task_failed_event(1, Some(&details));
+        assert_eq!(
+            owned(event_payloads_mut(&mut failed)),
+            vec![
+                (Failure, "outer".to_string()),
+                (Failure, "inner".to_string()),
+                (Failure, "trace".to_string())
+            ]
+        );
+
+        let mut timer = new_timer_created_event(1, &Default::default());
+        assert!(event_payloads_mut(&mut timer).is_empty());
+    }
+
+    #[test]
+    fn test_action_payloads_include_carryover_events() {
+        let mut action = new_complete_orchestration_action(
+            1,
+            OrchestrationStatus::ContinuedAsNew,
+            Some("next"),
+            &[new_event_raised_event("approval", Some("yes"))],
+            None,
+        );
+        for (_, payload) in action_payloads_mut(&mut action) {
+            payload.make_ascii_uppercase();
+        }
+        // The result of an execution that continued as new is the next one's input
+        assert_eq!(
+            owned(action_payloads_mut(&mut action)),
+            vec![(Input, "NEXT".to_string()), (EventData, "YES".to_string())]
+        );
+    }
+}
@@ -0,0 +1,534 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::durabletask_pb::{HistoryEvent, OrchestratorAction, TaskFailureDetails};
+use crate::Error;
+
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub(crate) mod fields;
+pub mod store;
+
+/// Converts orchestration inputs, outputs, activity payloads, event data and custom
+/// status to and from the string payloads stored in history.
+///
+/// Values pass through a [`serde_json::Value`] so codecs can be used as trait objects and
+/// swapped at runtime on the client and worker.
+pub trait PayloadCodec: Send + Sync {
+    /// A short name identifying the encoding, e.g. `json`.
+    fn name(&self) -> &'static str;
+    fn encode(&self, value: &Value) -> Result<String, Error>;
+    fn decode(&self, payload: &str) -> Result<Value, Error>;
+}
+
+/// Serializes `value` with `codec`. Unit and `None` values produce no payload.
+pub fn to_payload<T: Serialize + ?Sized>(
+    codec: &dyn PayloadCodec,
+    value: &T,
+) -> Result<Option<String>, Error> {
+    match serde_json::to_value(value)? {
+        Value::Null => Ok(None),
+        value => codec.encode(&value).map(Some),
+    }
+}
+
+/// Deserializes a payload produced by [`to_payload`]. A missing payload decodes as `null`.
+pub fn from_payload<T: DeserializeOwned>(
+    codec: &dyn PayloadCodec,
+    payload: Option<&str>,
+) -> Result<T, Error> {
+    let value = match payload {
+        None => Value::Null,
+        Some(payload) => codec.decode(payload)?,
+    };
+    Ok(serde_json::from_value(value)?)
+}
+
+/// A payload captured by a builder, encoded once the client's codec is known.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Payload {
+    /// An already serialized payload that's passed through unchanged.
+    Raw(String),
+    Value(Value),
+    /// A value that failed to serialize, reported when the payload is encoded.
+    Invalid(String),
+}
+
+impl Payload {
+    pub(crate) fn from_value<T: Serialize + ?Sized>(value: &T) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => Payload::Value(value),
+            Err(e) => Payload::Invalid(e.to_string()),
+        }
+    }
+
+    pub(crate) fn encode(&self, codec: &dyn PayloadCodec) -> Result<Option<String>, Error> {
+        match self {
+            Payload::Raw(payload) => Ok(Some(payload.clone())),
+            Payload::Value(Value::Null) => Ok(None),
+            Payload::Value(value) => codec.encode(value).map(Some),
+            Payload::Invalid(message) => Err(Error::Serialization(message.clone().into())),
+        }
+    }
+}
+
+/// The kind of value a payload holds. Transformers can bind a sealed payload to it, so it
+/// can't be read back as another kind.
+///
+/// A payload keeps its kind wherever it's copied, e.g. event data is [`EventData`] both in
+/// the sender's `EventSent` event and in the receiver's `EventRaised` event.
+///
+/// [`EventData`]: PayloadField::EventData
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayloadField {
+    /// An orchestration or activity input, including the input an orchestration continues as
+    /// new with.
+    Input,
+    /// An orchestration or activity result.
+    Output,
+    /// The data of an external event.
+    EventData,
+    CustomStatus,
+    /// Why an orchestration was terminated, suspended or resumed.
+    Reason,
+    /// The details an activity reported with its last heartbeat.
+    HeartbeatDetails,
+    /// A failure message or stack trace.
+    Failure,
+}
+
+impl PayloadField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayloadField::Input => "input",
+            PayloadField::Output => "output",
+            PayloadField::EventData => "event_data",
+            PayloadField::CustomStatus => "custom_status",
+            PayloadField::Reason => "reason",
+            PayloadField::HeartbeatDetails => "heartbeat_details",
+            PayloadField::Failure => "failure",
+        }
+    }
+}
+
+/// Transforms encoded payloads on their way to and from storage, e.g. to encrypt them.
+///
+/// Transformers apply to every input, output, event data, custom status and failure message
+/// written by the client and worker, so they must accept any string.
+#[async_trait]
+pub trait PayloadTransformer: Send + Sync {
+    /// Transforms a `field` payload read by the orchestration `instance_id` before it's
+    /// stored.
+    async fn seal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error>;
+    /// Like [`seal`](Self::seal), for a payload `sender_id` sends to `instance_id`, which is
+    /// kept in both of their histories, e.g. a sub-orchestration input. It must stay readable
+    /// by both until they're purged.
+    async fn seal_shared(
+        &self,
+        instance_id: &str,
+        _sender_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        self.seal(instance_id, field, payload).await
+    }
+    /// Reverses [`seal`](Self::seal) for a payload read by `instance_id`: the instance it was
+    /// sealed for, or its sender if it was [shared](Self::seal_shared). Payloads that weren't
+    /// sealed by this transformer, e.g. ones written before it was configured, should be
+    /// returned unchanged unless the transformer requires them to be sealed.
+    ///
+    /// Failures the framework reports itself, e.g. activity timeouts, are stored as they are.
+    async fn unseal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error>;
+    /// Releases anything held for the payloads of `instance_id` once it's purged.
+    async fn purge(&self, _instance_id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The transformers configured on a client or worker, sealed in order and unsealed in reverse.
+#[derive(Clone, Default)]
+pub(crate) struct PayloadPipeline {
+    transformers: Vec<Arc<dyn PayloadTransformer>>,
+}
+
+impl PayloadPipeline {
+    pub(crate) fn push(&mut self, transformer: Arc<dyn PayloadTransformer>) {
+        self.transformers.push(transformer);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
+    }
+
+    pub(crate) async fn seal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        mut payload: String,
+    ) -> Result<String, Error> {
+        for transformer in &self.transformers {
+            payload = transformer.seal(instance_id, field, payload).await?;
+        }
+        Ok(payload)
+    }
+
+    async fn seal_shared(
+        &self,
+        instance_id: &str,
+        sender_id: &str,
+        field: PayloadField,
+        mut payload: String,
+    ) -> Result<String, Error> {
+        for transformer in &self.transformers {
+            payload = transformer
+                .seal_shared(instance_id, sender_id, field, payload)
+                .await?;
+        }
+        Ok(payload)
+    }
+
+    pub(crate) async fn unseal(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        mut payload: String,
+    ) -> Result<String, Error> {
+        for transformer in self.transformers.iter().rev() {
+            payload = transformer.unseal(instance_id, field, payload).await?;
+        }
+        Ok(payload)
+    }
+
+    pub(crate) async fn seal_option(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        match payload {
+            Some(payload) => self.seal(instance_id, field, payload).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn unseal_option(
+        &self,
+        instance_id: &str,
+        field: PayloadField,
+        payload: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        match payload {
+            Some(payload) => self.unseal(instance_id, field, payload).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn seal_event(
+        &self,
+        instance_id: &str,
+        e: &mut HistoryEvent,
+    ) -> Result<(), Error> {
+        self.seal_all(instance_id, fields::event_payloads_mut(e))
+            .await
+    }
+
+    /// Unseals the payloads of `e`, read from the history of `instance_id`.
+    pub(crate) async fn unseal_event(
+        &self,
+        instance_id: &str,
+        e: &mut HistoryEvent,
+    ) -> Result<(), Error> {
+        self.unseal_all(instance_id, fields::event_payloads_mut(e))
+            .await
+    }
+
+    /// Like [`unseal_event`](Self::unseal_event), but leaves the payloads that can't be unsealed
+    /// as they're stored, e.g. ones whose blob was deleted, instead of failing.
+    pub(crate) async fn unseal_event_or_keep(&self, instance_id: &str, e: &mut HistoryEvent) {
+        for (field, payload) in fields::event_payloads_mut(e) {
+            if let Ok(unsealed) = self.unseal(instance_id, field, payload.clone()).await {
+                *payload = unsealed;
+            }
+        }
+    }
+
+    /// Seals the payloads of an action taken by `instance_id`. Payloads delivered to another
+    /// instance are [shared](PayloadTransformer::seal_shared) with it, so they outlive either
+    /// of them being purged.
+    pub(crate) async fn seal_action(
+        &self,
+        instance_id: &str,
+        parent_instance_id: Option<&str>,
+        action: &mut OrchestratorAction,
+    ) -> Result<(), Error> {
+        let reader = fields::action_payload_reader(action, instance_id, parent_instance_id);
+        if reader == instance_id {
+            return self
+                .seal_all(instance_id, fields::action_payloads_mut(action))
+                .await;
+        }
+        for (field, payload) in fields::action_payloads_mut(action) {
+            *payload = self
+                .seal_shared(&reader, instance_id, field, std::mem::take(payload))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn unseal_failure(
+        &self,
+        instance_id: &str,
+        details: Option<&mut TaskFailureDetails>,
+    ) -> Result<(), Error> {
+        self.unseal_all(instance_id, fields::failure_payloads_mut(details))
+            .await
+    }
+
+    pub(crate) async fn purge(&self, instance_id: &str) -> Result<(), Error> {
+        for transformer in &self.transformers {
+            transformer.purge(instance_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn seal_all(
+        &self,
+        instance_id: &str,
+        payloads: Vec<(PayloadField, &mut String)>,
+    ) -> Result<(), Error> {
+        for (field, payload) in payloads {
+            *payload = self
+                .seal(instance_id, field, std::mem::take(payload))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn unseal_all(
+        &self,
+        instance_id: &str,
+        payloads: Vec<(PayloadField, &mut String)>,
+    ) -> Result<(), Error> {
+        for (field, payload) in payloads {
+            *payload = self
+                .unseal(instance_id, field, std::mem::take(payload))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The default codec, encoding payloads as JSON.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl PayloadCodec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, value: &Value) -> Result<String, Error> {
+        Ok(serde_json::to_string(value)?)
+    }
+
+    fn decode(&self, payload: &str) -> Result<Value, Error> {
+        Ok(serde_json::from_str(payload)?)
+    }
+}
+
+/// Passes string values through unchanged, for orchestrations exchanging plain text.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RawStringCodec;
+
+impl PayloadCodec for RawStringCodec {
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    fn encode(&self, value: &Value) -> Result<String, Error> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(Error::Serialization(
+                "the raw string codec can only encode strings".into(),
+            )),
+        }
+    }
+
+    fn decode(&self, payload: &str) -> Result<Value, Error> {
+        Ok(Value::String(payload.to_string()))
+    }
+}
+
+/// Encodes payloads as base64 MessagePack.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl PayloadCodec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, value: &Value) -> Result<String, Error> {
+        let bytes = rmp_serde::to_vec_named(value).map_err(|e| Error::Serialization(e.into()))?;
+        Ok(encode_base64(&bytes))
+    }
+
+    fn decode(&self, payload: &str) -> Result<Value, Error> {
+        let bytes = decode_base64(payload)?;
+        rmp_serde::from_slice(&bytes).map_err(|e| Error::Serialization(e.into()))
+    }
+}
+
+/// Encodes payloads as base64 CBOR.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl PayloadCodec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, value: &Value) -> Result<String, Error> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(|e| Error::Serialization(e.into()))?;
+        Ok(encode_base64(&bytes))
+    }
+
+    fn decode(&self, payload: &str) -> Result<Value, Error> {
+        let bytes = decode_base64(payload)?;
+        ciborium::from_reader(bytes.as_slice()).map_err(|e| Error::Serialization(e.into()))
+    }
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor", feature = "encryption"))]
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor", feature = "encryption"))]
+pub(crate) fn decode_base64(payload: &str) -> Result<Vec<u8>, Error> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .map_err(|e| Error::Serialization(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u32,
+        items: Vec<String>,
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            items: vec!["apple".to_string(), "pear".to_string()],
+        }
+    }
+
+    fn roundtrip(codec: &dyn PayloadCodec) {
+        let payload = to_payload(codec, &order()).unwrap();
+        assert!(payload.is_some());
+        let decoded: Order = from_payload(codec, payload.as_deref()).unwrap();
+        assert_eq!(decoded, order());
+    }
+
+    #[test]
+    fn test_json_codec() {
+        roundtrip(&JsonCodec);
+        assert_eq!(
+            to_payload(&JsonCodec, &order()).unwrap().unwrap(),
+            r#"{"id":7,"items":["apple","pear"]}"#
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_codec() {
+        roundtrip(&MessagePackCodec);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_codec() {
+        roundtrip(&CborCodec);
+    }
+
+    #[test]
+    fn test_raw_string_codec() {
+        let payload = to_payload(&RawStringCodec, "plain text").unwrap();
+        assert_eq!(payload.as_deref(), Some("plain text"));
+        let decoded: String = from_payload(&RawStringCodec, payload.as_deref()).unwrap();
+        assert_eq!(decoded, "plain text");
+
+        assert!(matches!(
+            to_payload(&RawStringCodec, &order()),
+            Err(Error::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_unit_payload_is_absent() {
+        assert_eq!(to_payload(&JsonCodec, &()).unwrap(), None);
+        from_payload::<()>(&JsonCodec, None).unwrap();
+        assert_eq!(from_payload::<Option<u32>>(&JsonCodec, None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_serialization_errors_are_surfaced() {
+        let mut invalid = std::collections::HashMap::new();
+        invalid.insert(vec![1u8], "non-string keys can't be JSON");
+
+        assert!(matches!(
+            to_payload(&JsonCodec, &invalid),
+            Err(Error::Serialization(_))
+        ));
+        assert!(matches!(
+            Payload::from_value(&invalid).encode(&JsonCodec),
+            Err(Error::Serialization(_))
+        ));
+        assert!(matches!(
+            from_payload::<Order>(&JsonCodec, Some("not json")),
+            Err(Error::Serialization(_))
+        ));
+    }
+}
@@ -0,0 +1,343 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+//! Offloading of large payloads to an external blob store.
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::payload::{PayloadField, PayloadTransformer};
+use crate::Error;
+
+const TOKEN_PREFIX: &str = "blob:v1:";
+/// The prefix of blobs shared by two instances. Instance prefixes never start with a `.`.
+const SHARED_PREFIX: &str = ".shared/";
+
+/// Stores payloads that are too large to keep in history.
+///
+/// Keys are `/`-separated paths made of URL-safe segments.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error>;
+    /// Deletes a blob. Deleting a blob that doesn't exist succeeds.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+    /// Lists the keys that start with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+}
+
+/// A [`BlobStore`] that keeps each blob in a file under a root directory.
+pub struct FileSystemBlobStore {
+    root: PathBuf,
+}
+
+impl FileSystemBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileSystemBlobStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        let valid = !key.is_empty()
+            && !key.contains('\\')
+            && relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(Error::InvalidArgument(format!("invalid blob key '{key}'")));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileSystemBlobStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(io_error)?;
+        }
+        // Write to a temporary file first so readers never see a partial blob
+        let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        tokio::fs::write(&tmp, data).await.map_err(io_error)?;
+        tokio::fs::rename(&tmp, &path).await.map_err(io_error)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        tokio::fs::read(self.path(key)?).await.map_err(io_error)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(io_error(e)),
+        }
+        // Remove the parent directory once it's empty
+        if let Some(parent) = path.parent().filter(|p| *p != self.root) {
+            let _ = tokio::fs::remove_dir(parent).await;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![(self.root.clone(), String::new())];
+        while let Some((dir, dir_key)) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_error(e)),
+            };
+            while let Some(entry) = entries.next_entry().await.map_err(io_error)? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let key = format!("{dir_key}{name}");
+                if entry.file_type().await.map_err(io_error)?.is_dir() {
+                    // Only descend into directories that can contain matching keys
+                    let dir_key = format!("{key}/");
+                    if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                        dirs.push((entry.path(), dir_key));
+                    }
+                } else if key.starts_with(prefix) && !name.ends_with(".tmp") {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::Backend(Box::new(e))
+}
+
+/// Moves payloads larger than a threshold to a [`BlobStore`], leaving a reference token in
+/// history that's resolved when the payload is read.
+///
+/// Blobs are stored under the ID of the instance whose history receives them and are deleted
+/// when that instance is purged. Payloads sent to another instance, like sub-orchestration
+/// inputs, event data and a sub-orchestration's result, are kept in both histories: they're
+/// stored once with a reference from each instance, and deleted once both are purged.
+pub struct PayloadOffloader {
+    store: Arc<dyn BlobStore>,
+    threshold: usize,
+}
+
+impl PayloadOffloader {
+    /// Offloads payloads longer than `threshold` bytes to `store`.
+    pub fn new(store: Arc<dyn BlobStore>, threshold: usize) -> Self {
+        PayloadOffloader { store, threshold }
+    }
+
+    pub fn is_reference(payload: &str) -> bool {
+        payload.starts_with(TOKEN_PREFIX)
+    }
+}
+
+#[async_trait]
+impl PayloadTransformer for PayloadOffloader {
+    async fn seal(
+        &self,
+        instance_id: &str,
+        _field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        if payload.len() <= self.threshold {
+            return Ok(payload);
+        }
+        let key = format!("{}{}", instance_prefix(instance_id), Uuid::new_v4());
+        self.store.put(&key, payload.as_bytes()).await?;
+        Ok(format!("{TOKEN_PREFIX}{key}"))
+    }
+
+    async fn seal_shared(
+        &self,
+        instance_id: &str,
+        sender_id: &str,
+        _field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        if payload.len() <= self.threshold {
+            return Ok(payload);
+        }
+        let id = Uuid::new_v4();
+        let key = format!("{SHARED_PREFIX}{id}/data");
+        self.store.put(&key, payload.as_bytes()).await?;
+        for instance_id in [instance_id, sender_id] {
+            let prefix = instance_prefix(instance_id);
+            let reference = prefix.trim_end_matches('/');
+            self.store
+                .put(&format!("{SHARED_PREFIX}{id}/refs/{reference}"), &[])
+                .await?;
+            self.store
+                .put(&format!("{prefix}{SHARED_PREFIX}{id}"), &[])
+                .await?;
+        }
+        Ok(format!("{TOKEN_PREFIX}{key}"))
+    }
+
+    async fn unseal(
+        &self,
+        _instance_id: &str,
+        _field: PayloadField,
+        payload: String,
+    ) -> Result<String, Error> {
+        let Some(key) = payload.strip_prefix(TOKEN_PREFIX) else {
+            return Ok(payload);
+        };
+        let data = self.store.get(key).await?;
+        String::from_utf8(data).map_err(|e| Error::Serialization(e.into()))
+    }
+
+    async fn purge(&self, instance_id: &str) -> Result<(), Error> {
+        let prefix = instance_prefix(instance_id);
+        for key in self.store.list(&prefix).await? {
+            // Shared blobs are deleted once no instance references them
+            if let Some(id) = key[prefix.len()..].strip_prefix(SHARED_PREFIX) {
+                let refs = format!("{SHARED_PREFIX}{id}/refs/");
+                let reference = prefix.trim_end_matches('/');
+                self.store.delete(&format!("{refs}{reference}")).await?;
+                if self.store.list(&refs).await?.is_empty() {
+                    self.store
+                        .delete(&format!("{SHARED_PREFIX}{id}/data"))
+                        .await?;
+                }
+            }
+            self.store.delete(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes `instance_id` into a single key segment, so any instance ID maps to its own
+/// directory.
+fn instance_prefix(instance_id: &str) -> String {
+    let mut prefix = String::with_capacity(instance_id.len() + 1);
+    for b in instance_id.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            prefix.push(b as char);
+        } else {
+            prefix.push_str(&format!("%{b:02X}"));
+        }
+    }
+    prefix.push('/');
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::PayloadField::Input;
+
+    fn temp_store() -> (PathBuf, Arc<FileSystemBlobStore>) {
+        let root = std::env::temp_dir().join(format!("durabletask-blobs-{}", Uuid::new_v4()));
+        (root.clone(), Arc::new(FileSystemBlobStore::new(root)))
+    }
+
+    #[tokio::test]
+    async fn test_offload_above_threshold() {
+        let (root, store) = temp_store();
+        let offloader = PayloadOffloader::new(store.clone(), 8);
+
+        let small = offloader
+            .seal("abc", Input, "\"tiny\"".to_string())
+            .await
+            .unwrap();
+        assert_eq!(small, "\"tiny\"");
+
+        let large = "\"a much larger payload\"".to_string();
+        let token = offloader.seal("abc", Input, large.clone()).await.unwrap();
+        assert!(PayloadOffloader::is_reference(&token));
+        assert_eq!(store.list("abc/").await.unwrap().len(), 1);
+        assert_eq!(offloader.unseal("abc", Input, token).await.unwrap(), large);
+        assert_eq!(
+            offloader.unseal("abc", Input, small).await.unwrap(),
+            "\"tiny\""
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_purge_deletes_instance_blobs() {
+        let (root, store) = temp_store();
+        let offloader = PayloadOffloader::new(store.clone(), 0);
+
+        let token = offloader
+            .seal("a/b", Input, "first".to_string())
+            .await
+            .unwrap();
+        offloader
+            .seal("a/b", Input, "second".to_string())
+            .await
+            .unwrap();
+        let other = offloader
+            .seal("a", Input, "third".to_string())
+            .await
+            .unwrap();
+        assert!(token.starts_with("blob:v1:a%2Fb/"));
+
+        offloader.purge("a/b").await.unwrap();
+        assert!(store.list("a%2Fb/").await.unwrap().is_empty());
+        assert!(offloader.unseal("a/b", Input, token).await.is_err());
+        assert_eq!(offloader.unseal("a", Input, other).await.unwrap(), "third");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_shared_blobs_outlive_one_instance() {
+        let (root, store) = temp_store();
+        let offloader = PayloadOffloader::new(store.clone(), 0);
+
+        let token = offloader
+            .seal_shared("child", "parent", Input, "input".to_string())
+            .await
+            .unwrap();
+        assert!(token.starts_with("blob:v1:.shared/"));
+
+        offloader.purge("child").await.unwrap();
+        assert_eq!(
+            offloader
+                .unseal("parent", Input, token.clone())
+                .await
+                .unwrap(),
+            "input"
+        );
+        assert!(store.list("child/").await.unwrap().is_empty());
+
+        offloader.purge("parent").await.unwrap();
+        assert!(offloader.unseal("parent", Input, token).await.is_err());
+        assert!(store.list("").await.unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_keys_are_rejected() {
+        let (_, store) = temp_store();
+        for key in ["", "../escape", "/absolute", "a/../b", "a\\b"] {
+            assert!(matches!(
+                store.put(key, b"data").await,
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+}
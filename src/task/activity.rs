@@ -0,0 +1,198 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::api::InstanceID;
+use crate::backend::routing::Priority;
+use crate::payload::{from_payload, to_payload, PayloadCodec};
+use crate::task::CancellationToken;
+use crate::Error;
+
+/// The `error_type` of the `TaskFailed` event reported for an activity that exceeded one of
+/// its [`ActivityOptions`] timeouts.
+pub const ACTIVITY_TIMEOUT_ERROR_TYPE: &str = "ActivityTimeout";
+
+/// Timeouts and routing of an activity scheduled with
+/// [`call_activity_with_options`](crate::task::OrchestrationContext::call_activity_with_options).
+///
+/// An activity that exceeds one of the timeouts fails with [`ACTIVITY_TIMEOUT_ERROR_TYPE`]. The
+/// task queue and priority override those of the worker's
+/// [`TaskRouting`](crate::backend::routing::TaskRouting).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ActivityOptions {
+    schedule_to_start_timeout: Option<Duration>,
+    start_to_close_timeout: Option<Duration>,
+    heartbeat_timeout: Option<Duration>,
+    task_queue: Option<String>,
+    priority: Option<Priority>,
+}
+
+impl ActivityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long the activity may wait in its queue before a worker starts it. Only the first
+    /// attempt is timed, since retries were delayed by earlier attempts.
+    pub fn with_schedule_to_start_timeout(mut self, timeout: Duration) -> Self {
+        self.schedule_to_start_timeout = Some(timeout);
+        self
+    }
+
+    /// How long a single attempt of the activity may run.
+    pub fn with_start_to_close_timeout(mut self, timeout: Duration) -> Self {
+        self.start_to_close_timeout = Some(timeout);
+        self
+    }
+
+    /// How long the activity may run without calling [`ActivityContext::heartbeat`].
+    pub fn with_heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Enqueues the activity on `task_queue`, so only workers that fetch it run the activity.
+    pub fn with_task_queue(mut self, task_queue: &str) -> Self {
+        self.task_queue = Some(task_queue.to_string());
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn schedule_to_start_timeout(&self) -> Option<Duration> {
+        self.schedule_to_start_timeout
+    }
+
+    pub fn start_to_close_timeout(&self) -> Option<Duration> {
+        self.start_to_close_timeout
+    }
+
+    pub fn heartbeat_timeout(&self) -> Option<Duration> {
+        self.heartbeat_timeout
+    }
+
+    pub fn task_queue(&self) -> Option<&str> {
+        self.task_queue.as_deref()
+    }
+
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What the worker running an activity hands to it besides its input.
+#[derive(Clone, Default)]
+pub(crate) struct ActivityHooks {
+    pub cancellation: CancellationToken,
+    /// Receives the encoded details of each heartbeat.
+    pub heartbeats: Option<mpsc::UnboundedSender<Option<String>>>,
+    /// The details of the last heartbeat of a previous attempt.
+    pub heartbeat_details: Option<String>,
+}
+
+/// The context passed to an activity function.
+#[derive(Clone)]
+pub struct ActivityContext {
+    instance_id: InstanceID,
+    task_id: i32,
+    name: String,
+    input: Option<String>,
+    codec: Arc<dyn PayloadCodec>,
+    hooks: ActivityHooks,
+}
+
+impl ActivityContext {
+    pub(crate) fn new(
+        instance_id: InstanceID,
+        task_id: i32,
+        name: &str,
+        input: Option<String>,
+        codec: Arc<dyn PayloadCodec>,
+        hooks: ActivityHooks,
+    ) -> Self {
+        ActivityContext {
+            instance_id,
+            task_id,
+            name: name.to_string(),
+            input,
+            codec,
+            hooks,
+        }
+    }
+
+    /// The ID of the orchestration instance that scheduled this activity.
+    pub fn instance_id(&self) -> &InstanceID {
+        &self.instance_id
+    }
+
+    pub fn task_id(&self) -> i32 {
+        self.task_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Deserializes the activity input with the worker's codec.
+    pub fn get_input<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        from_payload(self.codec.as_ref(), self.input.as_deref())
+    }
+
+    /// Cancelled when the worker shuts down, the activity exceeds one of its timeouts or its
+    /// orchestration is terminated.
+    /// Activities that stop early because of a shutdown should return an error, and are retried
+    /// by another worker.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.hooks.cancellation
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.hooks.cancellation.is_cancelled()
+    }
+
+    /// Reports that the activity is still making progress, which resets its heartbeat timeout
+    /// and extends the lease of its work item. The `details` are saved, and handed to the
+    /// activity again through [`heartbeat_details`](Self::heartbeat_details) if it's retried.
+    pub fn heartbeat<T: Serialize + ?Sized>(&self, details: &T) -> Result<(), Error> {
+        let details = to_payload(self.codec.as_ref(), details)?;
+        if let Some(heartbeats) = &self.hooks.heartbeats {
+            // The worker stops listening once the activity timed out
+            let _ = heartbeats.send(details);
+        }
+        Ok(())
+    }
+
+    /// Deserializes the details of the last heartbeat of a previous attempt, if any.
+    pub fn heartbeat_details<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        from_payload(self.codec.as_ref(), self.hooks.heartbeat_details.as_deref())
+    }
+
+    pub(crate) fn codec(&self) -> Arc<dyn PayloadCodec> {
+        self.codec.clone()
+    }
+}
@@ -0,0 +1,92 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use tokio::sync::watch;
+
+/// Signals running work, like an activity, that it should stop early.
+///
+/// Tokens are cheap to clone, and every clone observes the same cancellation.
+#[derive(Clone, Debug)]
+pub struct CancellationToken(watch::Receiver<bool>);
+
+impl Default for CancellationToken {
+    /// A token that's never cancelled.
+    fn default() -> Self {
+        CancellationToken(watch::channel(false).1)
+    }
+}
+
+impl CancellationToken {
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until the token is cancelled, which may be never.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.0.clone();
+        if receiver.wait_for(|cancelled| *cancelled).await.is_err() {
+            // The token can no longer be cancelled
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Cancels the [`CancellationToken`]s it hands out.
+#[derive(Debug)]
+pub(crate) struct CancellationSource(watch::Sender<bool>);
+
+impl Default for CancellationSource {
+    fn default() -> Self {
+        CancellationSource(watch::channel(false).0)
+    }
+}
+
+impl CancellationSource {
+    pub(crate) fn token(&self) -> CancellationToken {
+        CancellationToken(self.0.subscribe())
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellation_token() {
+        let source = CancellationSource::default();
+        let token = source.token();
+        assert!(!token.is_cancelled());
+
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        source.cancel();
+        waiter.await.unwrap();
+        assert!(token.is_cancelled());
+        assert!(source.token().is_cancelled());
+
+        let never = CancellationToken::default();
+        assert!(!never.is_cancelled());
+        let result = tokio::time::timeout(Duration::from_millis(20), never.cancelled()).await;
+        assert!(result.is_err());
+    }
+}
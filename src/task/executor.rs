@@ -0,0 +1,1518 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::task::noop_waker_ref;
+use prost::Message;
+use tokio::sync::mpsc;
+
+use crate::api::InstanceID;
+use crate::backend::cache::{ExtendedSessionOptions, SessionCache};
+use crate::backend::executor::{DetachedStart, Executor, OrchestratorOutput};
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::orchestrator_action::OrchestratorActionType;
+use crate::durabletask_pb::{
+    HistoryEvent, OrchestrationStatus, OrchestratorResponse, TaskFailureDetails,
+};
+use crate::internal::{
+    get_history_event_type_name, new_complete_orchestration_action, new_task_completed_event,
+    new_task_failed_event,
+};
+use crate::payload::{PayloadCodec, PayloadField, PayloadPipeline};
+use crate::task::activity::ActivityHooks;
+use crate::task::orchestration::TaskResult;
+use crate::task::{ActivityContext, ActivityOptions, OrchestrationContext, TaskRegistry};
+use crate::Error;
+
+/// Runs the orchestrators and activities of a [`TaskRegistry`] in-process.
+///
+/// Payloads are unsealed before they're handed to orchestrator and activity code, and the
+/// payloads it produces are sealed before they're returned to the backend.
+pub(crate) struct TaskExecutor {
+    registry: Arc<TaskRegistry>,
+    codec: Arc<dyn PayloadCodec>,
+    payloads: PayloadPipeline,
+    sessions: Option<SessionCache<Session>>,
+}
+
+impl TaskExecutor {
+    pub(crate) fn new(
+        registry: Arc<TaskRegistry>,
+        codec: Arc<dyn PayloadCodec>,
+        payloads: PayloadPipeline,
+    ) -> Self {
+        TaskExecutor {
+            registry,
+            codec,
+            payloads,
+            sessions: None,
+        }
+    }
+
+    /// Keeps suspended orchestrators in memory between work items, so they only process the
+    /// history added since their last execution instead of replaying all of it.
+    pub(crate) fn with_sessions(mut self, options: ExtendedSessionOptions) -> Self {
+        self.sessions = Some(SessionCache::new(options));
+        self
+    }
+
+    async fn forward_heartbeat(
+        &self,
+        instance_id: &InstanceID,
+        details: Option<String>,
+        heartbeats: Option<&mpsc::UnboundedSender<Option<String>>>,
+    ) -> Result<(), Error> {
+        let details = self
+            .payloads
+            .seal_option(&instance_id.0, PayloadField::HeartbeatDetails, details)
+            .await?;
+        if let Some(heartbeats) = heartbeats {
+            let _ = heartbeats.send(details);
+        }
+        Ok(())
+    }
+
+    async fn unseal_events<'e>(
+        &self,
+        instance_id: &InstanceID,
+        events: &'e [HistoryEvent],
+    ) -> Result<Cow<'e, [HistoryEvent]>, Error> {
+        if self.payloads.is_empty() {
+            return Ok(Cow::Borrowed(events));
+        }
+        let mut events = events.to_vec();
+        for e in events.iter_mut().filter(|e| is_read_by_orchestrator(e)) {
+            self.payloads.unseal_event(&instance_id.0, e).await?;
+        }
+        Ok(Cow::Owned(events))
+    }
+}
+
+#[async_trait]
+impl Executor for TaskExecutor {
+    async fn execute_orchestrator(
+        &self,
+        instance_id: &InstanceID,
+        old_events: &[HistoryEvent],
+        new_events: &[HistoryEvent],
+    ) -> Result<OrchestratorOutput, Error> {
+        let session = self
+            .sessions
+            .as_ref()
+            .and_then(|sessions| sessions.take(&instance_id.0))
+            .filter(|session| session.resumes(old_events));
+        let (mut execution, processed, mut size) = match session {
+            Some(session) => (session.execution, session.history_length, session.size),
+            None => {
+                let ctx = OrchestrationContext::new(instance_id.clone(), self.codec.clone());
+                (
+                    OrchestrationExecution::new(self.registry.clone(), ctx),
+                    0,
+                    0,
+                )
+            }
+        };
+
+        let replayed_events = self
+            .unseal_events(instance_id, &old_events[processed..])
+            .await?;
+        let unsealed_new_events = self.unseal_events(instance_id, new_events).await?;
+
+        execution.ctx.lock().is_replaying = true;
+        for e in replayed_events.iter() {
+            execution.process_event(e);
+        }
+        execution.ctx.lock().is_replaying = false;
+        for e in unsealed_new_events.iter() {
+            execution.process_event(e);
+        }
+
+        let mut response = execution.response();
+        let activity_options = execution.activity_options();
+        let detached_starts = execution.detached_starts();
+        let parent_instance_id = parent_instance_id(old_events.iter().chain(new_events));
+        for action in response.actions.iter_mut() {
+            self.payloads
+                .seal_action(&instance_id.0, parent_instance_id.as_deref(), action)
+                .await?;
+        }
+        response.custom_status = self
+            .payloads
+            .seal_option(
+                &instance_id.0,
+                PayloadField::CustomStatus,
+                response.custom_status,
+            )
+            .await?;
+
+        if let (Some(sessions), false) = (&self.sessions, execution.is_complete) {
+            size += old_events[processed..]
+                .iter()
+                .chain(new_events)
+                .map(|e| e.encoded_len())
+                .sum::<usize>();
+            let session = Session {
+                execution,
+                history_length: old_events.len() + new_events.len(),
+                last_event: new_events.last().or(old_events.last()).cloned(),
+                size,
+            };
+            sessions.put(&instance_id.0, session, size);
+        }
+        Ok(OrchestratorOutput {
+            response,
+            activity_options,
+            detached_starts,
+        })
+    }
+
+    async fn execute_activity(
+        &self,
+        instance_id: &InstanceID,
+        event: &HistoryEvent,
+        hooks: ActivityHooks,
+    ) -> Result<HistoryEvent, Error> {
+        let mut event = event.clone();
+        self.payloads
+            .unseal_event(&instance_id.0, &mut event)
+            .await?;
+        let Some(EventType::TaskScheduled(scheduled)) = &event.event_type else {
+            return Err(Error::InvalidHistory(format!(
+                "expected a TaskScheduled event, got {}",
+                get_history_event_type_name(&event)
+            )));
+        };
+        let task_id = event.event_id;
+
+        let Some(activity) = self.registry.activity(&scheduled.name) else {
+            let details = failure_details(
+                "ActivityNotRegistered",
+                format!("activity '{}' is not registered", scheduled.name),
+            );
+            let mut result = new_task_failed_event(task_id, Some(&details));
+            self.payloads
+                .seal_event(&instance_id.0, &mut result)
+                .await?;
+            return Ok(result);
+        };
+
+        // Heartbeat details are sealed like any other payload before they reach the worker
+        let (heartbeats, mut sealing) = mpsc::unbounded_channel();
+        let cancellation = hooks.cancellation.clone();
+        let ctx = ActivityContext::new(
+            instance_id.clone(),
+            task_id,
+            &scheduled.name,
+            scheduled.input.clone(),
+            self.codec.clone(),
+            ActivityHooks {
+                cancellation: cancellation.clone(),
+                heartbeats: Some(heartbeats),
+                heartbeat_details: self
+                    .payloads
+                    .unseal_option(
+                        &instance_id.0,
+                        PayloadField::HeartbeatDetails,
+                        hooks.heartbeat_details,
+                    )
+                    .await?,
+            },
+        );
+        let output = activity(ctx);
+        tokio::pin!(output);
+        let output = loop {
+            tokio::select! {
+                output = &mut output => break output,
+                Some(details) = sealing.recv() => {
+                    self.forward_heartbeat(instance_id, details, hooks.heartbeats.as_ref()).await?;
+                }
+            }
+        };
+        while let Ok(details) = sealing.try_recv() {
+            self.forward_heartbeat(instance_id, details, hooks.heartbeats.as_ref())
+                .await?;
+        }
+        let mut result = match output {
+            Ok(output) => new_task_completed_event(task_id, output.as_deref()),
+            // The activity stopped early and is retried instead of failing the task
+            Err(_) if cancellation.is_cancelled() => return Err(Error::Cancelled),
+            Err(details) => new_task_failed_event(task_id, Some(&details)),
+        };
+        self.payloads
+            .seal_event(&instance_id.0, &mut result)
+            .await?;
+        Ok(result)
+    }
+}
+
+/// A suspended orchestrator kept between work items.
+struct Session {
+    execution: OrchestrationExecution,
+    /// The number of history events the execution has processed.
+    history_length: usize,
+    /// The last processed event, as stored by the backend.
+    last_event: Option<HistoryEvent>,
+    size: usize,
+}
+
+impl Session {
+    /// Whether `old_events` continues the history this session has processed, so the execution
+    /// can pick up where it left off.
+    fn resumes(&self, old_events: &[HistoryEvent]) -> bool {
+        self.history_length <= old_events.len()
+            && old_events[..self.history_length].last() == self.last_event.as_ref()
+    }
+}
+
+/// A replay of an orchestrator over its history.
+struct OrchestrationExecution {
+    registry: Arc<TaskRegistry>,
+    ctx: OrchestrationContext,
+    orchestrator: Option<BoxFuture<'static, TaskResult>>,
+    is_complete: bool,
+}
+
+impl OrchestrationExecution {
+    fn new(registry: Arc<TaskRegistry>, ctx: OrchestrationContext) -> Self {
+        OrchestrationExecution {
+            registry,
+            ctx,
+            orchestrator: None,
+            is_complete: false,
+        }
+    }
+
+    fn process_event(&mut self, e: &HistoryEvent) {
+        // Events raised after the orchestrator returned can still be carried over to a new
+        // execution
+        if let Some(EventType::EventRaised(_)) = &e.event_type {
+            self.ctx.lock().raise_event(e);
+        }
+        if self.is_complete {
+            return;
+        }
+
+        match &e.event_type {
+            Some(EventType::OrchestratorStarted(_)) => {
+                if let Some(time) = e
+                    .timestamp
+                    .clone()
+                    .and_then(|t| SystemTime::try_from(t).ok())
+                {
+                    self.ctx.lock().current_time = time;
+                }
+            }
+            Some(EventType::ExecutionStarted(started)) => {
+                {
+                    let mut state = self.ctx.lock();
+                    state.execution_id = started
+                        .orchestration_instance
+                        .as_ref()
+                        .and_then(|instance| instance.execution_id.clone())
+                        .unwrap_or_default();
+                    state.name.clone_from(&started.name);
+                    state.input.clone_from(&started.input);
+                }
+                match self.registry.orchestrator(&started.name) {
+                    Some(orchestrator) => self.orchestrator = Some(orchestrator(self.ctx.clone())),
+                    None => self.fail(failure_details(
+                        "OrchestratorNotRegistered",
+                        format!("orchestrator '{}' is not registered", started.name),
+                    )),
+                }
+            }
+            Some(EventType::TaskScheduled(_))
+            | Some(EventType::TimerCreated(_))
+            | Some(EventType::SubOrchestrationInstanceCreated(_))
+            | Some(EventType::EventSent(_)) => {
+                let scheduled = {
+                    let mut state = self.ctx.lock();
+                    state.activity_options.remove(&e.event_id);
+                    state.detached_starts.remove(&e.event_id);
+                    state.pending_actions.remove(&e.event_id)
+                };
+                if scheduled.is_none() {
+                    self.fail(failure_details(
+                        "NonDeterministicOrchestrator",
+                        format!(
+                            "history has a {} event with ID {} that the orchestrator didn't schedule",
+                            get_history_event_type_name(e),
+                            e.event_id
+                        ),
+                    ));
+                }
+            }
+            Some(EventType::TaskCompleted(completed)) => {
+                self.ctx
+                    .lock()
+                    .results
+                    .insert(completed.task_scheduled_id, Ok(completed.result.clone()));
+            }
+            Some(EventType::TaskFailed(failed)) => {
+                let details = failed.failure_details.clone().unwrap_or_default();
+                self.ctx
+                    .lock()
+                    .results
+                    .insert(failed.task_scheduled_id, Err(details));
+            }
+            Some(EventType::SubOrchestrationInstanceCompleted(completed)) => {
+                self.ctx
+                    .lock()
+                    .results
+                    .insert(completed.task_scheduled_id, Ok(completed.result.clone()));
+            }
+            Some(EventType::SubOrchestrationInstanceFailed(failed)) => {
+                let details = failed.failure_details.clone().unwrap_or_default();
+                self.ctx
+                    .lock()
+                    .results
+                    .insert(failed.task_scheduled_id, Err(details));
+            }
+            Some(EventType::TimerFired(fired)) => {
+                self.ctx.lock().results.insert(fired.timer_id, Ok(None));
+            }
+            Some(EventType::HistoryState(_)) => {
+                // Tasks with lower IDs were scheduled before the snapshot, which may have dropped
+                // the events that recorded them
+                let mut state = self.ctx.lock();
+                state.pending_actions.retain(|id, _| *id >= e.event_id);
+                state.activity_options.retain(|id, _| *id >= e.event_id);
+                state.detached_starts.retain(|id, _| *id >= e.event_id);
+            }
+            Some(EventType::ExecutionTerminated(terminated)) => {
+                self.ctx.lock().pending_actions.clear();
+                self.complete(
+                    OrchestrationStatus::Terminated,
+                    terminated.input.clone(),
+                    None,
+                );
+            }
+            _ => {}
+        }
+
+        self.poll();
+    }
+
+    fn poll(&mut self) {
+        if self.is_complete {
+            return;
+        }
+        let Some(orchestrator) = self.orchestrator.as_mut() else {
+            return;
+        };
+        let mut cx = Context::from_waker(noop_waker_ref());
+        if let Poll::Ready(result) = orchestrator.as_mut().poll(&mut cx) {
+            let continue_as_new = self
+                .ctx
+                .lock()
+                .continue_as_new
+                .as_ref()
+                .map(|c| c.input.clone());
+            match (result, continue_as_new) {
+                (Ok(_), Some(input)) => {
+                    self.complete(OrchestrationStatus::ContinuedAsNew, input, None)
+                }
+                (Ok(output), None) => self.complete(OrchestrationStatus::Completed, output, None),
+                (Err(details), _) => {
+                    self.complete(OrchestrationStatus::Failed, None, Some(details))
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, details: TaskFailureDetails) {
+        self.ctx.lock().pending_actions.clear();
+        self.complete(OrchestrationStatus::Failed, None, Some(details));
+    }
+
+    fn complete(
+        &mut self,
+        status: OrchestrationStatus,
+        output: Option<String>,
+        details: Option<TaskFailureDetails>,
+    ) {
+        self.is_complete = true;
+        self.orchestrator = None;
+
+        let mut state = self.ctx.lock();
+        state.event_waiters.clear();
+        let id = state.next_sequence_number();
+        let action =
+            new_complete_orchestration_action(id, status, output.as_deref(), &[], details.as_ref());
+        state.pending_actions.insert(id, action);
+    }
+
+    /// Returns the actions that aren't in the history yet. They stay pending until the events
+    /// recording them are replayed.
+    fn response(&self) -> OrchestratorResponse {
+        let state = self.ctx.lock();
+        let mut actions: Vec<_> = state.pending_actions.values().cloned().collect();
+        if state
+            .continue_as_new
+            .as_ref()
+            .is_some_and(|c| c.preserve_unprocessed_events)
+        {
+            for action in actions.iter_mut() {
+                if let Some(OrchestratorActionType::CompleteOrchestration(complete)) =
+                    &mut action.orchestrator_action_type
+                {
+                    if complete.orchestration_status() == OrchestrationStatus::ContinuedAsNew {
+                        complete.carryover_events.clone_from(&state.buffered_events);
+                    }
+                }
+            }
+        }
+        OrchestratorResponse {
+            instance_id: state.instance_id.0.clone(),
+            actions,
+            custom_status: state.custom_status.clone(),
+        }
+    }
+
+    /// Returns the detached orchestration starts that aren't in the history yet.
+    fn detached_starts(&self) -> HashMap<i32, DetachedStart> {
+        let state = self.ctx.lock();
+        state
+            .detached_starts
+            .iter()
+            .filter(|(id, _)| state.pending_actions.contains_key(id))
+            .map(|(id, start)| (*id, start.clone()))
+            .collect()
+    }
+
+    /// Returns the options of the scheduled activities that aren't in the history yet.
+    fn activity_options(&self) -> HashMap<i32, ActivityOptions> {
+        let state = self.ctx.lock();
+        state
+            .activity_options
+            .iter()
+            .filter(|(id, _)| state.pending_actions.contains_key(id))
+            .map(|(id, options)| (*id, options.clone()))
+            .collect()
+    }
+}
+
+/// Whether orchestrator code can observe the payloads of `e`. Payloads it only writes, like
+/// activity inputs, are left sealed so offloaded payloads aren't fetched on every replay.
+fn is_read_by_orchestrator(e: &HistoryEvent) -> bool {
+    matches!(
+        e.event_type,
+        Some(EventType::ExecutionStarted(_))
+            | Some(EventType::ExecutionTerminated(_))
+            | Some(EventType::TaskCompleted(_))
+            | Some(EventType::TaskFailed(_))
+            | Some(EventType::SubOrchestrationInstanceCompleted(_))
+            | Some(EventType::SubOrchestrationInstanceFailed(_))
+            | Some(EventType::EventRaised(_))
+    )
+}
+
+/// Returns the ID of the orchestration that started the one with history `events`, if any.
+fn parent_instance_id<'e>(mut events: impl Iterator<Item = &'e HistoryEvent>) -> Option<String> {
+    events.find_map(|e| match &e.event_type {
+        Some(EventType::ExecutionStarted(started)) => started
+            .parent_instance
+            .as_ref()
+            .and_then(|parent| parent.orchestration_instance.as_ref())
+            .map(|instance| instance.instance_id.clone()),
+        _ => None,
+    })
+}
+
+fn failure_details(error_type: &str, error_message: String) -> TaskFailureDetails {
+    TaskFailureDetails {
+        error_type: error_type.to_string(),
+        error_message,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{NewOrchestrationBuilder, OrchestrationIdReusePolicy};
+    use crate::backend::runtimestate::OrchestrationRuntimeState;
+    use crate::durabletask_pb::CreateOrchestrationAction;
+    use std::time::Duration;
+
+    use prost_wkt_types::Timestamp;
+
+    use crate::internal::{
+        new_event_raised_event, new_event_sent_event, new_execution_started_event,
+        new_orchestrator_started_event, new_parent_info, new_sub_orchestration_completed_event,
+        new_sub_orchestration_created_event, new_sub_orchestration_failed_event,
+        new_task_scheduled_event, new_timer_created_event, new_timer_fired_event,
+    };
+    use crate::payload::JsonCodec;
+
+    use super::*;
+
+    fn executor() -> TaskExecutor {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("greet", |ctx: OrchestrationContext| async move {
+                let name: String = ctx.get_input()?;
+                ctx.set_custom_status("greeting")?;
+                let greeting: String = ctx.call_activity("say_hello", &name).await?;
+                Ok::<_, Error>(greeting)
+            })
+            .unwrap();
+        registry
+            .add_activity("say_hello", |ctx: ActivityContext| async move {
+                let name: String = ctx.get_input()?;
+                Ok::<_, Error>(format!("hello {name}"))
+            })
+            .unwrap();
+        TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_replay() {
+        let executor = executor();
+        let id = InstanceID("abc".to_string());
+        let mut history = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("greet", "abc", Some(r#""world""#), None, None, None),
+        ];
+
+        let response = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.custom_status.as_deref(), Some(r#""greeting""#));
+        assert_eq!(response.actions.len(), 1);
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::ScheduleTask(task)) => {
+                assert_eq!(task.name, "say_hello");
+                assert_eq!(task.input.as_deref(), Some(r#""world""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+
+        let scheduled = new_task_scheduled_event(0, "say_hello", None, Some(r#""world""#), None);
+        let result = executor
+            .execute_activity(&id, &scheduled, ActivityHooks::default())
+            .await
+            .unwrap();
+        history.push(scheduled);
+
+        let response = executor
+            .execute_orchestrator(&id, &history, &[new_orchestrator_started_event(), result])
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 1);
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                assert_eq!(
+                    complete.orchestration_status(),
+                    OrchestrationStatus::Completed
+                );
+                assert_eq!(complete.result.as_deref(), Some(r#""hello world""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_sessions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let starts = Arc::new(AtomicUsize::new(0));
+        let mut registry = TaskRegistry::new();
+        let counter = starts.clone();
+        registry
+            .add_orchestrator("twice", move |ctx: OrchestrationContext| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let first: String = ctx.call_activity("say_hello", "a").await?;
+                    let second: String = ctx.call_activity("say_hello", "b").await?;
+                    Ok::<_, Error>(first + &second)
+                }
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        )
+        .with_sessions(ExtendedSessionOptions::default());
+        let id = InstanceID("abc".to_string());
+
+        let mut history = vec![];
+        let mut new_events = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("twice", "abc", None, None, None, None),
+        ];
+        for (task_id, result) in [(0, r#""a""#), (1, r#""b""#)] {
+            let response = executor
+                .execute_orchestrator(&id, &history, &new_events)
+                .await
+                .unwrap()
+                .response;
+            assert_eq!(response.actions.len(), 1);
+            history.append(&mut new_events);
+            history.push(new_task_scheduled_event(
+                task_id,
+                "say_hello",
+                None,
+                None,
+                None,
+            ));
+            new_events = vec![
+                new_orchestrator_started_event(),
+                new_task_completed_event(task_id, Some(result)),
+            ];
+        }
+        let response = executor
+            .execute_orchestrator(&id, &history, &new_events)
+            .await
+            .unwrap()
+            .response;
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                assert_eq!(complete.result.as_deref(), Some(r#""ab""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        assert_eq!(executor.sessions.as_ref().unwrap().len(), 0);
+
+        // Completed orchestrations aren't cached, so they're replayed from the start
+        let response = executor
+            .execute_orchestrator(&id, &history[..2], &[])
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 1);
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_from_snapshot() {
+        let executor = executor();
+        let id = InstanceID("abc".to_string());
+        let history = [
+            new_orchestrator_started_event(),
+            new_execution_started_event("greet", "abc", Some(r#""world""#), None, None, None),
+            new_task_scheduled_event(0, "say_hello", None, Some(r#""world""#), None),
+        ];
+        let mut state = OrchestrationRuntimeState::new(&id, &history);
+        state.take_snapshot();
+        state.commit();
+
+        let response = executor
+            .execute_orchestrator(
+                &id,
+                state.old_events(),
+                &[
+                    new_orchestrator_started_event(),
+                    new_task_completed_event(0, Some(r#""hello world""#)),
+                ],
+            )
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 1);
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                assert_eq!(complete.result.as_deref(), Some(r#""hello world""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_replay_matches_full_history() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("greet_all", |ctx: OrchestrationContext| async move {
+                let mut greetings = Vec::new();
+                for name in ["a", "b", "c"] {
+                    let greeting: String = ctx.call_activity("say_hello", name).await?;
+                    ctx.set_custom_status(&greeting)?;
+                    greetings.push(greeting);
+                }
+                Ok::<_, Error>(greetings)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+
+        let mut history = vec![];
+        let mut new_events = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("greet_all", "abc", None, None, None, None),
+        ];
+        for (task_id, name) in [(0, "a"), (1, "b"), (2, "c")] {
+            history.append(&mut new_events);
+            let input = format!(r#""{name}""#);
+            history.push(new_task_scheduled_event(
+                task_id,
+                "say_hello",
+                None,
+                Some(&input),
+                None,
+            ));
+            // A turn that only delivered an unrelated event
+            history.push(new_orchestrator_started_event());
+            new_events = vec![
+                new_orchestrator_started_event(),
+                new_task_completed_event(task_id, Some(&format!(r#""hello {name}""#))),
+            ];
+
+            let full = executor
+                .execute_orchestrator(&id, &history, &new_events)
+                .await
+                .unwrap()
+                .response;
+            let mut state = OrchestrationRuntimeState::new(&id, &history);
+            state.take_snapshot();
+            state.commit();
+            let compacted = executor
+                .execute_orchestrator(&id, state.old_events(), &new_events)
+                .await
+                .unwrap()
+                .response;
+            assert_eq!(compacted, full);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_snapshot_alone() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("greet_all", |ctx: OrchestrationContext| async move {
+                ctx.send_event(&InstanceID("audit".to_string()), "started", &())?;
+                let prefix: String = ctx.wait_for_external_event("prefix").await?;
+                let mut greetings = Vec::new();
+                for name in ["a", "b", "c"] {
+                    let greeting: String = ctx.call_activity("say_hello", name).await?;
+                    ctx.set_custom_status(&greeting)?;
+                    greetings.push(format!("{prefix} {greeting}"));
+                }
+                ctx.create_timer(Duration::from_secs(1)).await?;
+                Ok::<_, Error>(greetings)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+
+        // Every turn replays the full history, and the snapshot of it without the earlier events
+        let mut state = OrchestrationRuntimeState::new(&id, &[]);
+        let mut new_events = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("greet_all", "abc", None, None, None, None),
+            new_event_raised_event("prefix", Some(r#""oh""#)),
+        ];
+        for _ in 0..10 {
+            let full = executor
+                .execute_orchestrator(&id, state.old_events(), &new_events)
+                .await
+                .unwrap()
+                .response;
+            let mut snapshot = OrchestrationRuntimeState::new(&id, state.old_events());
+            snapshot.take_snapshot();
+            snapshot.commit();
+            let compacted = executor
+                .execute_orchestrator(&id, snapshot.old_events(), &new_events)
+                .await
+                .unwrap()
+                .response;
+            assert_eq!(compacted, full);
+
+            for e in &new_events {
+                state.add_event(e, true).unwrap();
+            }
+            state.apply_actions(&full.actions).unwrap();
+            if state.is_completed() {
+                state.commit();
+                break;
+            }
+            new_events = vec![new_orchestrator_started_event()];
+            for e in state.new_events() {
+                match &e.event_type {
+                    Some(EventType::TaskScheduled(scheduled)) => {
+                        let name: String =
+                            serde_json::from_str(scheduled.input.as_deref().unwrap()).unwrap();
+                        let result = format!(r#""hello {name}""#);
+                        new_events.push(new_task_completed_event(e.event_id, Some(&result)));
+                    }
+                    Some(EventType::TimerCreated(created)) => new_events.push(
+                        new_timer_fired_event(e.event_id, created.fire_at.as_ref().unwrap()),
+                    ),
+                    _ => {}
+                }
+            }
+            state.commit();
+        }
+
+        assert_eq!(
+            state.output().unwrap(),
+            r#"["oh hello a","oh hello b","oh hello c"]"#
+        );
+        // Only the results of the resolved tasks are left
+        state.take_snapshot();
+        let snapshot = state.snapshot().unwrap();
+        assert!(!snapshot.iter().any(|e| matches!(
+            e.event_type,
+            Some(
+                EventType::TaskScheduled(_) | EventType::TimerCreated(_) | EventType::EventSent(_)
+            )
+        )));
+        assert_eq!(snapshot.last().unwrap().event_id, 5);
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_continue_as_new() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("counter", |ctx: OrchestrationContext| async move {
+                let count: u32 = ctx.get_input()?;
+                ctx.continue_as_new(&(count + 1), true)?;
+                Ok::<_, Error>(count)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+
+        let raised = new_event_raised_event("incr", Some("1"));
+        let response = executor
+            .execute_orchestrator(
+                &id,
+                &[],
+                &[
+                    new_orchestrator_started_event(),
+                    new_execution_started_event("counter", "abc", Some("1"), None, None, None),
+                    raised.clone(),
+                ],
+            )
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 1);
+        let complete = match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => complete.clone(),
+            a => panic!("unexpected action: {a:?}"),
+        };
+        assert_eq!(
+            complete.orchestration_status(),
+            OrchestrationStatus::ContinuedAsNew
+        );
+        assert_eq!(complete.result.as_deref(), Some("2"));
+        assert_eq!(complete.carryover_events, vec![raised]);
+
+        let mut state = OrchestrationRuntimeState::new(
+            &id,
+            &[new_execution_started_event(
+                "counter",
+                "abc",
+                Some("1"),
+                None,
+                None,
+                None,
+            )],
+        );
+        assert!(state.apply_actions(&response.actions).unwrap());
+        assert!(state.continued_as_new());
+        assert_eq!(state.input().unwrap(), "2");
+        assert_eq!(state.new_events().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_external_events() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("approve", |ctx: OrchestrationContext| async move {
+                let first = ctx.wait_for_external_event::<String>("Approval");
+                let second = ctx.wait_for_external_event::<String>("APPROVAL");
+                let (first, second) = (first.await?, second.await?);
+                ctx.create_timer(Duration::from_secs(1)).await?;
+                let buffered: String = ctx.wait_for_external_event("approval").await?;
+                let timeout = ctx
+                    .wait_for_external_event_with_timeout::<String>("never", Duration::ZERO)
+                    .await;
+                assert!(matches!(timeout, Err(Error::Timeout(_))));
+                Ok::<_, Error>(format!("{first}{second}{buffered}"))
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+        let fire_at = Timestamp::from(SystemTime::UNIX_EPOCH);
+
+        let history = [
+            new_orchestrator_started_event(),
+            new_execution_started_event("approve", "abc", None, None, None, None),
+            new_event_raised_event("approval", Some(r#""a""#)),
+            new_event_raised_event("Approval", Some(r#""b""#)),
+            new_timer_created_event(0, &fire_at),
+            new_event_raised_event("approval", Some(r#""c""#)),
+            new_timer_fired_event(0, &fire_at),
+            new_timer_created_event(1, &fire_at),
+        ];
+        let response = executor
+            .execute_orchestrator(&id, &history, &[new_timer_fired_event(1, &fire_at)])
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 1);
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                assert_eq!(complete.result.as_deref(), Some(r#""abc""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_sub_orchestration() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("parent", |ctx: OrchestrationContext| async move {
+                let child: String = ctx.call_sub_orchestrator("child", "input").await?;
+                let failed = ctx
+                    .call_sub_orchestrator_with_id::<String, _>("child", "fixed", "input")
+                    .await;
+                assert!(matches!(failed, Err(Error::TaskFailed(_))));
+                Ok::<_, Error>(child)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+        let mut history = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("parent", "abc", None, None, None, None),
+        ];
+
+        let response = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap()
+            .response;
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CreateSubOrchestration(create)) => {
+                assert_eq!(create.instance_id, "abc:0000");
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+
+        history.extend([
+            new_sub_orchestration_created_event(0, "child", None, None, "abc:0000", None),
+            new_sub_orchestration_completed_event(0, Some(r#""done""#)),
+            new_sub_orchestration_created_event(1, "child", None, None, "fixed", None),
+        ]);
+        let response = executor
+            .execute_orchestrator(
+                &id,
+                &history,
+                &[new_sub_orchestration_failed_event(1, None)],
+            )
+            .await
+            .unwrap()
+            .response;
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                assert_eq!(complete.result.as_deref(), Some(r#""done""#));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purged_parent_keeps_sub_orchestration_payloads() {
+        use crate::payload::store::{FileSystemBlobStore, PayloadOffloader};
+
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("parent", |ctx: OrchestrationContext| async move {
+                ctx.call_sub_orchestrator::<String, _>("child", "input")
+                    .await
+            })
+            .unwrap();
+        registry
+            .add_orchestrator("child", |ctx: OrchestrationContext| async move {
+                let input: String = ctx.get_input()?;
+                Ok::<_, Error>(format!("{input} done"))
+            })
+            .unwrap();
+        let root = std::env::temp_dir().join(format!("durabletask-blobs-{}", uuid::Uuid::new_v4()));
+        let mut payloads = PayloadPipeline::default();
+        payloads.push(Arc::new(PayloadOffloader::new(
+            Arc::new(FileSystemBlobStore::new(&root)),
+            0,
+        )));
+        let executor = TaskExecutor::new(Arc::new(registry), Arc::new(JsonCodec), payloads);
+
+        let response = executor
+            .execute_orchestrator(
+                &InstanceID("abc".to_string()),
+                &[],
+                &[new_execution_started_event(
+                    "parent", "abc", None, None, None, None,
+                )],
+            )
+            .await
+            .unwrap()
+            .response;
+        let input = match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CreateSubOrchestration(create)) => create.input.clone(),
+            a => panic!("unexpected action: {a:?}"),
+        };
+        executor.payloads.purge("abc").await.unwrap();
+
+        // The child still replays after its parent is purged
+        let parent = new_parent_info(0, "parent", "abc", None);
+        let started = new_execution_started_event(
+            "child",
+            "abc:0000",
+            input.as_deref(),
+            Some(parent),
+            None,
+            None,
+        );
+        let response = executor
+            .execute_orchestrator(&InstanceID("abc:0000".to_string()), &[], &[started])
+            .await
+            .unwrap()
+            .response;
+        let result = match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::CompleteOrchestration(complete)) => {
+                complete.result.clone().unwrap()
+            }
+            a => panic!("unexpected action: {a:?}"),
+        };
+        // and its result is kept for the parent, which receives it
+        executor.payloads.purge("abc:0000").await.unwrap();
+        assert_eq!(
+            executor
+                .payloads
+                .unseal("abc", PayloadField::Output, result)
+                .await
+                .unwrap(),
+            r#""input done""#
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_start_orchestration() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("starter", |ctx: OrchestrationContext| async move {
+                let policy = OrchestrationIdReusePolicy {
+                    action: CreateOrchestrationAction::Ignore as i32,
+                    ..Default::default()
+                };
+                let builder = NewOrchestrationBuilder::new()
+                    .input(&42)
+                    .orchestration_id_reuse_policy(policy);
+                let instance_id = ctx.start_orchestration("worker", builder).await?;
+                ctx.wait_for_external_event::<()>("done").await?;
+                Ok::<_, Error>(instance_id)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+        let mut history = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("starter", "abc", None, None, None, None),
+        ];
+
+        let mut instance_ids = vec![];
+        for _ in 0..2 {
+            let output = executor
+                .execute_orchestrator(&id, &[], &history)
+                .await
+                .unwrap();
+            match &output.response.actions[0].orchestrator_action_type {
+                Some(OrchestratorActionType::CreateSubOrchestration(create)) => {
+                    assert_eq!(create.name, "worker");
+                    assert_eq!(create.input.as_deref(), Some("42"));
+                    instance_ids.push(create.instance_id.clone());
+                }
+                a => panic!("unexpected action: {a:?}"),
+            }
+            let start = &output.detached_starts[&0];
+            assert_eq!(
+                start.reuse_policy.as_ref().unwrap().action(),
+                CreateOrchestrationAction::Ignore
+            );
+        }
+        // Replays generate the same instance ID
+        assert_eq!(instance_ids[0], instance_ids[1]);
+
+        // Once the start is recorded it isn't requested again, and the task resolved without
+        // waiting for the instance to be created
+        history.extend([
+            new_sub_orchestration_created_event(
+                0,
+                "worker",
+                None,
+                Some("42"),
+                &instance_ids[0],
+                None,
+            ),
+            new_event_raised_event("done", None),
+        ]);
+        let output = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap();
+        assert!(output.detached_starts.is_empty());
+        match &output.response.actions[..] {
+            [action] => match &action.orchestrator_action_type {
+                Some(OrchestratorActionType::CompleteOrchestration(complete)) => assert_eq!(
+                    complete.result,
+                    Some(serde_json::to_string(&instance_ids[0]).unwrap())
+                ),
+                a => panic!("unexpected action: {a:?}"),
+            },
+            actions => panic!("unexpected actions: {actions:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_send_event() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_orchestrator("notify", |ctx: OrchestrationContext| async move {
+                ctx.send_event(&InstanceID("other".to_string()), "ping", &1)?;
+                ctx.create_timer(Duration::from_secs(1)).await?;
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+        let mut history = vec![
+            new_orchestrator_started_event(),
+            new_execution_started_event("notify", "abc", None, None, None, None),
+        ];
+
+        let response = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.actions.len(), 2);
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::SendEvent(send)) => {
+                assert_eq!(send.instance.as_ref().unwrap().instance_id, "other");
+                assert_eq!(send.name, "ping");
+                assert_eq!(send.data.as_deref(), Some("1"));
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+
+        // The sent event is in the history, so it isn't sent again
+        let fire_at = Timestamp::from(SystemTime::UNIX_EPOCH);
+        history.extend([
+            new_event_sent_event(0, "other", "ping", Some("1")),
+            new_timer_created_event(1, &fire_at),
+        ]);
+        let response = executor
+            .execute_orchestrator(&id, &history, &[new_orchestrator_started_event()])
+            .await
+            .unwrap()
+            .response;
+        assert!(response.actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_failures() {
+        let executor = executor();
+        let id = InstanceID("abc".to_string());
+        let complete_status = |response: OrchestratorResponse| match &response.actions[..] {
+            [action] => match &action.orchestrator_action_type {
+                Some(OrchestratorActionType::CompleteOrchestration(complete)) => complete.clone(),
+                a => panic!("unexpected action: {a:?}"),
+            },
+            actions => panic!("unexpected actions: {actions:?}"),
+        };
+
+        // The input isn't a string, so deserializing it fails the orchestration
+        let history = [new_execution_started_event(
+            "greet",
+            "abc",
+            Some("42"),
+            None,
+            None,
+            None,
+        )];
+        let response = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap()
+            .response;
+        let complete = complete_status(response);
+        assert_eq!(complete.orchestration_status(), OrchestrationStatus::Failed);
+
+        let history = [new_execution_started_event(
+            "missing", "abc", None, None, None, None,
+        )];
+        let response = executor
+            .execute_orchestrator(&id, &[], &history)
+            .await
+            .unwrap()
+            .response;
+        let complete = complete_status(response);
+        assert_eq!(
+            complete.failure_details.unwrap().error_type,
+            "OrchestratorNotRegistered"
+        );
+
+        let history = [
+            new_execution_started_event("greet", "abc", Some(r#""world""#), None, None, None),
+            new_task_scheduled_event(0, "other", None, None, None),
+            new_task_scheduled_event(1, "other", None, None, None),
+        ];
+        let response = executor
+            .execute_orchestrator(&id, &history, &[])
+            .await
+            .unwrap()
+            .response;
+        let complete = complete_status(response);
+        assert_eq!(
+            complete.failure_details.unwrap().error_type,
+            "NonDeterministicOrchestrator"
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_execute_orchestrator_unseals_payloads() {
+        use crate::payload::encryption::{AesGcmEncryptor, LocalKeyring};
+
+        let keyring = Arc::new(LocalKeyring::new());
+        keyring.add_key("k1", [7; 32]).unwrap();
+        let mut payloads = PayloadPipeline::default();
+        payloads.push(Arc::new(AesGcmEncryptor::new(keyring)));
+        let mut executor = executor();
+        executor.payloads = payloads.clone();
+
+        let mut started =
+            new_execution_started_event("greet", "abc", Some(r#""world""#), None, None, None);
+        payloads.seal_event("abc", &mut started).await.unwrap();
+        let response = executor
+            .execute_orchestrator(&InstanceID("abc".to_string()), &[], &[started])
+            .await
+            .unwrap()
+            .response;
+
+        let custom_status = response.custom_status.unwrap();
+        assert!(custom_status.starts_with("enc:v1:k1:"));
+        assert_eq!(
+            payloads
+                .unseal("abc", PayloadField::CustomStatus, custom_status)
+                .await
+                .unwrap(),
+            r#""greeting""#
+        );
+        match &response.actions[0].orchestrator_action_type {
+            Some(OrchestratorActionType::ScheduleTask(task)) => {
+                let input = task.input.clone().unwrap();
+                assert!(input.starts_with("enc:v1:k1:"));
+                assert_eq!(
+                    payloads
+                        .unseal("abc", PayloadField::Input, input)
+                        .await
+                        .unwrap(),
+                    r#""world""#
+                );
+            }
+            a => panic!("unexpected action: {a:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_activity_not_registered() {
+        let executor = executor();
+        let scheduled = new_task_scheduled_event(3, "missing", None, None, None);
+        let result = executor
+            .execute_activity(
+                &InstanceID("abc".to_string()),
+                &scheduled,
+                ActivityHooks::default(),
+            )
+            .await
+            .unwrap();
+        match result.event_type {
+            Some(EventType::TaskFailed(failed)) => {
+                assert_eq!(failed.task_scheduled_id, 3);
+                assert_eq!(
+                    failed.failure_details.unwrap().error_type,
+                    "ActivityNotRegistered"
+                );
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_activity_cancelled() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_activity("wait", |ctx: ActivityContext| async move {
+                ctx.cancellation_token().cancelled().await;
+                Err::<(), _>(Error::Cancelled)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let source = crate::task::cancellation::CancellationSource::default();
+        let scheduled = new_task_scheduled_event(0, "wait", None, None, None);
+        let id = InstanceID("abc".to_string());
+        let hooks = ActivityHooks {
+            cancellation: source.token(),
+            ..Default::default()
+        };
+        let result = executor.execute_activity(&id, &scheduled, hooks);
+        source.cancel();
+        assert!(matches!(result.await, Err(Error::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_orchestrator_activity_options() {
+        let mut registry = TaskRegistry::new();
+        let options = ActivityOptions::new().with_heartbeat_timeout(Duration::from_secs(5));
+        registry
+            .add_orchestrator("greet", {
+                let options = options.clone();
+                move |ctx: OrchestrationContext| {
+                    let options = options.clone();
+                    async move {
+                        let first = ctx.call_activity::<String, _>("say_hello", "a");
+                        let second =
+                            ctx.call_activity_with_options::<String, _>("say_hello", "b", options);
+                        first.await?;
+                        second.await
+                    }
+                }
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let id = InstanceID("abc".to_string());
+        let started = new_execution_started_event("greet", "abc", None, None, None, None);
+
+        let output = executor
+            .execute_orchestrator(
+                &id,
+                &[],
+                &[new_orchestrator_started_event(), started.clone()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.response.actions.len(), 2);
+        assert_eq!(output.activity_options, HashMap::from([(1, options)]));
+
+        // Tasks that are in the history already are no longer reported
+        let history = [
+            new_orchestrator_started_event(),
+            started,
+            new_task_scheduled_event(0, "say_hello", None, Some(r#""a""#), None),
+            new_task_scheduled_event(1, "say_hello", None, Some(r#""b""#), None),
+        ];
+        let output = executor
+            .execute_orchestrator(&id, &history, &[new_task_completed_event(0, None)])
+            .await
+            .unwrap();
+        assert!(output.activity_options.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_activity_heartbeats() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_activity("count", |ctx: ActivityContext| async move {
+                let start = ctx.heartbeat_details::<u32>()?.unwrap_or_default();
+                for i in start..3 {
+                    ctx.heartbeat(&(i + 1))?;
+                }
+                Ok::<_, Error>(start)
+            })
+            .unwrap();
+        let executor = TaskExecutor::new(
+            Arc::new(registry),
+            Arc::new(JsonCodec),
+            PayloadPipeline::default(),
+        );
+        let scheduled = new_task_scheduled_event(0, "count", None, None, None);
+        let (heartbeats, mut received) = mpsc::unbounded_channel();
+        let hooks = ActivityHooks {
+            heartbeats: Some(heartbeats),
+            heartbeat_details: Some("1".to_string()),
+            ..Default::default()
+        };
+        let result = executor
+            .execute_activity(&InstanceID("abc".to_string()), &scheduled, hooks)
+            .await
+            .unwrap();
+        match result.event_type {
+            Some(EventType::TaskCompleted(completed)) => {
+                assert_eq!(completed.result.as_deref(), Some("1"));
+            }
+            e => panic!("unexpected event: {e:?}"),
+        }
+        assert_eq!(received.recv().await, Some(Some("2".to_string())));
+        assert_eq!(received.recv().await, Some(Some("3".to_string())));
+        assert_eq!(received.recv().await, None);
+    }
+}
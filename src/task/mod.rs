@@ -0,0 +1,25 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+pub mod activity;
+pub mod cancellation;
+pub(crate) mod executor;
+pub mod orchestration;
+pub mod registry;
+
+pub use activity::{ActivityContext, ActivityOptions, ACTIVITY_TIMEOUT_ERROR_TYPE};
+pub use cancellation::CancellationToken;
+pub use orchestration::{OrchestrationContext, Task};
+pub use registry::TaskRegistry;
@@ -0,0 +1,614 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use prost_wkt_types::Timestamp;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use crate::api::{InstanceID, NewOrchestrationBuilder};
+use crate::backend::executor::DetachedStart;
+use crate::durabletask_pb::history_event::EventType;
+use crate::durabletask_pb::{HistoryEvent, OrchestratorAction, TaskFailureDetails};
+use crate::internal::{
+    new_create_sub_orchestration_action, new_create_timer_action, new_schedule_task_action,
+    new_send_event_action, sub_orchestration_instance_id,
+};
+use crate::payload::{from_payload, to_payload, PayloadCodec};
+use crate::task::ActivityOptions;
+use crate::Error;
+
+/// The namespace of the name-based UUIDs returned by [`OrchestrationContext::new_uuid`].
+const UUID_NAMESPACE: Uuid = Uuid::from_u128(0x9e952958_5e33_4daf_827f_2fa12937b875);
+
+/// The encoded output of a task or orchestrator, or the details of its failure.
+pub(crate) type TaskResult = Result<Option<String>, TaskFailureDetails>;
+
+/// Replay state shared between an [`OrchestrationContext`] and the executor driving it.
+pub(crate) struct OrchestrationState {
+    pub(crate) instance_id: InstanceID,
+    pub(crate) execution_id: String,
+    pub(crate) name: String,
+    pub(crate) input: Option<String>,
+    pub(crate) is_replaying: bool,
+    pub(crate) current_time: SystemTime,
+    pub(crate) sequence_number: i32,
+    pub(crate) uuid_counter: u32,
+    pub(crate) pending_actions: BTreeMap<i32, OrchestratorAction>,
+    /// The options of activities scheduled with any, by task ID.
+    pub(crate) activity_options: HashMap<i32, ActivityOptions>,
+    /// The detached orchestrations started by `CreateSubOrchestration` actions, by task ID.
+    pub(crate) detached_starts: HashMap<i32, DetachedStart>,
+    pub(crate) results: HashMap<i32, TaskResult>,
+    pub(crate) custom_status: Option<String>,
+    /// `EventRaised` events the orchestrator hasn't received yet, in the order they arrived.
+    pub(crate) buffered_events: Vec<HistoryEvent>,
+    /// The lowercased event names and IDs of tasks waiting for external events, in the order
+    /// they started waiting.
+    pub(crate) event_waiters: VecDeque<(String, u32)>,
+    /// The events handed to waiting tasks that didn't receive them yet, by waiter ID.
+    pub(crate) event_results: HashMap<u32, HistoryEvent>,
+    pub(crate) next_waiter_id: u32,
+    pub(crate) continue_as_new: Option<ContinueAsNew>,
+}
+
+/// A request to restart the orchestration once the orchestrator returns.
+pub(crate) struct ContinueAsNew {
+    pub(crate) input: Option<String>,
+    pub(crate) preserve_unprocessed_events: bool,
+}
+
+impl OrchestrationState {
+    pub(crate) fn next_sequence_number(&mut self) -> i32 {
+        let id = self.sequence_number;
+        self.sequence_number += 1;
+        id
+    }
+
+    /// Hands an `EventRaised` event to the first task waiting for it, or buffers it until one
+    /// does.
+    pub(crate) fn raise_event(&mut self, e: &HistoryEvent) {
+        if let Some(e) = self.hand_to_waiter(e.clone()) {
+            self.buffered_events.push(e);
+        }
+    }
+
+    /// Hands back an event whose task was dropped before receiving it. It arrived before any
+    /// buffered event, so it's the next one handed out.
+    fn return_event(&mut self, e: HistoryEvent) {
+        if let Some(e) = self.hand_to_waiter(e) {
+            self.buffered_events.insert(0, e);
+        }
+    }
+
+    /// Hands an `EventRaised` event to the first task waiting for it, or returns it if none is.
+    fn hand_to_waiter(&mut self, e: HistoryEvent) -> Option<HistoryEvent> {
+        let Some(EventType::EventRaised(raised)) = &e.event_type else {
+            return None;
+        };
+        let name = raised.name.to_lowercase();
+        let Some(i) = self.event_waiters.iter().position(|(n, _)| *n == name) else {
+            return Some(e);
+        };
+        let (_, waiter) = self.event_waiters.remove(i).expect("waiter exists");
+        self.event_results.insert(waiter, e);
+        None
+    }
+
+    /// Removes and returns the oldest buffered event named `name`.
+    fn take_buffered_event(&mut self, name: &str) -> Option<HistoryEvent> {
+        let i = self.buffered_events.iter().position(|e| {
+            matches!(&e.event_type, Some(EventType::EventRaised(raised)) if raised.name.to_lowercase() == name)
+        })?;
+        Some(self.buffered_events.remove(i))
+    }
+}
+
+/// The context passed to an orchestrator function.
+///
+/// Orchestrators are replayed from their history, so they must only interact with the outside
+/// world through this context.
+#[derive(Clone)]
+pub struct OrchestrationContext {
+    state: Arc<Mutex<OrchestrationState>>,
+    codec: Arc<dyn PayloadCodec>,
+}
+
+impl OrchestrationContext {
+    pub(crate) fn new(instance_id: InstanceID, codec: Arc<dyn PayloadCodec>) -> Self {
+        OrchestrationContext {
+            state: Arc::new(Mutex::new(OrchestrationState {
+                instance_id,
+                execution_id: String::new(),
+                name: String::new(),
+                input: None,
+                is_replaying: false,
+                current_time: SystemTime::UNIX_EPOCH,
+                sequence_number: 0,
+                uuid_counter: 0,
+                pending_actions: BTreeMap::new(),
+                activity_options: HashMap::new(),
+                detached_starts: HashMap::new(),
+                results: HashMap::new(),
+                custom_status: None,
+                buffered_events: Vec::new(),
+                event_waiters: VecDeque::new(),
+                event_results: HashMap::new(),
+                next_waiter_id: 0,
+                continue_as_new: None,
+            })),
+            codec,
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, OrchestrationState> {
+        self.state.lock().unwrap()
+    }
+
+    pub(crate) fn codec(&self) -> Arc<dyn PayloadCodec> {
+        self.codec.clone()
+    }
+
+    pub fn instance_id(&self) -> InstanceID {
+        self.lock().instance_id.clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.lock().name.clone()
+    }
+
+    /// Whether the orchestrator is replaying events it has already processed.
+    pub fn is_replaying(&self) -> bool {
+        self.lock().is_replaying
+    }
+
+    /// The time at which the current orchestrator episode started.
+    pub fn current_time(&self) -> SystemTime {
+        self.lock().current_time
+    }
+
+    /// The time at which the current orchestrator episode started, in UTC.
+    pub fn current_utc_datetime(&self) -> DateTime<Utc> {
+        DateTime::from(self.current_time())
+    }
+
+    /// Returns a name-based UUID derived from the instance ID, the execution ID and the number
+    /// of UUIDs created so far, so replays return the same values.
+    pub fn new_uuid(&self) -> Uuid {
+        let mut state = self.lock();
+        let name = format!(
+            "{}_{}_{}",
+            state.instance_id, state.execution_id, state.uuid_counter
+        );
+        state.uuid_counter += 1;
+        Uuid::new_v5(&UUID_NAMESPACE, name.as_bytes())
+    }
+
+    /// Returns a random number generator seeded with [`new_uuid`](Self::new_uuid), so replays
+    /// generate the same values.
+    pub fn new_rng(&self) -> ChaCha8Rng {
+        let uuid = self.new_uuid();
+        let mut seed = [0; 32];
+        seed[..16].copy_from_slice(uuid.as_bytes());
+        seed[16..].copy_from_slice(uuid.as_bytes());
+        ChaCha8Rng::from_seed(seed)
+    }
+
+    /// Deserializes the orchestration input with the worker's codec.
+    pub fn get_input<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        from_payload(self.codec.as_ref(), self.lock().input.as_deref())
+    }
+
+    /// Sets a custom status that's visible to clients while the orchestration runs.
+    pub fn set_custom_status<T: Serialize + ?Sized>(&self, status: &T) -> Result<(), Error> {
+        let status = to_payload(self.codec.as_ref(), status)?;
+        self.lock().custom_status = status;
+        Ok(())
+    }
+
+    /// Restarts the orchestration with a new `input` and an empty history once the orchestrator
+    /// returns, which then ignores the orchestrator's output.
+    ///
+    /// When `preserve_unprocessed_events` is set, external events the orchestrator hasn't
+    /// received yet are carried over to the new execution instead of being dropped.
+    pub fn continue_as_new<I: Serialize + ?Sized>(
+        &self,
+        input: &I,
+        preserve_unprocessed_events: bool,
+    ) -> Result<(), Error> {
+        let input = to_payload(self.codec.as_ref(), input)?;
+        self.lock().continue_as_new = Some(ContinueAsNew {
+            input,
+            preserve_unprocessed_events,
+        });
+        Ok(())
+    }
+
+    /// Schedules the activity `name` and returns a task that resolves to its output.
+    pub fn call_activity<T, I>(&self, name: &str, input: &I) -> Task<T>
+    where
+        T: DeserializeOwned,
+        I: Serialize + ?Sized,
+    {
+        match to_payload(self.codec.as_ref(), input) {
+            Ok(input) => self.schedule(|id| new_schedule_task_action(id, name, input.as_deref())),
+            Err(e) => self.failed(e),
+        }
+    }
+
+    /// Like [`call_activity`](Self::call_activity), with timeouts enforced by the worker that
+    /// runs the activity.
+    pub fn call_activity_with_options<T, I>(
+        &self,
+        name: &str,
+        input: &I,
+        options: ActivityOptions,
+    ) -> Task<T>
+    where
+        T: DeserializeOwned,
+        I: Serialize + ?Sized,
+    {
+        let task = self.call_activity(name, input);
+        if let (TaskState::Scheduled(id), false) = (&task.state, options.is_empty()) {
+            self.lock().activity_options.insert(*id, options);
+        }
+        task
+    }
+
+    /// Starts the orchestrator `name` as a child of this orchestration and returns a task that
+    /// resolves to its output.
+    ///
+    /// The child's instance ID is derived from this instance's ID and the task's sequence
+    /// number, e.g. `parent:0003`, so it's the same on every replay.
+    pub fn call_sub_orchestrator<T, I>(&self, name: &str, input: &I) -> Task<T>
+    where
+        T: DeserializeOwned,
+        I: Serialize + ?Sized,
+    {
+        self.create_sub_orchestration(name, None, input)
+    }
+
+    /// Like [`call_sub_orchestrator`](Self::call_sub_orchestrator), with an explicit instance
+    /// ID for the child.
+    pub fn call_sub_orchestrator_with_id<T, I>(
+        &self,
+        name: &str,
+        instance_id: &str,
+        input: &I,
+    ) -> Task<T>
+    where
+        T: DeserializeOwned,
+        I: Serialize + ?Sized,
+    {
+        self.create_sub_orchestration(name, Some(instance_id), input)
+    }
+
+    fn create_sub_orchestration<T, I>(
+        &self,
+        name: &str,
+        instance_id: Option<&str>,
+        input: &I,
+    ) -> Task<T>
+    where
+        I: Serialize + ?Sized,
+    {
+        let input = match to_payload(self.codec.as_ref(), input) {
+            Ok(input) => input,
+            Err(e) => return self.failed(e),
+        };
+        let parent_id = self.lock().instance_id.0.clone();
+        self.schedule(|id| {
+            let instance_id = match instance_id {
+                Some(instance_id) => instance_id.to_string(),
+                None => sub_orchestration_instance_id(&parent_id, id),
+            };
+            new_create_sub_orchestration_action(id, name, &instance_id, input.as_deref())
+        })
+    }
+
+    /// Raises the event `name` on the orchestration instance `instance_id`, which receives it
+    /// through [`wait_for_external_event`](Self::wait_for_external_event).
+    ///
+    /// The event is delivered when this orchestration's current work item is committed, and is
+    /// only sent once even though the orchestrator is replayed.
+    pub fn send_event<T: Serialize + ?Sized>(
+        &self,
+        instance_id: &InstanceID,
+        name: &str,
+        data: &T,
+    ) -> Result<(), Error> {
+        let data = to_payload(self.codec.as_ref(), data)?;
+        let mut state = self.lock();
+        let id = state.next_sequence_number();
+        state.pending_actions.insert(
+            id,
+            new_send_event_action(id, &instance_id.0, name, data.as_deref()),
+        );
+        Ok(())
+    }
+
+    /// Starts the orchestrator `name` as an independent instance and returns a task that
+    /// resolves to its instance ID.
+    ///
+    /// Unlike a sub-orchestration, the new instance has no parent and this orchestration isn't
+    /// notified when it completes. The start is recorded in the history and the instance is
+    /// created once this orchestration's current work item is committed, so it's only started
+    /// once even though the orchestrator is replayed. A deterministic instance ID is generated
+    /// when `orchestration` doesn't specify one. If an instance with the same ID exists, its
+    /// reuse policy decides whether it's replaced or the start is dropped.
+    pub fn start_orchestration(
+        &self,
+        name: &str,
+        orchestration: NewOrchestrationBuilder,
+    ) -> Task<InstanceID> {
+        let priority = orchestration.priority;
+        let request = match orchestration.build_with(self.codec.as_ref()) {
+            Ok(request) => request,
+            Err(e) => return self.failed(e),
+        };
+        let instance_id = match request.instance_id.is_empty() {
+            true => self.new_uuid().to_string(),
+            false => request.instance_id,
+        };
+        let output = match to_payload(self.codec.as_ref(), &InstanceID(instance_id.clone())) {
+            Ok(output) => output,
+            Err(e) => return self.failed(e),
+        };
+        let mut state = self.lock();
+        let id = state.next_sequence_number();
+        state.pending_actions.insert(
+            id,
+            new_create_sub_orchestration_action(id, name, &instance_id, request.input.as_deref()),
+        );
+        state.detached_starts.insert(
+            id,
+            DetachedStart {
+                reuse_policy: request.orchestration_id_reuse_policy,
+                scheduled_start_timestamp: request.scheduled_start_timestamp,
+                priority,
+            },
+        );
+        // Nothing is sent back once the instance is created, so the task resolves right away
+        state.results.insert(id, Ok(output));
+        drop(state);
+        Task::new(self.clone(), TaskState::Scheduled(id))
+    }
+
+    /// Returns a task that completes once `delay` has elapsed.
+    pub fn create_timer(&self, delay: Duration) -> Task<()> {
+        let fire_at = Timestamp::from(self.current_time() + delay);
+        self.schedule(|id| new_create_timer_action(id, &fire_at))
+    }
+
+    /// Returns a task that resolves to the input of the next external event named `name`.
+    ///
+    /// Names are case-insensitive. Events that arrive before the orchestrator waits for them are
+    /// buffered, and are handed out in the order they arrived to tasks in the order they started
+    /// waiting.
+    pub fn wait_for_external_event<T: DeserializeOwned>(&self, name: &str) -> Task<T> {
+        self.wait_for_event(name, None)
+    }
+
+    /// Like [`wait_for_external_event`](Self::wait_for_external_event), but fails with
+    /// [`Error::Timeout`] if no event arrives within `timeout`.
+    pub fn wait_for_external_event_with_timeout<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        timeout: Duration,
+    ) -> Task<T> {
+        self.wait_for_event(name, Some(timeout))
+    }
+
+    fn wait_for_event<T>(&self, name: &str, timeout: Option<Duration>) -> Task<T> {
+        let key = name.to_lowercase();
+        let mut state = self.lock();
+        let waiter = state.next_waiter_id;
+        state.next_waiter_id += 1;
+
+        let mut timer = None;
+        match state.take_buffered_event(&key) {
+            Some(e) => {
+                state.event_results.insert(waiter, e);
+            }
+            None => {
+                state.event_waiters.push_back((key, waiter));
+                if let Some(timeout) = timeout {
+                    let id = state.next_sequence_number();
+                    let fire_at = Timestamp::from(state.current_time + timeout);
+                    state
+                        .pending_actions
+                        .insert(id, new_create_timer_action(id, &fire_at));
+                    timer = Some(id);
+                }
+            }
+        }
+        drop(state);
+
+        Task::new(
+            self.clone(),
+            TaskState::ExternalEvent {
+                name: name.to_string(),
+                waiter,
+                timer,
+            },
+        )
+    }
+
+    fn schedule<T>(&self, action: impl FnOnce(i32) -> OrchestratorAction) -> Task<T> {
+        let mut state = self.lock();
+        let id = state.next_sequence_number();
+        state.pending_actions.insert(id, action(id));
+        Task::new(self.clone(), TaskState::Scheduled(id))
+    }
+
+    fn failed<T>(&self, error: Error) -> Task<T> {
+        Task::new(self.clone(), TaskState::Failed(Some(error)))
+    }
+}
+
+enum TaskState {
+    Scheduled(i32),
+    ExternalEvent {
+        name: String,
+        waiter: u32,
+        /// The timer that ends the wait, if it has a timeout.
+        timer: Option<i32>,
+    },
+    Failed(Option<Error>),
+}
+
+/// A durable task scheduled by an orchestrator. It resolves once its result is in the
+/// orchestration history.
+pub struct Task<T> {
+    ctx: OrchestrationContext,
+    state: TaskState,
+    _output: PhantomData<fn() -> T>,
+}
+
+impl<T> Task<T> {
+    fn new(ctx: OrchestrationContext, state: TaskState) -> Self {
+        Task {
+            ctx,
+            state,
+            _output: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Future for Task<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The executor polls the orchestrator after every history event, so no waker is needed
+        let this = self.get_mut();
+        match &mut this.state {
+            TaskState::Failed(error) => {
+                Poll::Ready(Err(error.take().expect("task polled after completion")))
+            }
+            TaskState::Scheduled(id) => match this.ctx.lock().results.remove(id) {
+                None => Poll::Pending,
+                Some(Ok(output)) => {
+                    Poll::Ready(from_payload(this.ctx.codec.as_ref(), output.as_deref()))
+                }
+                Some(Err(details)) => Poll::Ready(Err(Error::TaskFailed(details))),
+            },
+            TaskState::ExternalEvent {
+                name,
+                waiter,
+                timer,
+            } => {
+                let mut state = this.ctx.lock();
+                if let Some(e) = state.event_results.remove(waiter) {
+                    drop(state);
+                    let input = match e.event_type {
+                        Some(EventType::EventRaised(raised)) => raised.input,
+                        _ => None,
+                    };
+                    return Poll::Ready(from_payload(this.ctx.codec.as_ref(), input.as_deref()));
+                }
+                if timer.is_some_and(|timer| state.results.remove(&timer).is_some()) {
+                    state.event_waiters.retain(|(_, w)| w != waiter);
+                    return Poll::Ready(Err(Error::Timeout(format!(
+                        "no '{}' event was raised",
+                        name
+                    ))));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        // A dropped waiter must not consume events meant for later ones
+        if let TaskState::ExternalEvent { waiter, .. } = &self.state {
+            if let Ok(mut state) = self.ctx.state.lock() {
+                state.event_waiters.retain(|(_, w)| w != waiter);
+                if let Some(e) = state.event_results.remove(waiter) {
+                    state.return_event(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use rand_chacha::rand_core::RngCore;
+
+    use super::*;
+    use crate::internal::new_event_raised_event;
+    use crate::payload::JsonCodec;
+
+    fn context(execution_id: &str) -> OrchestrationContext {
+        let ctx = OrchestrationContext::new(InstanceID("abc".to_string()), Arc::new(JsonCodec));
+        ctx.lock().execution_id = execution_id.to_string();
+        ctx
+    }
+
+    #[test]
+    fn test_deterministic_helpers() {
+        let (first, replayed) = (context("e1"), context("e1"));
+        let uuids: Vec<_> = (0..2).map(|_| first.new_uuid()).collect();
+        assert_ne!(uuids[0], uuids[1]);
+        assert_eq!(uuids[0].get_version_num(), 5);
+        assert_eq!(
+            uuids,
+            (0..2).map(|_| replayed.new_uuid()).collect::<Vec<_>>()
+        );
+        assert_eq!(first.new_rng().next_u64(), replayed.new_rng().next_u64());
+        assert_ne!(context("e2").new_uuid(), uuids[0]);
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        first.lock().current_time = time;
+        assert_eq!(first.current_utc_datetime().timestamp(), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_dropped_waiter_returns_its_event() {
+        let ctx = context("e1");
+        let first = ctx.wait_for_external_event::<String>("approval");
+        let mut second = ctx.wait_for_external_event::<String>("Approval");
+        ctx.lock()
+            .raise_event(&new_event_raised_event("APPROVAL", Some(r#""yes""#)));
+
+        // The event handed to the first waiter goes to the next one
+        drop(first);
+        assert_eq!((&mut second).now_or_never().unwrap().unwrap(), "yes");
+
+        // Without another waiter, it's buffered for the next task that waits
+        let third = ctx.wait_for_external_event::<String>("approval");
+        ctx.lock()
+            .raise_event(&new_event_raised_event("approval", Some(r#""first""#)));
+        ctx.lock()
+            .raise_event(&new_event_raised_event("approval", Some(r#""second""#)));
+        drop(third);
+        let fourth = ctx.wait_for_external_event::<String>("approval");
+        assert_eq!(fourth.now_or_never().unwrap().unwrap(), "first");
+        assert_eq!(ctx.lock().buffered_events.len(), 1);
+    }
+}
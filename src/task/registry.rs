@@ -0,0 +1,131 @@
+/*
+  Copyright 2024 Mike Nguyen (mikeee) <hey@mike.ee>
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+
+use crate::internal::new_task_failure_details;
+use crate::payload::{to_payload, PayloadCodec};
+use crate::task::orchestration::TaskResult;
+use crate::task::{ActivityContext, OrchestrationContext};
+use crate::Error;
+
+pub(crate) type Orchestrator =
+    Arc<dyn Fn(OrchestrationContext) -> BoxFuture<'static, TaskResult> + Send + Sync>;
+pub(crate) type Activity =
+    Arc<dyn Fn(ActivityContext) -> BoxFuture<'static, TaskResult> + Send + Sync>;
+
+/// The orchestrators and activities a worker can run, by name.
+#[derive(Default)]
+pub struct TaskRegistry {
+    orchestrators: HashMap<String, Orchestrator>,
+    activities: HashMap<String, Activity>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an orchestrator. Its output is serialized with the worker's codec, and an
+    /// error fails the orchestration.
+    pub fn add_orchestrator<F, Fut, O, E>(
+        &mut self,
+        name: &str,
+        orchestrator: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(OrchestrationContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<O, E>> + Send + 'static,
+        O: Serialize,
+        E: Display,
+    {
+        if self.orchestrators.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "orchestrator '{name}' is already registered"
+            )));
+        }
+        let orchestrator: Orchestrator = Arc::new(move |ctx: OrchestrationContext| {
+            let codec = ctx.codec();
+            let output = orchestrator(ctx);
+            Box::pin(async move { encode_result(codec.as_ref(), output.await) })
+        });
+        self.orchestrators.insert(name.to_string(), orchestrator);
+        Ok(())
+    }
+
+    /// Registers an activity. Its output is serialized with the worker's codec, and an error
+    /// is reported to the orchestrator as a task failure.
+    pub fn add_activity<F, Fut, O, E>(&mut self, name: &str, activity: F) -> Result<(), Error>
+    where
+        F: Fn(ActivityContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<O, E>> + Send + 'static,
+        O: Serialize,
+        E: Display,
+    {
+        if self.activities.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "activity '{name}' is already registered"
+            )));
+        }
+        let activity: Activity = Arc::new(move |ctx: ActivityContext| {
+            let codec = ctx.codec();
+            let output = activity(ctx);
+            Box::pin(async move { encode_result(codec.as_ref(), output.await) })
+        });
+        self.activities.insert(name.to_string(), activity);
+        Ok(())
+    }
+
+    pub(crate) fn orchestrator(&self, name: &str) -> Option<&Orchestrator> {
+        self.orchestrators.get(name)
+    }
+
+    pub(crate) fn activity(&self, name: &str) -> Option<&Activity> {
+        self.activities.get(name)
+    }
+}
+
+fn encode_result<O: Serialize, E: Display>(
+    codec: &dyn PayloadCodec,
+    result: Result<O, E>,
+) -> TaskResult {
+    match result {
+        Ok(output) => to_payload(codec, &output).map_err(new_task_failure_details),
+        Err(e) => Err(new_task_failure_details(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_registration() {
+        let mut registry = TaskRegistry::new();
+        registry
+            .add_activity("greet", |_ctx| async { Ok::<_, Error>("hello") })
+            .unwrap();
+        let result = registry.add_activity("greet", |_ctx| async { Ok::<_, Error>("hi") });
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(registry.activity("greet").is_some());
+        assert!(registry.orchestrator("greet").is_none());
+    }
+}